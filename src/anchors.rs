@@ -0,0 +1,523 @@
+use crate::config::{parse_hex, parse_name};
+use crate::dnssec::{calc_key_tag, DNSKEY_REVOKE, DNSKEY_SEP, DNSKEY_ZONE};
+use crate::packet::*;
+use std::{
+    fs,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// How long a new key must be seen before it's trusted (RFC 5011 section 2.4.1).
+pub const ADD_HOLD_DOWN: u64 = 30 * 24 * 3600;
+/// How long a revoked key is remembered before it's forgotten.
+pub const REMOVE_HOLD_DOWN: u64 = 30 * 24 * 3600;
+
+/// The states of a trust anchor of RFC 5011 section 4.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyState {
+    AddPend,
+    Valid,
+    Missing,
+    Revoked,
+    Removed,
+}
+
+impl KeyState {
+    fn parse(arg: &str) -> Result<KeyState> {
+        match arg {
+            "addpend" => Ok(KeyState::AddPend),
+            "valid" => Ok(KeyState::Valid),
+            "missing" => Ok(KeyState::Missing),
+            "revoked" => Ok(KeyState::Revoked),
+            "removed" => Ok(KeyState::Removed),
+            _ => Err(format!("Unknown key state {}", arg).into()),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            KeyState::AddPend => "addpend",
+            KeyState::Valid => "valid",
+            KeyState::Missing => "missing",
+            KeyState::Revoked => "revoked",
+            KeyState::Removed => "removed",
+        }
+    }
+
+    pub fn is_trusted(self) -> bool {
+        matches!(self, KeyState::Valid | KeyState::Missing)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TrustedKey {
+    /// The DNSKEY, always without its REVOKE flag.
+    pub key: DnsRecord,
+    pub state: KeyState,
+    /// When the key entered its state, in seconds since the epoch.
+    pub since: u64,
+}
+
+impl TrustedKey {
+    pub fn key_tag(&self) -> u16 {
+        self.key
+            .rdata()
+            .map(|rdata| calc_key_tag(&rdata))
+            .unwrap_or(0)
+    }
+
+    fn set_state(&mut self, state: KeyState, now: u64) {
+        println!(
+            "Trust anchor {} {} is now {}",
+            display_zone(self.key.domain()),
+            self.key_tag(),
+            state.name()
+        );
+        self.state = state;
+        self.since = now;
+    }
+}
+
+/// The trust anchors of every zone, as kept in the state file.
+pub struct AnchorFile {
+    path: String,
+    pub keys: Vec<TrustedKey>,
+}
+
+impl AnchorFile {
+    /// Read the state file, a missing file meaning no key is known yet.
+    pub fn load(path: &str) -> Result<AnchorFile> {
+        let mut anchors = AnchorFile {
+            path: path.to_owned(),
+            keys: Vec::new(),
+        };
+
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(anchors),
+            Err(err) => return Err(err.into()),
+        };
+
+        for (i, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let key = parse_key(line).map_err(|err| format!("{}:{}: {}", path, i + 1, err))?;
+            anchors.keys.push(key);
+        }
+
+        Ok(anchors)
+    }
+
+    /// Write the state file, replacing it only once it's fully written.
+    pub fn save(&self) -> Result<()> {
+        let mut content = String::from(
+            "# rdns trust anchors, see RFC 5011\n\
+             # zone state since flags protocol algorithm public-key\n",
+        );
+        for trusted in &self.keys {
+            let DnsRecord::DNSKEY {
+                domain,
+                flags,
+                protocol,
+                algorithm,
+                public_key,
+                ..
+            } = &trusted.key
+            else {
+                continue;
+            };
+            content += &format!(
+                "{} {} {} {} {} {} {}\n",
+                display_zone(domain),
+                trusted.state.name(),
+                trusted.since,
+                flags,
+                protocol,
+                algorithm,
+                public_key
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect::<String>()
+            );
+        }
+
+        let tmp = format!("{}.tmp", self.path);
+        fs::write(&tmp, content)?;
+        fs::rename(&tmp, &self.path)?;
+
+        Ok(())
+    }
+
+    pub fn has_zone(&self, zone: &str) -> bool {
+        self.keys.iter().any(|trusted| trusted.key.domain() == zone)
+    }
+
+    /// Keys currently trusted to sign the DNSKEY RRset of their zone.
+    pub fn trusted(&self) -> impl Iterator<Item = &DnsRecord> {
+        self.keys
+            .iter()
+            .filter(|trusted| trusted.state.is_trusted())
+            .map(|trusted| &trusted.key)
+    }
+
+    /// Move the keys of `zone` through their states given its latest
+    /// validated DNSKEY RRset. New keys are trusted right away if
+    /// `trusted_already` says so, otherwise only after the add hold-down.
+    /// `self_signed` tells whether a revoked key signed the RRset itself.
+    pub fn update(
+        &mut self,
+        zone: &str,
+        keys: &[&DnsRecord],
+        trusted_already: impl Fn(&DnsRecord) -> bool,
+        self_signed: impl Fn(&DnsRecord) -> bool,
+        now: u64,
+    ) {
+        let mut seen = Vec::new();
+
+        for key in keys {
+            let DnsRecord::DNSKEY { flags, .. } = key else {
+                continue;
+            };
+            if flags & DNSKEY_SEP == 0 || flags & DNSKEY_ZONE == 0 {
+                continue;
+            }
+
+            let unrevoked = without_revoke(key);
+            let entry = self.find(&unrevoked);
+            seen.push(unrevoked.clone());
+
+            if flags & DNSKEY_REVOKE != 0 {
+                // only the key itself can revoke it
+                if let Some(entry) = entry {
+                    let revocable = !matches!(entry.state, KeyState::Revoked | KeyState::Removed);
+                    if revocable && self_signed(key) {
+                        entry.set_state(KeyState::Revoked, now);
+                    }
+                }
+                continue;
+            }
+
+            match entry {
+                None => {
+                    let state = if trusted_already(key) {
+                        KeyState::Valid
+                    } else {
+                        KeyState::AddPend
+                    };
+                    let mut trusted = TrustedKey {
+                        key: unrevoked,
+                        state,
+                        since: now,
+                    };
+                    trusted.set_state(state, now);
+                    self.keys.push(trusted);
+                }
+                Some(entry) => match entry.state {
+                    KeyState::AddPend if now.saturating_sub(entry.since) >= ADD_HOLD_DOWN => {
+                        entry.set_state(KeyState::Valid, now)
+                    }
+                    KeyState::Missing => entry.set_state(KeyState::Valid, now),
+                    _ => {}
+                },
+            }
+        }
+
+        for entry in self.keys.iter_mut() {
+            if entry.key.domain() != zone {
+                continue;
+            }
+
+            let present = seen.iter().any(|key| same_key(key, &entry.key));
+            match entry.state {
+                KeyState::Valid if !present => entry.set_state(KeyState::Missing, now),
+                KeyState::Revoked if now.saturating_sub(entry.since) >= REMOVE_HOLD_DOWN => {
+                    entry.set_state(KeyState::Removed, now)
+                }
+                _ => {}
+            }
+        }
+
+        // a pending key that disappears has to start over
+        self.keys.retain(|entry| {
+            entry.key.domain() != zone
+                || entry.state != KeyState::AddPend
+                || seen.iter().any(|key| same_key(key, &entry.key))
+        });
+    }
+
+    fn find(&mut self, key: &DnsRecord) -> Option<&mut TrustedKey> {
+        self.keys
+            .iter_mut()
+            .find(|trusted| same_key(&trusted.key, key))
+    }
+
+    fn find_by_tag(&mut self, zone: &str, key_tag: u16) -> Result<&mut TrustedKey> {
+        self.keys
+            .iter_mut()
+            .find(|trusted| trusted.key.domain() == zone && trusted.key_tag() == key_tag)
+            .ok_or_else(|| format!("No key {} for {}", key_tag, display_zone(zone)).into())
+    }
+}
+
+fn parse_key(line: &str) -> Result<TrustedKey> {
+    let words = line.split_whitespace().collect::<Vec<_>>();
+    let [zone, state, since, key @ ..] = words.as_slice() else {
+        return Err("Expected a zone, state, time and key".into());
+    };
+
+    Ok(TrustedKey {
+        key: parse_dnskey(zone, key)?,
+        state: KeyState::parse(state)?,
+        since: since.parse()?,
+    })
+}
+
+fn parse_dnskey(zone: &str, args: &[&str]) -> Result<DnsRecord> {
+    let [flags, protocol, algorithm, public_key] = args else {
+        return Err("Expected flags, protocol, algorithm and public key".into());
+    };
+
+    Ok(DnsRecord::DNSKEY {
+        domain: parse_name(zone),
+        flags: flags.parse::<u16>()? & !DNSKEY_REVOKE,
+        protocol: protocol.parse()?,
+        algorithm: algorithm.parse()?,
+        public_key: parse_hex(public_key)?,
+        ttl: 0,
+    })
+}
+
+fn without_revoke(key: &DnsRecord) -> DnsRecord {
+    let mut key = key.clone();
+    if let DnsRecord::DNSKEY { flags, ttl, .. } = &mut key {
+        *flags &= !DNSKEY_REVOKE;
+        *ttl = 0;
+    }
+    key
+}
+
+/// Whether two DNSKEYs are the same key, whatever their TTL and REVOKE flag.
+fn same_key(a: &DnsRecord, b: &DnsRecord) -> bool {
+    match (a, b) {
+        (
+            DnsRecord::DNSKEY {
+                domain,
+                algorithm,
+                public_key,
+                ..
+            },
+            DnsRecord::DNSKEY {
+                domain: other_domain,
+                algorithm: other_algorithm,
+                public_key: other_public_key,
+                ..
+            },
+        ) => {
+            domain == other_domain && algorithm == other_algorithm && public_key == other_public_key
+        }
+        _ => false,
+    }
+}
+
+fn display_zone(zone: &str) -> &str {
+    if zone.is_empty() {
+        "."
+    } else {
+        zone
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+/// `rdns anchors <state-file> <command>`, to inspect and override the
+/// trust anchors without waiting for RFC 5011 to run its course.
+pub fn run_command(args: &[String]) -> Result<()> {
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    let [path, command, args @ ..] = args.as_slice() else {
+        return Err("Usage: rdns anchors <state-file> list|add|accept|remove ...".into());
+    };
+
+    let mut anchors = AnchorFile::load(path)?;
+    let now = unix_now();
+
+    match (*command, args) {
+        ("list", []) => {
+            for trusted in &anchors.keys {
+                let pending = match trusted.state {
+                    KeyState::AddPend => {
+                        let left = (trusted.since + ADD_HOLD_DOWN).saturating_sub(now);
+                        format!(", trusted in {} days", left.div_ceil(24 * 3600))
+                    }
+                    _ => String::new(),
+                };
+                println!(
+                    "{} {} {} since {}{}",
+                    display_zone(trusted.key.domain()),
+                    trusted.key_tag(),
+                    trusted.state.name(),
+                    trusted.since,
+                    pending
+                );
+            }
+            return Ok(());
+        }
+        ("add", [zone, key @ ..]) => {
+            let key = parse_dnskey(zone, key)?;
+            match anchors.find(&key) {
+                Some(trusted) => trusted.set_state(KeyState::Valid, now),
+                None => {
+                    let mut trusted = TrustedKey {
+                        key,
+                        state: KeyState::Valid,
+                        since: now,
+                    };
+                    trusted.set_state(KeyState::Valid, now);
+                    anchors.keys.push(trusted);
+                }
+            }
+        }
+        ("accept", [zone, key_tag]) => {
+            anchors
+                .find_by_tag(&parse_name(zone), key_tag.parse()?)?
+                .set_state(KeyState::Valid, now);
+        }
+        ("remove", [zone, key_tag]) => {
+            anchors
+                .find_by_tag(&parse_name(zone), key_tag.parse()?)?
+                .set_state(KeyState::Removed, now);
+        }
+        _ => {
+            return Err(format!(
+                "Unknown command {}, expected list, \
+                 add <zone> <flags> <protocol> <algorithm> <key>, \
+                 accept <zone> <tag> or remove <zone> <tag>",
+                command
+            )
+            .into())
+        }
+    }
+
+    anchors.save()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const T0: u64 = 1_700_000_000;
+
+    fn ksk(public_key: u8, flags: u16) -> DnsRecord {
+        DnsRecord::DNSKEY {
+            domain: String::new(),
+            flags: DNSKEY_ZONE | DNSKEY_SEP | flags,
+            protocol: 3,
+            algorithm: 8,
+            public_key: vec![public_key; 32],
+            ttl: 3600,
+        }
+    }
+
+    fn anchor_file() -> AnchorFile {
+        AnchorFile {
+            path: String::new(),
+            keys: Vec::new(),
+        }
+    }
+
+    fn states(anchors: &AnchorFile) -> Vec<KeyState> {
+        anchors.keys.iter().map(|trusted| trusted.state).collect()
+    }
+
+    /// See the root's DNSKEY RRset `keys` at `now`, none of them trusted
+    /// beforehand and only `signer` signing it.
+    fn see(anchors: &mut AnchorFile, keys: &[&DnsRecord], signer: Option<u8>, now: u64) {
+        let signed = |key: &DnsRecord| matches!(key, DnsRecord::DNSKEY { public_key, .. } if Some(public_key[0]) == signer);
+        anchors.update("", keys, |_| false, signed, now);
+    }
+
+    #[test]
+    fn new_key_trusted_after_hold_down() {
+        let mut anchors = anchor_file();
+        let key = ksk(1, 0);
+
+        see(&mut anchors, &[&key], None, T0);
+        assert_eq!(states(&anchors), [KeyState::AddPend]);
+        assert_eq!(anchors.trusted().count(), 0);
+
+        see(&mut anchors, &[&key], None, T0 + ADD_HOLD_DOWN - 1);
+        assert_eq!(states(&anchors), [KeyState::AddPend]);
+
+        see(&mut anchors, &[&key], None, T0 + ADD_HOLD_DOWN);
+        assert_eq!(states(&anchors), [KeyState::Valid]);
+        assert_eq!(anchors.keys[0].since, T0 + ADD_HOLD_DOWN);
+        // trusted without its TTL
+        assert_eq!(
+            anchors.trusted().collect::<Vec<_>>(),
+            [&without_revoke(&key)]
+        );
+
+        // a key the validator already trusts skips the hold-down
+        let mut anchors = anchor_file();
+        anchors.update("", &[&key], |_| true, |_| false, T0);
+        assert_eq!(states(&anchors), [KeyState::Valid]);
+    }
+
+    #[test]
+    fn pending_key_starts_over_when_gone() {
+        let mut anchors = anchor_file();
+        see(&mut anchors, &[&ksk(1, 0)], None, T0);
+        see(&mut anchors, &[&ksk(2, 0)], None, T0 + 10);
+        assert_eq!(states(&anchors), [KeyState::AddPend]);
+        assert_eq!(anchors.keys[0].key, without_revoke(&ksk(2, 0)));
+
+        see(
+            &mut anchors,
+            &[&ksk(1, 0), &ksk(2, 0)],
+            None,
+            T0 + ADD_HOLD_DOWN,
+        );
+        assert_eq!(states(&anchors), [KeyState::AddPend, KeyState::AddPend]);
+    }
+
+    #[test]
+    fn missing_key_still_trusted() {
+        let mut anchors = anchor_file();
+        let (old, new) = (ksk(1, 0), ksk(2, 0));
+        anchors.update("", &[&old], |_| true, |_| false, T0);
+
+        see(&mut anchors, &[&new], None, T0 + 10);
+        assert_eq!(states(&anchors), [KeyState::Missing, KeyState::AddPend]);
+        assert_eq!(anchors.trusted().count(), 1);
+
+        see(&mut anchors, &[&old, &new], None, T0 + 20);
+        assert_eq!(states(&anchors), [KeyState::Valid, KeyState::AddPend]);
+    }
+
+    #[test]
+    fn revoked_by_itself_only() {
+        let mut anchors = anchor_file();
+        let (key, revoked) = (ksk(1, 0), ksk(1, DNSKEY_REVOKE));
+        anchors.update("", &[&key], |_| true, |_| false, T0);
+
+        // another key can't revoke it
+        see(&mut anchors, &[&revoked, &ksk(2, 0)], Some(2), T0 + 10);
+        assert_eq!(states(&anchors)[0], KeyState::Valid);
+
+        see(&mut anchors, &[&revoked, &ksk(2, 0)], Some(1), T0 + 20);
+        assert_eq!(states(&anchors)[0], KeyState::Revoked);
+        assert!(anchors.trusted().all(|trusted| !same_key(trusted, &key)));
+
+        // it stays revoked, and is forgotten after the remove hold-down
+        see(&mut anchors, &[&key], None, T0 + 30);
+        assert_eq!(states(&anchors)[0], KeyState::Revoked);
+        see(&mut anchors, &[], None, T0 + 30 + REMOVE_HOLD_DOWN);
+        assert_eq!(states(&anchors)[0], KeyState::Removed);
+    }
+}
@@ -0,0 +1,194 @@
+use crate::context::{Answer, Context};
+use crate::dnssec::{Fetch, Security};
+use crate::metrics::Metrics;
+use crate::packet::*;
+use crate::resolver::recursion_lookup;
+use crate::router::Route;
+use std::sync::Arc;
+use tokio::time;
+
+/// Answer from the cache if possible, otherwise resolve and validate the
+/// question, unless the client disabled checking. Expired answers are
+/// served when that fails or takes too long.
+pub async fn answer_question(
+    question: &DnsQuestion,
+    context: &Arc<Context>,
+    checking_disabled: bool,
+) -> Result<(DnsPacket, Option<Security>)> {
+    let validate = context.validator.is_some() && !checking_disabled;
+
+    if let Some(cached) = context.cache.get(&question.name, question.qtype, validate) {
+        if context.cache.prefetch_due(&question.name, question.qtype) {
+            let question = question.clone();
+            let context = context.clone();
+            tokio::spawn(async move {
+                Metrics::count(&context.metrics.prefetches);
+                let result = refresh_question(&question, &context, false)
+                    .await
+                    .map_err(|err| err.to_string());
+                match result {
+                    Ok((_, Some(Security::Bogus(err)))) | Err(err) => {
+                        eprintln!("Failed to prefetch {}: {}", question.name, err);
+                        // the cached answer stays, to be prefetched again
+                        // on its next hit
+                        context
+                            .cache
+                            .prefetch_failed(&question.name, question.qtype);
+                    }
+                    Ok(_) => {}
+                }
+            });
+        }
+        return Ok(cached);
+    }
+    if validate && context.config.aggressive_nsec {
+        if let Some(packet) = context.cache.synthesize(&question.name, question.qtype) {
+            println!("Synthesized answer for {} from cached NSEC", question.name);
+            return Ok((packet, Some(Security::Secure)));
+        }
+    }
+
+    let Some(stale) = context
+        .cache
+        .get_stale(&question.name, question.qtype, validate)
+    else {
+        return refresh_question(question, context, checking_disabled).await;
+    };
+    if stale.failed_recently {
+        Metrics::count(&context.metrics.stale_after_failure);
+        return Ok((stale.packet, stale.security));
+    }
+
+    // keep resolving in the background if the client can't wait for it,
+    // the cache gets the answer either way
+    let refresh = {
+        let question = question.clone();
+        let context = context.clone();
+        tokio::spawn(async move {
+            let result = refresh_question(&question, &context, checking_disabled).await;
+            if result.is_err() {
+                context
+                    .cache
+                    .resolution_failed(&question.name, question.qtype);
+            }
+            result.map_err(|err| err.to_string())
+        })
+    };
+
+    match time::timeout(context.config.stale_answer_timeout, refresh).await {
+        Ok(Ok(Ok(fresh))) => return Ok(fresh),
+        Ok(Ok(Err(err))) => {
+            eprintln!(
+                "Failed to resolve {}, serving it stale: {}",
+                question.name, err
+            );
+            Metrics::count(&context.metrics.stale_after_failure);
+        }
+        Ok(Err(err)) => {
+            eprintln!(
+                "Failed to resolve {}, serving it stale: {}",
+                question.name, err
+            );
+            Metrics::count(&context.metrics.stale_after_failure);
+        }
+        Err(_) => {
+            println!(
+                "Resolving {} takes too long, serving it stale",
+                question.name
+            );
+            Metrics::count(&context.metrics.stale_after_timeout);
+        }
+    }
+
+    Ok((stale.packet, stale.security))
+}
+
+/// Resolve and validate the question, caching the answer. Clients asking
+/// the same question meanwhile get the same answer rather than starting a
+/// resolution of their own, which also leaves a spoofer a single query to
+/// race instead of one per client.
+async fn refresh_question(
+    question: &DnsQuestion,
+    context: &Context,
+    checking_disabled: bool,
+) -> Result<Answer> {
+    let key = (question.name.clone(), question.qtype, checking_disabled);
+    let mut joined = true;
+    let resolution = async {
+        joined = false;
+        resolve_and_validate(question, context, checking_disabled)
+            .await
+            .map_err(|err| err.to_string())
+    };
+    let result = context.resolutions.run(key, resolution).await?;
+    if joined {
+        Metrics::count(&context.metrics.coalesced_queries);
+    }
+
+    Ok(result?)
+}
+
+async fn resolve_and_validate(
+    question: &DnsQuestion,
+    context: &Context,
+    checking_disabled: bool,
+) -> Result<Answer> {
+    let validator = context.validator.as_ref().filter(|_| !checking_disabled);
+
+    let result = resolve_question(question, context).await?;
+    let security = match validator {
+        Some(validator) => Some(
+            validator
+                .validate(&question.name, question.qtype, &result, &*fetch(context))
+                .await,
+        ),
+        None => None,
+    };
+    context
+        .cache
+        .insert(&question.name, question.qtype, &result, security.clone());
+
+    Ok((result, security))
+}
+
+/// Resolve what the validator asks for the same way as client questions.
+pub fn fetch(context: &Context) -> Box<Fetch<'_>> {
+    Box::new(move |name, qtype| {
+        Box::pin(async move { resolve_question(&DnsQuestion::new(&name, qtype), context).await })
+    })
+}
+
+pub async fn resolve_question(question: &DnsQuestion, context: &Context) -> Result<DnsPacket> {
+    let forwarder = match context.router.route(&question.name) {
+        Route::Forward(forwarder) => forwarder,
+        Route::Recurse => {
+            return recursion_lookup(
+                &question.name,
+                question.qtype,
+                &context.config,
+                &context.rtts,
+            )
+            .await
+        }
+        Route::Refuse => {
+            let mut response = DnsPacket::new();
+            response.header.rescode = ResultCode::REFUSED;
+            return Ok(response);
+        }
+    };
+
+    match forwarder.forward(&question.name, question.qtype).await {
+        Ok(response) => Ok(response),
+        Err(err) if context.config.forward_fallback => {
+            eprintln!("Forwarding failed, recursing instead: {}", err);
+            recursion_lookup(
+                &question.name,
+                question.qtype,
+                &context.config,
+                &context.rtts,
+            )
+            .await
+        }
+        Err(err) => Err(err),
+    }
+}
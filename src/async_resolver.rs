@@ -0,0 +1,60 @@
+use crate::answer::answer_question;
+use crate::config::Config;
+use crate::context::Context;
+use crate::dnssec::Security;
+use crate::packet::*;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+/// Resolver for use from tokio, answering the way the server does: from the
+/// cache when possible, otherwise resolving and validating upstream.
+///
+/// Upstream queries are made on tokio's sockets, so waiting on a slow
+/// upstream never holds up a thread. Only `workers` questions are resolved
+/// at a time, the others waiting their turn.
+#[derive(Clone)]
+pub struct Resolver {
+    context: Arc<Context>,
+    workers: Arc<Semaphore>,
+}
+
+impl Resolver {
+    pub fn new(config: Config) -> Result<Resolver> {
+        let workers = Arc::new(Semaphore::new(config.workers));
+        Ok(Resolver {
+            context: Arc::new(Context::new(config)?),
+            workers,
+        })
+    }
+
+    /// The configuration and state shared with every lookup.
+    pub fn context(&self) -> &Arc<Context> {
+        &self.context
+    }
+
+    /// Look up `name`, failing when its answer doesn't validate. The AD bit
+    /// of the answer tells whether it was proven secure.
+    pub async fn lookup(&self, name: &str, qtype: QueryType) -> Result<DnsPacket> {
+        let question = DnsQuestion::new(name, qtype);
+        match self.answer(&question, false).await? {
+            (_, Some(Security::Bogus(err))) => {
+                Err(format!("Bogus answer for {}: {}", name, err).into())
+            }
+            (mut packet, security) => {
+                packet.header.authed_data = security == Some(Security::Secure);
+                Ok(packet)
+            }
+        }
+    }
+
+    /// Answer `question`, along with how it validated unless validation is
+    /// off or `checking_disabled` is set.
+    pub async fn answer(
+        &self,
+        question: &DnsQuestion,
+        checking_disabled: bool,
+    ) -> Result<(DnsPacket, Option<Security>)> {
+        let _worker = self.workers.acquire().await?;
+        answer_question(question, &self.context, checking_disabled).await
+    }
+}
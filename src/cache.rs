@@ -0,0 +1,732 @@
+use crate::dnssec::{denial_proof, denied_name, Security};
+use crate::packet::*;
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Longest any record is cached, whatever its TTL.
+const MAX_CACHE_TTL: u32 = 86400;
+/// Longest a negative answer is cached (RFC 2308 section 5).
+const MAX_NEGATIVE_TTL: u32 = 10800;
+/// TTL of expired records when they're served anyway (RFC 8767 section 4).
+const STALE_TTL: u32 = 30;
+/// How long to keep serving expired records without trying to resolve
+/// them again once that failed.
+const STALE_RETRY: Duration = Duration::from_secs(30);
+
+struct Entry {
+    packet: DnsPacket,
+    /// `None` until the answer has been validated.
+    security: Option<Security>,
+    stored: Instant,
+    expires: Instant,
+    /// Set when refreshing the expired answer failed.
+    failed: Option<Instant>,
+    /// How many times the answer was served from the cache.
+    hits: u32,
+    /// Whether the answer is being refreshed ahead of its expiry.
+    prefetching: bool,
+}
+
+type Key = (String, QueryType);
+
+/// Cached answers, along with an index of when they expire so that the
+/// first to go is found without looking at all of them.
+struct Entries {
+    map: HashMap<Key, Entry>,
+    by_expiry: BTreeSet<(Instant, Key)>,
+}
+
+impl Entries {
+    fn insert(&mut self, key: Key, entry: Entry) {
+        if let Some(old) = self.map.get(&key) {
+            self.by_expiry.remove(&(old.expires, key.clone()));
+        }
+        self.by_expiry.insert((entry.expires, key.clone()));
+        self.map.insert(key, entry);
+    }
+
+    /// Drop the answer that expires first.
+    fn remove_first(&mut self) {
+        if let Some((_, key)) = self.by_expiry.pop_first() {
+            self.map.remove(&key);
+        }
+    }
+}
+
+/// An expired answer, served when it can't be resolved again in time.
+pub struct Stale {
+    pub packet: DnsPacket,
+    pub security: Option<Security>,
+    /// Resolving it failed moments ago, so don't bother trying again yet.
+    pub failed_recently: bool,
+}
+
+/// A cached answer as kept in a snapshot.
+pub struct SavedEntry {
+    pub qname: String,
+    pub qtype: QueryType,
+    /// The answer with the TTLs it had when saved.
+    pub packet: DnsPacket,
+    pub security: Option<Security>,
+    /// Seconds the answer had left when saved, negative once expired.
+    pub remaining: i64,
+    pub hits: u32,
+}
+
+/// Validated records proving names don't exist in a zone, for RFC 8198.
+struct Denials {
+    /// The zone's SOA with its signatures.
+    soa: Vec<DnsRecord>,
+    soa_expires: Instant,
+    /// NSEC or NSEC3 records by owner, each with its signatures.
+    records: HashMap<String, (Vec<DnsRecord>, Instant)>,
+}
+
+/// Answers by name and type, with their TTLs counting down as they sit
+/// in the cache.
+pub struct Cache {
+    max_entries: usize,
+    /// Hits after which an answer is refreshed before it expires, 0 to never.
+    prefetch_hits: u32,
+    /// How long answers are kept past their expiry to be served stale.
+    stale_window: Duration,
+    entries: Mutex<Entries>,
+    denials: Mutex<HashMap<String, Denials>>,
+}
+
+impl Cache {
+    pub fn new(max_entries: usize, prefetch_hits: u32, stale_window: Duration) -> Cache {
+        Cache {
+            max_entries,
+            prefetch_hits,
+            stale_window,
+            entries: Mutex::new(Entries {
+                map: HashMap::new(),
+                by_expiry: BTreeSet::new(),
+            }),
+            denials: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// A fresh answer, only if it's been validated when `validated` is set.
+    pub fn get(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        validated: bool,
+    ) -> Option<(DnsPacket, Option<Security>)> {
+        let mut entries = self.entries.lock().ok()?;
+        let entry = entries.map.get_mut(&(qname.to_ascii_lowercase(), qtype))?;
+
+        let now = Instant::now();
+        if entry.expires <= now || (validated && entry.security.is_none()) {
+            return None;
+        }
+        entry.hits = entry.hits.saturating_add(1);
+
+        let elapsed = now.duration_since(entry.stored).as_secs() as u32;
+        let mut packet = entry.packet.clone();
+        for record in packet
+            .answers
+            .iter_mut()
+            .chain(packet.authorities.iter_mut())
+            .chain(packet.resources.iter_mut())
+        {
+            record.set_ttl(record.ttl().saturating_sub(elapsed));
+        }
+
+        Some((packet, entry.security.clone()))
+    }
+
+    /// Whether a popular answer is in the last tenth of its TTL and should
+    /// be refreshed now, so clients never wait for it. Only says so once.
+    pub fn prefetch_due(&self, qname: &str, qtype: QueryType) -> bool {
+        let Ok(mut entries) = self.entries.lock() else {
+            return false;
+        };
+        let Some(entry) = entries.map.get_mut(&(qname.to_ascii_lowercase(), qtype)) else {
+            return false;
+        };
+
+        let now = Instant::now();
+        let ttl = entry.expires.saturating_duration_since(entry.stored);
+        let remaining = entry.expires.saturating_duration_since(now);
+        let due = self.prefetch_hits > 0
+            && entry.hits >= self.prefetch_hits
+            && !entry.prefetching
+            && remaining * 10 < ttl;
+        if due {
+            entry.prefetching = true;
+        }
+
+        due
+    }
+
+    /// Refreshing the answer ahead of its expiry failed, so it's due again
+    /// on a later hit rather than left to expire.
+    pub fn prefetch_failed(&self, qname: &str, qtype: QueryType) {
+        if let Ok(mut entries) = self.entries.lock() {
+            if let Some(entry) = entries.map.get_mut(&(qname.to_ascii_lowercase(), qtype)) {
+                entry.prefetching = false;
+            }
+        }
+    }
+
+    /// An expired answer still within the stale window (RFC 8767).
+    pub fn get_stale(&self, qname: &str, qtype: QueryType, validated: bool) -> Option<Stale> {
+        let entries = self.entries.lock().ok()?;
+        let entry = entries.map.get(&(qname.to_ascii_lowercase(), qtype))?;
+
+        let now = Instant::now();
+        if entry.expires + self.stale_window <= now || (validated && entry.security.is_none()) {
+            return None;
+        }
+
+        let mut packet = entry.packet.clone();
+        for record in packet
+            .answers
+            .iter_mut()
+            .chain(packet.authorities.iter_mut())
+            .chain(packet.resources.iter_mut())
+        {
+            record.set_ttl(STALE_TTL);
+        }
+
+        Some(Stale {
+            packet,
+            security: entry.security.clone(),
+            failed_recently: entry
+                .failed
+                .is_some_and(|failed| failed + STALE_RETRY > now),
+        })
+    }
+
+    /// Everything in the cache, to be restored after a restart.
+    pub fn save(&self) -> Vec<SavedEntry> {
+        let Ok(entries) = self.entries.lock() else {
+            return Vec::new();
+        };
+        let now = Instant::now();
+
+        entries
+            .map
+            .iter()
+            .map(|((qname, qtype), entry)| {
+                let elapsed = now.duration_since(entry.stored).as_secs() as u32;
+                let mut packet = entry.packet.clone();
+                for record in packet
+                    .answers
+                    .iter_mut()
+                    .chain(packet.authorities.iter_mut())
+                    .chain(packet.resources.iter_mut())
+                {
+                    record.set_ttl(record.ttl().saturating_sub(elapsed));
+                }
+
+                let remaining = if entry.expires > now {
+                    (entry.expires - now).as_secs() as i64
+                } else {
+                    -((now - entry.expires).as_secs() as i64)
+                };
+
+                SavedEntry {
+                    qname: qname.clone(),
+                    qtype: *qtype,
+                    packet,
+                    security: entry.security.clone(),
+                    remaining,
+                    hits: entry.hits,
+                }
+            })
+            .collect()
+    }
+
+    /// Put back answers saved `age` ago, dropping the ones that have since
+    /// expired beyond the stale window.
+    pub fn restore(&self, saved: Vec<SavedEntry>, age: Duration) {
+        let now = Instant::now();
+        let age_secs = age.as_secs() as i64;
+
+        for mut saved in saved {
+            let remaining = saved.remaining - age_secs;
+            let expires = if remaining >= 0 {
+                now + Duration::from_secs(remaining as u64)
+            } else if remaining.unsigned_abs() < self.stale_window.as_secs() {
+                match now.checked_sub(Duration::from_secs(remaining.unsigned_abs())) {
+                    Some(expires) => expires,
+                    None => continue,
+                }
+            } else {
+                continue;
+            };
+
+            for record in saved
+                .packet
+                .answers
+                .iter_mut()
+                .chain(saved.packet.authorities.iter_mut())
+                .chain(saved.packet.resources.iter_mut())
+            {
+                record.set_ttl(record.ttl().saturating_sub(age_secs as u32));
+            }
+            if remaining > 0 && saved.security == Some(Security::Secure) {
+                self.insert_denials(&saved.qname, saved.qtype, &saved.packet);
+            }
+
+            if let Ok(mut entries) = self.entries.lock() {
+                if entries.map.len() >= self.max_entries {
+                    break;
+                }
+                entries.insert(
+                    (saved.qname, saved.qtype),
+                    Entry {
+                        packet: saved.packet,
+                        security: saved.security,
+                        stored: now,
+                        expires,
+                        failed: None,
+                        hits: saved.hits,
+                        prefetching: false,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Note that refreshing an expired answer failed.
+    pub fn resolution_failed(&self, qname: &str, qtype: QueryType) {
+        if let Ok(mut entries) = self.entries.lock() {
+            if let Some(entry) = entries.map.get_mut(&(qname.to_ascii_lowercase(), qtype)) {
+                entry.failed = Some(Instant::now());
+            }
+        }
+    }
+
+    pub fn insert(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        packet: &DnsPacket,
+        security: Option<Security>,
+    ) {
+        if matches!(security, Some(Security::Bogus(_))) {
+            return;
+        }
+        let Some(ttl) = cache_ttl(packet) else {
+            return;
+        };
+
+        let mut packet = packet.clone();
+        packet
+            .resources
+            .retain(|record| record.qtype() != QueryType::OPT);
+
+        if security == Some(Security::Secure) {
+            self.insert_denials(qname, qtype, &packet);
+        }
+
+        let Ok(mut entries) = self.entries.lock() else {
+            return;
+        };
+        let now = Instant::now();
+        // drop answers too old to be served even stale, then make room by
+        // dropping whatever would expire first
+        while let Some((expires, _)) = entries.by_expiry.first() {
+            if *expires + self.stale_window > now && entries.map.len() < self.max_entries {
+                break;
+            }
+            entries.remove_first();
+        }
+
+        // a refreshed answer stays as popular as it was
+        let key = (qname.to_ascii_lowercase(), qtype);
+        let hits = entries.map.get(&key).map_or(0, |entry| entry.hits);
+        entries.insert(
+            key,
+            Entry {
+                packet,
+                security,
+                stored: now,
+                expires: now + Duration::from_secs(ttl as u64),
+                failed: None,
+                hits,
+                prefetching: false,
+            },
+        );
+    }
+
+    /// Remember the NSEC and NSEC3 records of a secure negative answer,
+    /// along with the SOA of their zone. Only the proof of a denial has
+    /// been validated, whatever else comes with other answers hasn't.
+    fn insert_denials(&self, qname: &str, qtype: QueryType, packet: &DnsPacket) {
+        if denied_name(&qname.to_ascii_lowercase(), qtype, packet).is_none() {
+            return;
+        }
+        let Some((zone, soa_ttl, minimum)) =
+            packet.authorities.iter().find_map(|record| match record {
+                DnsRecord::SOA {
+                    domain,
+                    ttl,
+                    minimum,
+                    ..
+                } => Some((domain.clone(), *ttl, *minimum)),
+                _ => None,
+            })
+        else {
+            return;
+        };
+        let Ok(mut denials) = self.denials.lock() else {
+            return;
+        };
+
+        let now = Instant::now();
+        let ttl = soa_ttl.min(minimum).min(MAX_NEGATIVE_TTL);
+        let denials = denials.entry(zone.clone()).or_insert_with(|| Denials {
+            soa: Vec::new(),
+            soa_expires: now,
+            records: HashMap::new(),
+        });
+        denials.soa = with_signatures(&packet.authorities, &zone, QueryType::SOA);
+        denials.soa_expires = now + Duration::from_secs(ttl as u64);
+        denials.records.retain(|_, (_, expires)| *expires > now);
+
+        for record in &packet.authorities {
+            let (DnsRecord::NSEC { domain, ttl, .. } | DnsRecord::NSEC3 { domain, ttl, .. }) =
+                record
+            else {
+                continue;
+            };
+            if !in_bailiwick(domain, &zone) {
+                continue;
+            }
+
+            let ttl = (*ttl).min(minimum).min(MAX_NEGATIVE_TTL);
+            denials.records.insert(
+                domain.clone(),
+                (
+                    with_signatures(&packet.authorities, domain, record.qtype()),
+                    now + Duration::from_secs(ttl as u64),
+                ),
+            );
+        }
+    }
+
+    /// Prove from cached NSEC or NSEC3 records that `qname` doesn't exist
+    /// or has no `qtype` records, without asking upstream (RFC 8198).
+    pub fn synthesize(&self, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
+        let qname = qname.to_ascii_lowercase();
+        let denials = self.denials.lock().ok()?;
+        let now = Instant::now();
+
+        let (zone, denials) = denials
+            .iter()
+            .filter(|(zone, denials)| in_bailiwick(&qname, zone) && denials.soa_expires > now)
+            .max_by_key(|(zone, _)| label_count(zone))?;
+
+        let records = denials
+            .records
+            .values()
+            .filter(|(_, expires)| *expires > now)
+            .flat_map(|(records, _)| records)
+            .filter(|record| record.qtype() != QueryType::RRSIG)
+            .collect::<Vec<_>>();
+        let (nxdomain, proof) = denial_proof(zone, &qname, qtype, &records)?;
+
+        let mut packet = DnsPacket::new();
+        packet.header.rescode = if nxdomain {
+            ResultCode::NXDOMAIN
+        } else {
+            ResultCode::NOERROR
+        };
+        let remaining = |expires: Instant| expires.saturating_duration_since(now).as_secs() as u32;
+        for record in &denials.soa {
+            let mut record = record.clone();
+            record.set_ttl(record.ttl().min(remaining(denials.soa_expires)));
+            packet.authorities.push(record);
+        }
+        for owner in proof.iter().map(|record| record.domain()) {
+            let Some((records, expires)) = denials.records.get(owner) else {
+                continue;
+            };
+            for record in records {
+                let mut record = record.clone();
+                record.set_ttl(record.ttl().min(remaining(*expires)));
+                packet.authorities.push(record);
+            }
+        }
+
+        Some(packet)
+    }
+}
+
+/// How long `packet` may be cached, if at all.
+fn cache_ttl(packet: &DnsPacket) -> Option<u32> {
+    let ttls = |records: &[DnsRecord]| {
+        records
+            .iter()
+            .filter(|record| record.qtype() != QueryType::OPT)
+            .map(DnsRecord::ttl)
+            .min()
+    };
+
+    match packet.header.rescode {
+        ResultCode::NOERROR if !packet.answers.is_empty() => {
+            Some(ttls(&packet.answers)?.min(MAX_CACHE_TTL))
+        }
+        // negative answers last as long as the SOA says (RFC 2308 section 5)
+        ResultCode::NOERROR | ResultCode::NXDOMAIN => {
+            packet.authorities.iter().find_map(|record| match record {
+                DnsRecord::SOA { ttl, minimum, .. } => {
+                    Some((*ttl).min(*minimum).min(MAX_NEGATIVE_TTL))
+                }
+                _ => None,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// The records of `domain` and `qtype` followed by the RRSIGs covering them.
+fn with_signatures(records: &[DnsRecord], domain: &str, qtype: QueryType) -> Vec<DnsRecord> {
+    let rrset = records
+        .iter()
+        .filter(|record| record.domain() == domain && record.qtype() == qtype);
+    let sigs = records.iter().filter(|record| {
+        matches!(record, DnsRecord::RRSIG { domain: owner, type_covered, .. }
+            if owner == domain && *type_covered == qtype)
+    });
+
+    rrset.chain(sigs).cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn soa() -> DnsRecord {
+        DnsRecord::SOA {
+            domain: "example.com".to_owned(),
+            mname: "ns.example.com".to_owned(),
+            rname: "hostmaster.example.com".to_owned(),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 300,
+            ttl: 300,
+        }
+    }
+
+    fn nsec(domain: &str, next_domain: &str) -> DnsRecord {
+        DnsRecord::NSEC {
+            domain: domain.to_owned(),
+            next_domain: next_domain.to_owned(),
+            types: vec![QueryType::A],
+            ttl: 300,
+        }
+    }
+
+    /// NSEC records proving b.example.com doesn't exist, wildcard included.
+    fn proof() -> Vec<DnsRecord> {
+        vec![
+            soa(),
+            nsec("example.com", "a.example.com"),
+            nsec("a.example.com", "z.example.com"),
+        ]
+    }
+
+    fn cache() -> Cache {
+        Cache::new(100, 0, Duration::ZERO)
+    }
+
+    fn answer(domain: &str, ttl: u32) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.answers.push(DnsRecord::A {
+            domain: domain.to_owned(),
+            addr: "192.0.2.1".parse().unwrap(),
+            ttl,
+        });
+        packet
+    }
+
+    #[test]
+    fn evicts_soonest_expiry() {
+        let cache = Cache::new(2, 0, Duration::ZERO);
+        cache.insert(
+            "a.example.com",
+            QueryType::A,
+            &answer("a.example.com", 100),
+            None,
+        );
+        cache.insert(
+            "b.example.com",
+            QueryType::A,
+            &answer("b.example.com", 50),
+            None,
+        );
+        // refreshing an answer doesn't leave its old expiry behind
+        cache.insert(
+            "a.example.com",
+            QueryType::A,
+            &answer("a.example.com", 300),
+            None,
+        );
+        cache.insert(
+            "c.example.com",
+            QueryType::A,
+            &answer("c.example.com", 200),
+            None,
+        );
+
+        assert!(cache.get("a.example.com", QueryType::A, false).is_some());
+        assert!(cache.get("b.example.com", QueryType::A, false).is_none());
+        assert!(cache.get("c.example.com", QueryType::A, false).is_some());
+
+        let entries = cache.entries.lock().unwrap();
+        assert_eq!(entries.map.len(), 2);
+        assert_eq!(entries.by_expiry.len(), 2);
+    }
+
+    #[test]
+    fn synthesizes_no_data() {
+        let cache = cache();
+        let mut packet = DnsPacket::new();
+        packet.authorities = proof();
+        cache.insert(
+            "a.example.com",
+            QueryType::MX,
+            &packet,
+            Some(Security::Secure),
+        );
+
+        let synthesized = cache.synthesize("a.example.com", QueryType::AAAA).unwrap();
+        assert_eq!(synthesized.header.rescode, ResultCode::NOERROR);
+        assert!(synthesized.answers.is_empty());
+        // the NSEC shows A records exist
+        assert!(cache.synthesize("a.example.com", QueryType::A).is_none());
+        // and says nothing of other zones
+        assert!(cache.synthesize("b.example.net", QueryType::A).is_none());
+    }
+
+    #[test]
+    fn synthesizes_from_secure_denial() {
+        let cache = cache();
+        let mut packet = DnsPacket::new();
+        packet.header.rescode = ResultCode::NXDOMAIN;
+        packet.authorities = proof();
+        cache.insert(
+            "c.example.com",
+            QueryType::A,
+            &packet,
+            Some(Security::Secure),
+        );
+
+        let synthesized = cache.synthesize("b.example.com", QueryType::A).unwrap();
+        assert_eq!(synthesized.header.rescode, ResultCode::NXDOMAIN);
+        assert_eq!(synthesized.authorities.len(), 3);
+        // a.example.com exists
+        assert!(cache.synthesize("a.example.com", QueryType::A).is_none());
+    }
+
+    #[test]
+    fn ignores_nsec_of_positive_answer() {
+        // the answer was validated, the NSEC records next to it weren't
+        let cache = cache();
+        let mut packet = DnsPacket::new();
+        packet.answers.push(DnsRecord::A {
+            domain: "www.example.com".to_owned(),
+            addr: "192.0.2.1".parse().unwrap(),
+            ttl: 300,
+        });
+        packet.authorities = proof();
+        cache.insert(
+            "www.example.com",
+            QueryType::A,
+            &packet,
+            Some(Security::Secure),
+        );
+
+        assert!(cache.synthesize("b.example.com", QueryType::A).is_none());
+    }
+
+    #[test]
+    fn ignores_insecure_denial() {
+        let cache = cache();
+        let mut packet = DnsPacket::new();
+        packet.header.rescode = ResultCode::NXDOMAIN;
+        packet.authorities = proof();
+        cache.insert(
+            "c.example.com",
+            QueryType::A,
+            &packet,
+            Some(Security::Insecure),
+        );
+
+        assert!(cache.synthesize("b.example.com", QueryType::A).is_none());
+    }
+
+    #[test]
+    fn prefetches_again_after_failure() {
+        let cache = Cache::new(100, 1, Duration::ZERO);
+        cache.insert(
+            "a.example.com",
+            QueryType::A,
+            &answer("a.example.com", 100),
+            None,
+        );
+        assert!(cache.get("a.example.com", QueryType::A, false).is_some());
+        assert!(!cache.prefetch_due("a.example.com", QueryType::A));
+
+        // into the last tenth of its TTL
+        if let Some(entry) = cache
+            .entries
+            .lock()
+            .unwrap()
+            .map
+            .get_mut(&("a.example.com".to_owned(), QueryType::A))
+        {
+            entry.stored -= Duration::from_secs(95);
+            entry.expires -= Duration::from_secs(95);
+        }
+        assert!(cache.prefetch_due("a.example.com", QueryType::A));
+        assert!(!cache.prefetch_due("A.example.com", QueryType::A));
+
+        cache.prefetch_failed("a.example.com", QueryType::A);
+        assert!(cache.prefetch_due("a.example.com", QueryType::A));
+    }
+
+    fn ttl(cache: &Cache, qname: &str) -> Option<u32> {
+        let (packet, _) = cache.get(qname, QueryType::A, false)?;
+        Some(packet.answers[0].ttl())
+    }
+
+    #[test]
+    fn restores_what_is_left() {
+        let cache = cache();
+        for (domain, ttl) in [("a.example.com", 300), ("b.example.com", 60)] {
+            cache.insert(domain, QueryType::A, &answer(domain, ttl), None);
+        }
+        let saved = cache.save();
+        assert_eq!(saved.len(), 2);
+
+        // saved 80 seconds ago, the short lived answer is gone
+        let restored = Cache::new(100, 0, Duration::ZERO);
+        restored.restore(cache.save(), Duration::from_secs(80));
+        assert_eq!(ttl(&restored, "a.example.com"), Some(220));
+        assert_eq!(ttl(&restored, "b.example.com"), None);
+        assert!(restored
+            .get_stale("b.example.com", QueryType::A, false)
+            .is_none());
+
+        // unless expired answers are kept to be served stale
+        let restored = Cache::new(100, 0, Duration::from_secs(3600));
+        restored.restore(saved, Duration::from_secs(80));
+        assert_eq!(ttl(&restored, "b.example.com"), None);
+        assert!(restored
+            .get_stale("b.example.com", QueryType::A, false)
+            .is_some());
+    }
+}
@@ -0,0 +1,493 @@
+use crate::packet::{DnsRecord, Result};
+use crate::tsig::{Algorithm, Key};
+use crate::zonefile::base64_decode;
+use std::{
+    fs,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QnameMinimisation {
+    Off,
+    /// Minimise, but fall back to the full name when a server misbehaves.
+    Relaxed,
+    /// Minimise and treat misbehaving servers as failures.
+    Strict,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressFamily {
+    Ipv4,
+    Ipv6,
+}
+
+impl AddressFamily {
+    pub fn of(addr: &IpAddr) -> AddressFamily {
+        match addr {
+            IpAddr::V4(_) => AddressFamily::Ipv4,
+            IpAddr::V6(_) => AddressFamily::Ipv6,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RouteAction {
+    Forward(Vec<SocketAddr>),
+    Recurse,
+    Refuse,
+}
+
+/// What to do with queries for names at or below `suffix`.
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub suffix: String,
+    pub action: RouteAction,
+}
+
+/// A zone served authoritatively from the zone file at `file`.
+#[derive(Debug, Clone)]
+pub struct LocalZone {
+    pub origin: String,
+    pub file: String,
+}
+
+/// A zone transferred from `primary` and served as a secondary, kept in
+/// `file` between restarts if given.
+#[derive(Debug, Clone)]
+pub struct SecondaryZone {
+    pub origin: String,
+    pub primary: SocketAddr,
+    pub file: Option<String>,
+}
+
+/// Secondaries told when the zone at `origin` changes.
+#[derive(Debug, Clone)]
+pub struct Notify {
+    pub origin: String,
+    pub secondaries: Vec<SocketAddr>,
+}
+
+/// Clients allowed to do something, as networks in CIDR notation and as
+/// the names of the keys they sign their requests with.
+#[derive(Debug, Clone)]
+pub struct Acl {
+    networks: Vec<(IpAddr, u8)>,
+    keys: Vec<String>,
+}
+
+impl Acl {
+    /// An ACL allowing no one.
+    pub fn new() -> Acl {
+        Acl {
+            networks: Vec::new(),
+            keys: Vec::new(),
+        }
+    }
+
+    /// Whether the client at `addr` is allowed, having signed its request
+    /// with the key named `key` if any.
+    pub fn allows(&self, addr: IpAddr, key: Option<&str>) -> bool {
+        key.is_some_and(|key| self.keys.iter().any(|name| name == key))
+            || self
+                .networks
+                .iter()
+                .any(|(network, prefix)| match (network, addr) {
+                    (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                        let mask = u32::MAX.checked_shl(32 - *prefix as u32).unwrap_or(0);
+                        u32::from(*network) & mask == u32::from(addr) & mask
+                    }
+                    (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                        let mask = u128::MAX.checked_shl(128 - *prefix as u32).unwrap_or(0);
+                        u128::from(*network) & mask == u128::from(addr) & mask
+                    }
+                    _ => false,
+                })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub qname_minimisation: QnameMinimisation,
+    /// Address families used to reach upstream servers, preferred first.
+    pub address_families: Vec<AddressFamily>,
+    /// Resolvers to forward queries to instead of recursing from the root.
+    pub forwarders: Vec<SocketAddr>,
+    /// Recurse ourselves when none of the forwarders can answer.
+    pub forward_fallback: bool,
+    /// Per domain overrides of how queries are answered.
+    pub routes: Vec<Route>,
+    pub dnssec_validation: bool,
+    /// DS records trusted without proof, the root zone's keys when empty.
+    pub trust_anchors: Vec<DnsRecord>,
+    /// State file keeping the trust anchors up to date (RFC 5011).
+    pub trust_anchor_file: Option<String>,
+    /// Most answers kept in the cache.
+    pub cache_size: usize,
+    /// Answer from cached NSEC and NSEC3 records when they prove a name
+    /// doesn't exist (RFC 8198).
+    pub aggressive_nsec: bool,
+    /// Where the cache is saved on shutdown and restored from on startup.
+    pub cache_file: Option<String>,
+    /// Hits after which an answer is refreshed in the background before it
+    /// expires, zero to never prefetch.
+    pub prefetch_hits: u32,
+    /// How long expired answers are kept to be served when resolving them
+    /// again fails (RFC 8767), zero to never serve them.
+    pub serve_stale: Duration,
+    /// How long a client waits for a fresh answer before a stale one is
+    /// served instead.
+    pub stale_answer_timeout: Duration,
+    /// Queries resolved at once.
+    pub workers: usize,
+    /// Queries waiting for a worker beyond which new ones are dropped.
+    pub queue_size: usize,
+    /// TCP connections open at once, further ones being closed right away.
+    pub tcp_connections: usize,
+    /// Zones answered from our own data.
+    pub zones: Vec<LocalZone>,
+    /// Zones transferred from their primaries.
+    pub secondaries: Vec<SecondaryZone>,
+    /// Secondaries to send NOTIFY to.
+    pub notify: Vec<Notify>,
+    /// Resolve names outside of our zones, which are refused otherwise.
+    pub recursion: bool,
+    /// Clients allowed to transfer our zones with AXFR and IXFR.
+    pub allow_transfer: Acl,
+    /// Clients allowed to change our zones with dynamic updates.
+    pub allow_update: Acl,
+    /// Keys messages are signed with (RFC 8945).
+    pub keys: Vec<Key>,
+    /// Servers that our requests to are signed, with the name of the key.
+    pub server_keys: Vec<(IpAddr, String)>,
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            qname_minimisation: QnameMinimisation::Relaxed,
+            address_families: vec![AddressFamily::Ipv6, AddressFamily::Ipv4],
+            forwarders: Vec::new(),
+            forward_fallback: false,
+            routes: Vec::new(),
+            dnssec_validation: true,
+            trust_anchors: Vec::new(),
+            trust_anchor_file: None,
+            cache_size: 10000,
+            aggressive_nsec: true,
+            cache_file: None,
+            prefetch_hits: 3,
+            serve_stale: Duration::ZERO,
+            stale_answer_timeout: Duration::from_millis(1800),
+            workers: 16,
+            queue_size: 1024,
+            tcp_connections: 128,
+            zones: Vec::new(),
+            secondaries: Vec::new(),
+            notify: Vec::new(),
+            recursion: true,
+            allow_transfer: Acl::new(),
+            allow_update: Acl::new(),
+            keys: Vec::new(),
+            server_keys: Vec::new(),
+        }
+    }
+
+    /// Read a config file made of `option value...` lines, `#` starting a comment.
+    pub fn load(path: &str) -> Result<Config> {
+        let mut config = Config::new();
+
+        for (i, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            let mut words = line.split_whitespace();
+            let Some(option) = words.next() else {
+                continue;
+            };
+            let args = words.collect::<Vec<_>>();
+
+            config
+                .set(option, &args)
+                .map_err(|err| format!("{}:{}: {}", path, i + 1, err))?;
+        }
+
+        Ok(config)
+    }
+
+    fn set(&mut self, option: &str, args: &[&str]) -> Result<()> {
+        match option {
+            "qname-minimisation" => {
+                self.qname_minimisation = match single(option, args)? {
+                    "off" => QnameMinimisation::Off,
+                    "relaxed" => QnameMinimisation::Relaxed,
+                    "strict" => QnameMinimisation::Strict,
+                    other => return Err(format!("Invalid {} mode {}", option, other).into()),
+                }
+            }
+            "address-families" => {
+                let mut families = Vec::new();
+                for arg in args {
+                    let family = match *arg {
+                        "ipv4" => AddressFamily::Ipv4,
+                        "ipv6" => AddressFamily::Ipv6,
+                        other => return Err(format!("Invalid address family {}", other).into()),
+                    };
+                    if !families.contains(&family) {
+                        families.push(family);
+                    }
+                }
+                if families.is_empty() {
+                    return Err(format!("{} needs at least one value", option).into());
+                }
+                self.address_families = families;
+            }
+            "forwarders" => {
+                self.forwarders = args
+                    .iter()
+                    .map(|arg| parse_server(arg))
+                    .collect::<Result<_>>()?;
+            }
+            "forward-fallback" => self.forward_fallback = parse_switch(option, args)?,
+            "route" => {
+                let action = match args.get(1..) {
+                    Some(["recurse"]) => RouteAction::Recurse,
+                    Some(["refuse"]) => RouteAction::Refuse,
+                    Some(["forward", addrs @ ..]) if !addrs.is_empty() => RouteAction::Forward(
+                        addrs
+                            .iter()
+                            .map(|arg| parse_server(arg))
+                            .collect::<Result<_>>()?,
+                    ),
+                    _ => {
+                        return Err(format!(
+                            "{} takes a domain and then recurse, refuse or forward <servers>",
+                            option
+                        )
+                        .into())
+                    }
+                };
+                self.routes.push(Route {
+                    suffix: parse_name(args[0]),
+                    action,
+                });
+            }
+            "dnssec-validation" => self.dnssec_validation = parse_switch(option, args)?,
+            "trust-anchor" => {
+                let [zone, key_tag, algorithm, digest_type, digest] = args else {
+                    return Err(format!(
+                        "{} takes a zone, key tag, algorithm, digest type and digest",
+                        option
+                    )
+                    .into());
+                };
+                self.trust_anchors.push(DnsRecord::DS {
+                    domain: parse_name(zone),
+                    key_tag: key_tag.parse()?,
+                    algorithm: algorithm.parse()?,
+                    digest_type: digest_type.parse()?,
+                    digest: parse_hex(digest)?,
+                    ttl: 0,
+                });
+            }
+            "trust-anchor-file" => {
+                self.trust_anchor_file = Some(single(option, args)?.to_owned());
+            }
+            "cache-size" => self.cache_size = single(option, args)?.parse()?,
+            "aggressive-nsec" => self.aggressive_nsec = parse_switch(option, args)?,
+            "cache-file" => self.cache_file = Some(single(option, args)?.to_owned()),
+            "prefetch" => self.prefetch_hits = single(option, args)?.parse()?,
+            "serve-stale" => self.serve_stale = Duration::from_secs(single(option, args)?.parse()?),
+            "stale-answer-timeout" => {
+                self.stale_answer_timeout = Duration::from_millis(single(option, args)?.parse()?)
+            }
+            "workers" => match single(option, args)?.parse()? {
+                0 => return Err("At least one worker is needed".into()),
+                workers => self.workers = workers,
+            },
+            "queue-size" => self.queue_size = single(option, args)?.parse()?,
+            "tcp-connections" => self.tcp_connections = single(option, args)?.parse()?,
+            "zone" => {
+                let [origin, file] = args else {
+                    return Err(format!("{} takes a domain and a zone file", option).into());
+                };
+                self.zones.push(LocalZone {
+                    origin: parse_name(origin),
+                    file: file.to_string(),
+                });
+            }
+            "secondary" => {
+                let (origin, primary, file) = match args {
+                    [origin, primary] => (origin, primary, None),
+                    [origin, primary, file] => (origin, primary, Some(file.to_string())),
+                    _ => {
+                        return Err(format!(
+                            "{} takes a domain, its primary and optionally a file",
+                            option
+                        )
+                        .into())
+                    }
+                };
+                self.secondaries.push(SecondaryZone {
+                    origin: parse_name(origin),
+                    primary: parse_server(primary)?,
+                    file,
+                });
+            }
+            "notify" => {
+                let (origin, secondaries) = match args {
+                    [origin, secondaries @ ..] if !secondaries.is_empty() => (origin, secondaries),
+                    _ => {
+                        return Err(format!("{} takes a domain and its secondaries", option).into())
+                    }
+                };
+                self.notify.push(Notify {
+                    origin: parse_name(origin),
+                    secondaries: secondaries
+                        .iter()
+                        .map(|arg| parse_server(arg))
+                        .collect::<Result<_>>()?,
+                });
+            }
+            "recursion" => self.recursion = parse_switch(option, args)?,
+            "allow-transfer" => self.allow_transfer = parse_acl(args, &self.keys)?,
+            "allow-update" => self.allow_update = parse_acl(args, &self.keys)?,
+            "key" => {
+                let [name, algorithm, secret] = args else {
+                    return Err(format!(
+                        "{} takes a name, an algorithm and a base64 secret",
+                        option
+                    )
+                    .into());
+                };
+                let name = parse_name(name);
+                if self.key(&name).is_some() {
+                    return Err(format!("Key {} defined twice", name).into());
+                }
+                self.keys.push(Key {
+                    name,
+                    algorithm: Algorithm::from_name(algorithm)
+                        .ok_or_else(|| format!("Unsupported algorithm {}", algorithm))?,
+                    secret: base64_decode(secret)?,
+                });
+            }
+            "server-key" => {
+                let [server, key] = args else {
+                    return Err(format!("{} takes a server address and a key", option).into());
+                };
+                let server = server
+                    .parse::<IpAddr>()
+                    .map_err(|_| format!("Invalid address {}", server))?;
+                let key = key_name(key, &self.keys)?;
+                self.server_keys.retain(|(addr, _)| *addr != server);
+                self.server_keys.push((server, key));
+            }
+            _ => return Err(format!("Unknown option {}", option).into()),
+        }
+
+        Ok(())
+    }
+
+    pub fn key(&self, name: &str) -> Option<&Key> {
+        self.keys.iter().find(|key| key.name == name)
+    }
+
+    /// The key requests to the server at `addr` are signed with, if any.
+    pub fn server_key(&self, addr: IpAddr) -> Option<&Key> {
+        self.server_keys
+            .iter()
+            .find(|(server, _)| *server == addr)
+            .and_then(|(_, name)| self.key(name))
+    }
+}
+
+fn single<'a>(option: &str, args: &[&'a str]) -> Result<&'a str> {
+    match args {
+        [arg] => Ok(arg),
+        _ => Err(format!("{} takes exactly one value", option).into()),
+    }
+}
+
+fn parse_switch(option: &str, args: &[&str]) -> Result<bool> {
+    match single(option, args)? {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        other => Err(format!("{} must be on or off, not {}", option, other).into()),
+    }
+}
+
+/// A server given as `ip`, `ip:port` or `[ipv6]:port`, port 53 by default.
+fn parse_server(arg: &str) -> Result<SocketAddr> {
+    if let Ok(addr) = arg.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+
+    match arg.parse::<IpAddr>() {
+        Ok(ip) => Ok(SocketAddr::new(ip, 53)),
+        Err(_) => Err(format!("Invalid server address {}", arg).into()),
+    }
+}
+
+/// Networks given as `ip` or `ip/prefix`, a lone address being a network
+/// of its own, and keys among `keys` given as `key <name>`.
+fn parse_acl(args: &[&str], keys: &[Key]) -> Result<Acl> {
+    let mut networks = Vec::new();
+    let mut key_names = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if *arg == "key" {
+            let name = args.next().ok_or("Missing key name")?;
+            key_names.push(key_name(name, keys)?);
+            continue;
+        }
+
+        let (addr, prefix) = match arg.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (*arg, None),
+        };
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|_| format!("Invalid address {}", arg))?;
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| format!("Invalid prefix length in {}", arg))?,
+            None => max,
+        };
+        networks.push((addr, prefix));
+    }
+
+    Ok(Acl {
+        networks,
+        keys: key_names,
+    })
+}
+
+/// The name of a key among `keys`, the keys defined earlier in the config.
+fn key_name(arg: &str, keys: &[Key]) -> Result<String> {
+    let name = parse_name(arg);
+    if !keys.iter().any(|key| key.name == name) {
+        return Err(format!("Unknown key {}", arg).into());
+    }
+    Ok(name)
+}
+
+/// A domain name as written in the config, `.` being the root.
+pub fn parse_name(arg: &str) -> String {
+    arg.trim_end_matches('.').to_lowercase()
+}
+
+pub fn parse_hex(arg: &str) -> Result<Vec<u8>> {
+    if !arg.len().is_multiple_of(2) || !arg.is_ascii() {
+        return Err(format!("Invalid hex string {}", arg).into());
+    }
+
+    (0..arg.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&arg[i..i + 2], 16)?))
+        .collect()
+}
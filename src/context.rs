@@ -0,0 +1,62 @@
+use crate::cache::Cache;
+use crate::config::Config;
+use crate::dnssec::{Security, Validator};
+use crate::inflight::Inflight;
+use crate::metrics::Metrics;
+use crate::packet::{DnsPacket, QueryType, Result};
+use crate::resolver::RttTable;
+use crate::router::Router;
+use crate::snapshot;
+use crate::zone::Zones;
+
+/// Everything a query is handled with: the configuration and the state
+/// shared between queries.
+pub struct Context {
+    pub config: Config,
+    pub router: Router,
+    pub validator: Option<Validator>,
+    pub cache: Cache,
+    pub metrics: Metrics,
+    pub rtts: RttTable,
+    pub zones: Zones,
+    /// Questions being resolved, so that clients asking the same question
+    /// at the same time wait for a single resolution.
+    pub resolutions: Inflight<Resolution, std::result::Result<Answer, String>>,
+}
+
+/// A question being resolved, and whether its answer gets validated.
+pub type Resolution = (String, QueryType, bool);
+/// An answer, and how it validated.
+pub type Answer = (DnsPacket, Option<Security>);
+
+impl Context {
+    pub fn new(config: Config) -> Result<Context> {
+        let router = Router::new(&config);
+        let validator = match config.dnssec_validation {
+            true => Some(Validator::new(&config)?),
+            false => None,
+        };
+
+        let zones = Zones::load(&config)?;
+        let cache = Cache::new(config.cache_size, config.prefetch_hits, config.serve_stale);
+
+        let context = Context {
+            config,
+            router,
+            validator,
+            cache,
+            metrics: Metrics::new(),
+            rtts: RttTable::new(),
+            zones,
+            resolutions: Inflight::new(),
+        };
+
+        if let Some(path) = &context.config.cache_file {
+            if let Err(err) = snapshot::load(path, &context.cache, &context.rtts) {
+                eprintln!("Failed to restore the cache, starting cold: {}", err);
+            }
+        }
+
+        Ok(context)
+    }
+}
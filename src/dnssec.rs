@@ -0,0 +1,1737 @@
+use crate::anchors::{unix_now, AnchorFile};
+use crate::config::{parse_hex, Config};
+use crate::packet::*;
+use ring::{digest, signature};
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::{Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+/// NSEC3 iteration counts above this are treated as insecure (RFC 9276).
+const MAX_NSEC3_ITERATIONS: u16 = 150;
+/// Longest we trust what we learned about a zone's keys.
+const MAX_KEY_CACHE_TTL: u32 = 3600;
+/// Bounds on how often trust anchors are refreshed (RFC 5011 section 2.3).
+const MIN_ANCHOR_REFRESH: Duration = Duration::from_secs(3600);
+const MAX_ANCHOR_REFRESH: Duration = Duration::from_secs(15 * 24 * 3600);
+
+/// The root zone KSKs, KSK-2017 and KSK-2024.
+const ROOT_ANCHORS: [(u16, &str); 2] = [
+    (
+        20326,
+        "e06d44b80b8f1d39a95c0b0d7c65d08458e880409bbc683457104237c7f8ec8d",
+    ),
+    (
+        38696,
+        "683d2d0acb8c9b712a1948b27f741219298d0a450d612c483af444a4c0fb2b16",
+    ),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Security {
+    Secure,
+    Insecure,
+    Bogus(String),
+}
+
+/// What we know about the keys of a zone once its chain of trust is followed.
+#[derive(Debug, Clone)]
+enum ZoneKeys {
+    Secure(Vec<DnsRecord>),
+    Insecure,
+}
+
+/// Outcome of checking NSEC or NSEC3 records against a denial.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Proof {
+    Proven,
+    /// The records can't prove anything, e.g. an opted out NSEC3 span.
+    Insecure,
+    Unproven,
+}
+
+/// Outcome of asking the parent zone for the DS records of a name.
+enum Delegation {
+    Keys(ZoneKeys),
+    NotDelegated,
+}
+
+/// Resolves a name and type the same way client queries are.
+pub type Fetch<'a> = dyn Fn(String, QueryType) -> Pin<Box<dyn Future<Output = Result<DnsPacket>> + Send + 'a>>
+    + Send
+    + Sync
+    + 'a;
+
+/// Validates responses by following the chain of trust from the
+/// configured trust anchors down to the zone that signed them.
+pub struct Validator {
+    /// The anchors from the config, or the root's when none are configured.
+    configured: Vec<DnsRecord>,
+    /// Where anchors are kept up to date per RFC 5011, if anywhere.
+    anchor_file: Option<String>,
+    /// DS or DNSKEY records currently trusted without proof.
+    anchors: RwLock<Vec<DnsRecord>>,
+    keys: Mutex<HashMap<String, (ZoneKeys, Instant)>>,
+}
+
+impl Validator {
+    pub fn new(config: &Config) -> Result<Validator> {
+        let configured = if config.trust_anchors.is_empty() {
+            ROOT_ANCHORS
+                .iter()
+                .map(|(key_tag, digest)| DnsRecord::DS {
+                    domain: String::new(),
+                    key_tag: *key_tag,
+                    algorithm: 8,
+                    digest_type: 2,
+                    digest: parse_hex(digest).unwrap_or_default(),
+                    ttl: 0,
+                })
+                .collect()
+        } else {
+            config.trust_anchors.clone()
+        };
+
+        let validator = Validator {
+            configured,
+            anchor_file: config.trust_anchor_file.clone(),
+            anchors: RwLock::new(Vec::new()),
+            keys: Mutex::new(HashMap::new()),
+        };
+        match &validator.anchor_file {
+            Some(path) => validator.use_anchors(Some(&AnchorFile::load(path)?)),
+            None => validator.use_anchors(None),
+        }
+
+        Ok(validator)
+    }
+
+    pub fn manages_anchors(&self) -> bool {
+        self.anchor_file.is_some()
+    }
+
+    /// Trust the keys of the anchor file, falling back to the configured
+    /// anchors for zones it knows nothing about.
+    fn use_anchors(&self, file: Option<&AnchorFile>) {
+        let mut anchors = self
+            .configured
+            .iter()
+            .filter(|anchor| file.is_none_or(|file| !file.has_zone(anchor.domain())))
+            .cloned()
+            .collect::<Vec<_>>();
+        anchors.extend(file.into_iter().flat_map(AnchorFile::trusted).cloned());
+
+        if let Ok(mut current) = self.anchors.write() {
+            *current = anchors;
+        }
+        if let Ok(mut keys) = self.keys.lock() {
+            keys.clear();
+        }
+    }
+
+    fn anchors_of(&self, zone: &str) -> Vec<DnsRecord> {
+        self.anchors
+            .read()
+            .map(|anchors| {
+                anchors
+                    .iter()
+                    .filter(|anchor| anchor.domain() == zone)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Fetch the DNSKEY RRset of every zone with an anchor and update the
+    /// anchor file with it (RFC 5011). Returns when to refresh again.
+    pub async fn refresh_anchors(&self, fetch: &Fetch<'_>) -> Duration {
+        let Some(path) = &self.anchor_file else {
+            return MAX_ANCHOR_REFRESH;
+        };
+        let mut file = match AnchorFile::load(path) {
+            Ok(file) => file,
+            Err(err) => {
+                eprintln!("Failed to read trust anchors: {}", err);
+                return MIN_ANCHOR_REFRESH;
+            }
+        };
+
+        let mut zones = self
+            .configured
+            .iter()
+            .chain(file.keys.iter().map(|trusted| &trusted.key))
+            .map(|anchor| anchor.domain().to_owned())
+            .collect::<Vec<_>>();
+        zones.sort();
+        zones.dedup();
+
+        let now = unix_now();
+        let mut refresh = MAX_ANCHOR_REFRESH;
+        for zone in &zones {
+            let zone = zone.as_str();
+            let anchors = if file.has_zone(zone) {
+                file.trusted()
+                    .filter(|key| key.domain() == zone)
+                    .cloned()
+                    .collect()
+            } else {
+                self.configured
+                    .iter()
+                    .filter(|anchor| anchor.domain() == zone)
+                    .cloned()
+                    .collect::<Vec<_>>()
+            };
+            let anchors = anchors.iter().collect::<Vec<_>>();
+
+            let response = match fetch(zone.to_owned(), QueryType::DNSKEY).await {
+                Ok(response) => response,
+                Err(err) => {
+                    eprintln!("Failed to refresh trust anchors of {:?}: {}", zone, err);
+                    refresh = refresh.min(MIN_ANCHOR_REFRESH);
+                    continue;
+                }
+            };
+            let (keys, sigs) = rrset_of(&response.answers, zone, QueryType::DNSKEY);
+            if let Err(err) = verify_dnskeys(zone, &anchors, &keys, &sigs) {
+                eprintln!("Failed to refresh trust anchors of {:?}: {}", zone, err);
+                refresh = refresh.min(MIN_ANCHOR_REFRESH);
+                continue;
+            }
+
+            file.update(
+                zone,
+                &keys,
+                |key| anchors.iter().any(|anchor| anchor_matches(anchor, key)),
+                |key| self_signed(&keys, &sigs, key),
+                now,
+            );
+
+            // half the TTL or signature lifetime, whichever is shorter
+            let ttl = keys.iter().map(|key| key.ttl() as u64).min().unwrap_or(0);
+            let lifetime = sigs
+                .iter()
+                .filter_map(|sig| match sig {
+                    DnsRecord::RRSIG { expiration, .. } => {
+                        Some((*expiration as u64).saturating_sub(now))
+                    }
+                    _ => None,
+                })
+                .min()
+                .unwrap_or(0);
+            let wait = Duration::from_secs(ttl.min(lifetime) / 2);
+            refresh = refresh.min(wait.clamp(MIN_ANCHOR_REFRESH, MAX_ANCHOR_REFRESH));
+        }
+
+        if let Err(err) = file.save() {
+            eprintln!("Failed to save trust anchors: {}", err);
+        }
+        self.use_anchors(Some(&file));
+
+        refresh
+    }
+
+    pub async fn validate(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        response: &DnsPacket,
+        fetch: &Fetch<'_>,
+    ) -> Security {
+        match self.check_response(qname, qtype, response, fetch).await {
+            Ok(true) => Security::Secure,
+            Ok(false) => Security::Insecure,
+            Err(err) => Security::Bogus(err.to_string()),
+        }
+    }
+
+    /// Whether the response is secure, with errors meaning it's bogus.
+    async fn check_response(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        response: &DnsPacket,
+        fetch: &Fetch<'_>,
+    ) -> Result<bool> {
+        let mut secure = true;
+
+        for (rrset, sigs) in rrsets(&response.answers) {
+            secure &= self.verify_rrset(&rrset, &sigs, fetch).await?;
+
+            // an answer synthesized from a wildcard needs proof that
+            // there was nothing closer to the name
+            let owner = rrset[0].domain();
+            if let Some(labels) = sigs.iter().find_map(|sig| wildcard_labels(owner, sig)) {
+                secure &= self
+                    .verify_proof_records(&response.authorities, fetch)
+                    .await?;
+
+                let nsecs = of_type(&response.authorities, QueryType::NSEC);
+                let nsec3s = of_type(&response.authorities, QueryType::NSEC3);
+                let next_closer = ancestor(owner, labels + 1);
+                let proven = nsecs.iter().any(|nsec| nsec_covers(nsec, owner))
+                    || nsec3s.iter().any(|nsec3| nsec3_covers(nsec3, next_closer));
+                if !proven {
+                    return Err(format!("Wildcard answer for {} without proof", owner).into());
+                }
+            }
+        }
+
+        if let Some((target, nxdomain)) = denied_name(qname, qtype, response) {
+            secure &= self
+                .verify_denial(target, qtype, nxdomain, &response.authorities, fetch)
+                .await?;
+        }
+
+        Ok(secure)
+    }
+
+    /// Check an RRset against its signatures. Unsigned RRsets are fine as
+    /// long as they come from an insecure zone.
+    async fn verify_rrset(
+        &self,
+        rrset: &[&DnsRecord],
+        sigs: &[&DnsRecord],
+        fetch: &Fetch<'_>,
+    ) -> Result<bool> {
+        let owner = rrset[0].domain();
+        let qtype = rrset[0].qtype();
+
+        if sigs.is_empty() {
+            return match self.keys_for_name(owner, fetch).await? {
+                ZoneKeys::Insecure => Ok(false),
+                ZoneKeys::Secure(_) => {
+                    Err(format!("Missing signature over {} {:?}", owner, qtype).into())
+                }
+            };
+        }
+
+        let mut last_err = None;
+        for sig in sigs {
+            let DnsRecord::RRSIG { signer_name, .. } = sig else {
+                continue;
+            };
+            if !in_bailiwick(owner, signer_name) {
+                last_err = Some(format!("{} can't sign {} {:?}", signer_name, owner, qtype).into());
+                continue;
+            }
+
+            match self.zone_keys(signer_name, fetch).await? {
+                ZoneKeys::Insecure => return Ok(false),
+                ZoneKeys::Secure(keys) => match verify_signature(rrset, sig, &keys) {
+                    Ok(()) => return Ok(true),
+                    Err(err) => last_err = Some(err),
+                },
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| "No usable signature".into()))
+    }
+
+    /// Check that every SOA, NSEC and NSEC3 RRset in `records` is signed.
+    async fn verify_proof_records(&self, records: &[DnsRecord], fetch: &Fetch<'_>) -> Result<bool> {
+        let mut secure = true;
+        for (rrset, sigs) in rrsets(records) {
+            if matches!(
+                rrset[0].qtype(),
+                QueryType::SOA | QueryType::NSEC | QueryType::NSEC3
+            ) {
+                secure &= self.verify_rrset(&rrset, &sigs, fetch).await?;
+            }
+        }
+
+        Ok(secure)
+    }
+
+    /// Check that the authority section proves `name` or its `qtype` records
+    /// don't exist.
+    async fn verify_denial(
+        &self,
+        name: &str,
+        qtype: QueryType,
+        nxdomain: bool,
+        authorities: &[DnsRecord],
+        fetch: &Fetch<'_>,
+    ) -> Result<bool> {
+        let zone = authorities.iter().find_map(|record| match record {
+            DnsRecord::SOA { domain, .. } => Some(domain.as_str()),
+            DnsRecord::RRSIG {
+                type_covered: QueryType::NSEC | QueryType::NSEC3,
+                signer_name,
+                ..
+            } => Some(signer_name.as_str()),
+            _ => None,
+        });
+        let keys = match zone {
+            Some(zone) if in_bailiwick(name, zone) => self.zone_keys(zone, fetch).await?,
+            _ => self.keys_for_name(name, fetch).await?,
+        };
+        if let ZoneKeys::Insecure = keys {
+            return Ok(false);
+        }
+
+        if !self.verify_proof_records(authorities, fetch).await? {
+            return Ok(false);
+        }
+
+        let nsecs = of_type(authorities, QueryType::NSEC);
+        let nsec3s = of_type(authorities, QueryType::NSEC3);
+        let proof = if !nsecs.is_empty() {
+            nsec_denial(name, qtype, nxdomain, &nsecs)
+        } else if let Some(zone) = zone.filter(|_| !nsec3s.is_empty()) {
+            nsec3_denial(zone, name, qtype, nxdomain, &nsec3s)
+        } else {
+            Proof::Unproven
+        };
+
+        match proof {
+            Proof::Proven => Ok(true),
+            Proof::Insecure => Ok(false),
+            Proof::Unproven => {
+                Err(format!("Missing proof that {} {:?} doesn't exist", name, qtype).into())
+            }
+        }
+    }
+
+    /// The keys of the deepest zone `name` is in, following delegations
+    /// down from the root until they become insecure.
+    async fn keys_for_name(&self, name: &str, fetch: &Fetch<'_>) -> Result<ZoneKeys> {
+        let Some(anchor) = self.anchor_for(name) else {
+            return Ok(ZoneKeys::Insecure);
+        };
+        let mut keys = self.zone_keys(&anchor, fetch).await?;
+
+        for labels in label_count(&anchor) + 1..=label_count(name) {
+            if let ZoneKeys::Insecure = keys {
+                break;
+            }
+
+            let child = ancestor(name, labels);
+            if let Some(child_keys) = self.cached(child) {
+                keys = child_keys;
+                continue;
+            }
+            if let Delegation::Keys(child_keys) = self.delegation(child, fetch).await? {
+                self.cache(child, &child_keys);
+                keys = child_keys;
+            }
+        }
+
+        Ok(keys)
+    }
+
+    /// The deepest zone with a trust anchor that `name` is in.
+    fn anchor_for(&self, name: &str) -> Option<String> {
+        let anchors = self.anchors.read().ok()?;
+        anchors
+            .iter()
+            .map(DnsRecord::domain)
+            .filter(|zone| in_bailiwick(name, zone))
+            .max_by_key(|zone| label_count(zone))
+            .map(str::to_owned)
+    }
+
+    /// The keys of `zone`, which is known to be the apex of a zone.
+    async fn zone_keys(&self, zone: &str, fetch: &Fetch<'_>) -> Result<ZoneKeys> {
+        if let Some(keys) = self.cached(zone) {
+            return Ok(keys);
+        }
+
+        let anchor = self.anchor_for(zone);
+        let keys = if anchor.as_deref() == Some(zone) {
+            let anchors = self.anchors_of(zone);
+            self.dnskeys(zone, &anchors.iter().collect::<Vec<_>>(), fetch)
+                .await?
+        } else if anchor.is_none() {
+            ZoneKeys::Insecure
+        } else {
+            match self.delegation(zone, fetch).await? {
+                Delegation::Keys(keys) => keys,
+                Delegation::NotDelegated => {
+                    return Err(format!("{} is not a delegated zone", zone).into())
+                }
+            }
+        };
+
+        self.cache(zone, &keys);
+        Ok(keys)
+    }
+
+    /// Ask the parent for the DS records of `zone` and follow them to its keys.
+    ///
+    /// Validating the parent's records may lead back here for zones above,
+    /// so those calls are boxed.
+    async fn delegation(&self, zone: &str, fetch: &Fetch<'_>) -> Result<Delegation> {
+        let response = fetch(zone.to_owned(), QueryType::DS).await?;
+        let (ds_set, ds_sigs) = rrset_of(&response.answers, zone, QueryType::DS);
+
+        if !ds_set.is_empty() {
+            // the DS records must come from the parent, not the zone itself
+            let signed_by_child = ds_sigs.iter().any(|sig| {
+                matches!(sig, DnsRecord::RRSIG { signer_name, .. } if !in_bailiwick(zone, signer_name) || signer_name == zone)
+            });
+            if signed_by_child {
+                return Err(format!("DS records of {} not signed by its parent", zone).into());
+            }
+            if !Box::pin(self.verify_rrset(&ds_set, &ds_sigs, fetch)).await? {
+                return Ok(Delegation::Keys(ZoneKeys::Insecure));
+            }
+            return Ok(Delegation::Keys(self.dnskeys(zone, &ds_set, fetch).await?));
+        }
+
+        // no DS records, so the parent has to prove there are none
+        let parent = response
+            .authorities
+            .iter()
+            .find_map(|record| match record {
+                DnsRecord::SOA { domain, .. } if domain != zone && in_bailiwick(zone, domain) => {
+                    Some(domain.clone())
+                }
+                _ => None,
+            })
+            .unwrap_or_else(|| ancestor(zone, label_count(zone) - 1).to_owned());
+        if let ZoneKeys::Insecure = Box::pin(self.zone_keys(&parent, fetch)).await? {
+            return Ok(Delegation::Keys(ZoneKeys::Insecure));
+        }
+        if !Box::pin(self.verify_proof_records(&response.authorities, fetch)).await? {
+            return Ok(Delegation::Keys(ZoneKeys::Insecure));
+        }
+
+        let nsecs = of_type(&response.authorities, QueryType::NSEC);
+        let nsec3s = of_type(&response.authorities, QueryType::NSEC3);
+        let proof = if !nsecs.is_empty() {
+            nsec_delegation(zone, &nsecs)
+        } else if !nsec3s.is_empty() {
+            nsec3_delegation(&parent, zone, &nsec3s)
+        } else {
+            None
+        };
+
+        match proof {
+            Some(Proof::Proven) => Ok(Delegation::NotDelegated),
+            Some(Proof::Insecure) => Ok(Delegation::Keys(ZoneKeys::Insecure)),
+            _ => Err(format!("Missing proof that {} has no DS records", zone).into()),
+        }
+    }
+
+    /// Fetch the DNSKEY RRset of `zone` and check it's signed by a key one
+    /// of the `anchors` vouches for, DS records from the parent or trusted keys.
+    async fn dnskeys(
+        &self,
+        zone: &str,
+        anchors: &[&DnsRecord],
+        fetch: &Fetch<'_>,
+    ) -> Result<ZoneKeys> {
+        // with no anchor we can make sense of, the zone is as good as unsigned
+        if !anchors.iter().any(|anchor| is_supported_anchor(anchor)) {
+            return Ok(ZoneKeys::Insecure);
+        }
+
+        let response = fetch(zone.to_owned(), QueryType::DNSKEY).await?;
+        let (keys, sigs) = rrset_of(&response.answers, zone, QueryType::DNSKEY);
+
+        verify_dnskeys(zone, anchors, &keys, &sigs)
+    }
+
+    fn cached(&self, zone: &str) -> Option<ZoneKeys> {
+        let keys = self.keys.lock().ok()?;
+        keys.get(zone)
+            .filter(|(_, expires)| *expires > Instant::now())
+            .map(|(keys, _)| keys.clone())
+    }
+
+    fn cache(&self, zone: &str, keys: &ZoneKeys) {
+        let ttl = match keys {
+            ZoneKeys::Secure(keys) => keys.iter().map(DnsRecord::ttl).min().unwrap_or(0),
+            ZoneKeys::Insecure => MAX_KEY_CACHE_TTL,
+        };
+        let expires = Instant::now() + Duration::from_secs(ttl.min(MAX_KEY_CACHE_TTL) as u64);
+
+        if let Ok(mut cache) = self.keys.lock() {
+            cache.insert(zone.to_owned(), (keys.clone(), expires));
+        }
+    }
+}
+
+fn verify_dnskeys(
+    zone: &str,
+    anchors: &[&DnsRecord],
+    keys: &[&DnsRecord],
+    sigs: &[&DnsRecord],
+) -> Result<ZoneKeys> {
+    let anchors = anchors
+        .iter()
+        .filter(|anchor| is_supported_anchor(anchor))
+        .collect::<Vec<_>>();
+    if anchors.is_empty() {
+        return Ok(ZoneKeys::Insecure);
+    }
+
+    let trusted = keys
+        .iter()
+        .filter(|key| anchors.iter().any(|anchor| anchor_matches(anchor, key)))
+        .map(|key| (*key).clone())
+        .collect::<Vec<_>>();
+    if trusted.is_empty() {
+        return Err(format!("No DNSKEY of {} matches its trust anchors", zone).into());
+    }
+
+    for sig in sigs {
+        if verify_signature(keys, sig, &trusted).is_ok() {
+            return Ok(ZoneKeys::Secure(
+                keys.iter().map(|key| (*key).clone()).collect(),
+            ));
+        }
+    }
+
+    Err(format!("DNSKEY records of {} not signed by a trusted key", zone).into())
+}
+
+/// The name `response` says has no `qtype` records, the end of its CNAME
+/// chain, and whether the name doesn't exist at all. `None` if it answers.
+pub fn denied_name<'a>(
+    qname: &'a str,
+    qtype: QueryType,
+    response: &'a DnsPacket,
+) -> Option<(&'a str, bool)> {
+    // follow the CNAME chain to find out what was actually asked for
+    let mut target = qname;
+    if qtype != QueryType::CNAME {
+        for _ in 0..response.answers.len() {
+            match response.answers.iter().find_map(|record| match record {
+                DnsRecord::CNAME { domain, host, .. } if domain == target => Some(host),
+                _ => None,
+            }) {
+                Some(host) => target = host,
+                None => break,
+            }
+        }
+    }
+
+    let nxdomain = response.header.rescode == ResultCode::NXDOMAIN;
+    let answered = response
+        .answers
+        .iter()
+        .any(|record| record.domain() == target && record.qtype() == qtype);
+    (nxdomain || (!answered && response.header.rescode == ResultCode::NOERROR))
+        .then_some((target, nxdomain))
+}
+
+/// Group records into RRsets, each with the signatures covering it.
+fn rrsets(records: &[DnsRecord]) -> Vec<(Vec<&DnsRecord>, Vec<&DnsRecord>)> {
+    let mut keys = Vec::new();
+    for record in records {
+        let key = (record.domain(), record.qtype());
+        if key.1 != QueryType::RRSIG && key.1 != QueryType::OPT && !keys.contains(&key) {
+            keys.push(key);
+        }
+    }
+
+    keys.into_iter()
+        .map(|(domain, qtype)| rrset_of(records, domain, qtype))
+        .collect()
+}
+
+fn rrset_of<'a>(
+    records: &'a [DnsRecord],
+    domain: &str,
+    qtype: QueryType,
+) -> (Vec<&'a DnsRecord>, Vec<&'a DnsRecord>) {
+    let rrset = records
+        .iter()
+        .filter(|record| record.domain() == domain && record.qtype() == qtype)
+        .collect();
+    let sigs = records
+        .iter()
+        .filter(|record| {
+            matches!(record, DnsRecord::RRSIG { domain: owner, type_covered, .. }
+                if owner == domain && *type_covered == qtype)
+        })
+        .collect();
+
+    (rrset, sigs)
+}
+
+fn of_type(records: &[DnsRecord], qtype: QueryType) -> Vec<&DnsRecord> {
+    records
+        .iter()
+        .filter(|record| record.qtype() == qtype)
+        .collect()
+}
+
+/// If `sig` shows `owner` was synthesized from a wildcard, the number of
+/// labels of the wildcard's owner, not counting the `*`.
+fn wildcard_labels(owner: &str, sig: &DnsRecord) -> Option<usize> {
+    let DnsRecord::RRSIG { labels, .. } = sig else {
+        return None;
+    };
+
+    let mut owner_labels = label_count(owner);
+    if owner.starts_with("*.") || owner == "*" {
+        owner_labels -= 1;
+    }
+
+    ((*labels as usize) < owner_labels).then_some(*labels as usize)
+}
+
+fn verify_signature(rrset: &[&DnsRecord], sig: &DnsRecord, keys: &[DnsRecord]) -> Result<()> {
+    let DnsRecord::RRSIG {
+        domain,
+        type_covered,
+        algorithm,
+        key_tag,
+        signer_name,
+        signature,
+        ..
+    } = sig
+    else {
+        return Err("Not a signature".into());
+    };
+
+    if !is_current(sig) {
+        return Err(format!(
+            "Signature over {} {:?} is not current",
+            domain, type_covered
+        )
+        .into());
+    }
+
+    let data = signed_data(rrset, sig)?;
+    for key in keys {
+        let DnsRecord::DNSKEY {
+            domain: key_owner,
+            flags,
+            protocol,
+            algorithm: key_algorithm,
+            public_key,
+            ..
+        } = key
+        else {
+            continue;
+        };
+
+        let usable = key_owner == signer_name
+            && *protocol == 3
+            && flags & DNSKEY_ZONE != 0
+            && flags & DNSKEY_REVOKE == 0
+            && key_algorithm == algorithm
+            && key.rdata().map(|rdata| calc_key_tag(&rdata)).ok() == Some(*key_tag);
+        if usable && verify(*algorithm, public_key, &data, signature) {
+            return Ok(());
+        }
+    }
+
+    Err(format!(
+        "No key of {} verifies the signature over {} {:?}",
+        signer_name, domain, type_covered
+    )
+    .into())
+}
+
+/// Whether the validity window of an RRSIG includes the current time.
+fn is_current(sig: &DnsRecord) -> bool {
+    let DnsRecord::RRSIG {
+        expiration,
+        inception,
+        ..
+    } = sig
+    else {
+        return false;
+    };
+
+    let now = unix_now() as u32;
+    serial_le(*inception, now) && serial_le(now, *expiration)
+}
+
+/// The data an RRSIG signs: its own fields followed by the RRset in
+/// canonical form and order (RFC 4034 section 3.1.8.1).
+fn signed_data(rrset: &[&DnsRecord], sig: &DnsRecord) -> Result<Vec<u8>> {
+    let DnsRecord::RRSIG {
+        labels,
+        original_ttl,
+        ..
+    } = sig
+    else {
+        return Err("Not a signature".into());
+    };
+
+    let mut buffer = BytePacketBuffer::with_size(0xFFFF);
+    sig.write_rrsig_fields(&mut buffer)?;
+
+    let owner = rrset[0].domain();
+    let owner = match wildcard_labels(owner, sig) {
+        Some(_) if *labels == 0 => "*".to_owned(),
+        Some(_) => format!("*.{}", ancestor(owner, *labels as usize)),
+        None => owner.to_owned(),
+    };
+
+    let mut rdatas = rrset
+        .iter()
+        .map(|record| record.rdata())
+        .collect::<Result<Vec<_>>>()?;
+    rdatas.sort();
+    rdatas.dedup();
+
+    for rdata in rdatas {
+        buffer.write_qname(&owner)?;
+        buffer.write_u16(rrset[0].qtype().to_num())?;
+        buffer.write_u16(1)?; // class
+        buffer.write_u32(*original_ttl)?;
+        buffer.write_u16(rdata.len() as u16)?;
+        buffer.write_bytes(&rdata)?;
+    }
+
+    Ok(buffer.buf[..buffer.pos].to_vec())
+}
+
+/// Zone Key flag of a DNSKEY.
+pub const DNSKEY_ZONE: u16 = 0x0100;
+/// REVOKE flag of a DNSKEY (RFC 5011).
+pub const DNSKEY_REVOKE: u16 = 0x0080;
+/// Secure Entry Point flag of a DNSKEY, set on key signing keys.
+pub const DNSKEY_SEP: u16 = 0x0001;
+
+fn is_supported_algorithm(algorithm: u8) -> bool {
+    matches!(algorithm, 5 | 7 | 8 | 10 | 13 | 14 | 15)
+}
+
+fn verify(algorithm: u8, public_key: &[u8], data: &[u8], sig: &[u8]) -> bool {
+    let rsa = match algorithm {
+        5 | 7 => Some(&signature::RSA_PKCS1_1024_8192_SHA1_FOR_LEGACY_USE_ONLY),
+        8 => Some(&signature::RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY),
+        10 => Some(&signature::RSA_PKCS1_1024_8192_SHA512_FOR_LEGACY_USE_ONLY),
+        _ => None,
+    };
+    if let Some(params) = rsa {
+        // RFC 3110: exponent length, exponent, then the modulus
+        let (e_len, rest) = match public_key {
+            [0, hi, lo, rest @ ..] => (((*hi as usize) << 8) | *lo as usize, rest),
+            [len, rest @ ..] => (*len as usize, rest),
+            [] => return false,
+        };
+        if rest.len() <= e_len {
+            return false;
+        }
+        let (e, n) = rest.split_at(e_len);
+        let n = &n[n.iter().take_while(|byte| **byte == 0).count()..];
+
+        return signature::RsaPublicKeyComponents { n, e }
+            .verify(params, data, sig)
+            .is_ok();
+    }
+
+    let (alg, key): (&dyn signature::VerificationAlgorithm, Vec<u8>) = match algorithm {
+        // DNSKEY holds the bare point, ring wants it uncompressed with a prefix
+        13 => (
+            &signature::ECDSA_P256_SHA256_FIXED,
+            [&[4], public_key].concat(),
+        ),
+        14 => (
+            &signature::ECDSA_P384_SHA384_FIXED,
+            [&[4], public_key].concat(),
+        ),
+        15 => (&signature::ED25519, public_key.to_vec()),
+        _ => return false,
+    };
+
+    signature::UnparsedPublicKey::new(alg, key)
+        .verify(data, sig)
+        .is_ok()
+}
+
+fn ds_digest_algorithm(digest_type: u8) -> Option<&'static digest::Algorithm> {
+    match digest_type {
+        1 => Some(&digest::SHA1_FOR_LEGACY_USE_ONLY),
+        2 => Some(&digest::SHA256),
+        4 => Some(&digest::SHA384),
+        _ => None,
+    }
+}
+
+fn is_supported_anchor(anchor: &DnsRecord) -> bool {
+    match anchor {
+        DnsRecord::DS {
+            algorithm,
+            digest_type,
+            ..
+        } => is_supported_algorithm(*algorithm) && ds_digest_algorithm(*digest_type).is_some(),
+        DnsRecord::DNSKEY { algorithm, .. } => is_supported_algorithm(*algorithm),
+        _ => false,
+    }
+}
+
+/// Whether `key` is the key a DS record or trusted DNSKEY stands for.
+fn anchor_matches(anchor: &DnsRecord, key: &DnsRecord) -> bool {
+    match anchor {
+        DnsRecord::DS { .. } => ds_matches(anchor, key),
+        DnsRecord::DNSKEY { .. } => {
+            let unrevoked =
+                matches!(key, DnsRecord::DNSKEY { flags, .. } if flags & DNSKEY_REVOKE == 0);
+            unrevoked && anchor.domain() == key.domain() && anchor.rdata().ok() == key.rdata().ok()
+        }
+        _ => false,
+    }
+}
+
+/// Whether `key`, which may be revoked, signed the RRset itself.
+fn self_signed(rrset: &[&DnsRecord], sigs: &[&DnsRecord], key: &DnsRecord) -> bool {
+    let DnsRecord::DNSKEY {
+        algorithm,
+        public_key,
+        ..
+    } = key
+    else {
+        return false;
+    };
+    let Ok(rdata) = key.rdata() else {
+        return false;
+    };
+
+    sigs.iter().any(|sig| match sig {
+        DnsRecord::RRSIG {
+            algorithm: sig_algorithm,
+            key_tag,
+            signature,
+            ..
+        } => {
+            sig_algorithm == algorithm
+                && *key_tag == calc_key_tag(&rdata)
+                && is_current(sig)
+                && signed_data(rrset, sig)
+                    .is_ok_and(|data| verify(*algorithm, public_key, &data, signature))
+        }
+        _ => false,
+    })
+}
+
+fn ds_matches(ds: &DnsRecord, key: &DnsRecord) -> bool {
+    let DnsRecord::DS {
+        domain,
+        key_tag,
+        algorithm,
+        digest_type,
+        digest,
+        ..
+    } = ds
+    else {
+        return false;
+    };
+    let DnsRecord::DNSKEY {
+        domain: key_owner,
+        algorithm: key_algorithm,
+        flags,
+        ..
+    } = key
+    else {
+        return false;
+    };
+    let (Ok(rdata), Some(digest_algorithm)) = (key.rdata(), ds_digest_algorithm(*digest_type))
+    else {
+        return false;
+    };
+    let Ok(owner) = name_to_wire(key_owner) else {
+        return false;
+    };
+
+    domain == key_owner
+        && algorithm == key_algorithm
+        && flags & DNSKEY_REVOKE == 0
+        && calc_key_tag(&rdata) == *key_tag
+        && digest::digest(digest_algorithm, &[owner, rdata].concat()).as_ref() == digest.as_slice()
+}
+
+/// RFC 4034 appendix B.
+pub fn calc_key_tag(rdata: &[u8]) -> u16 {
+    let mut ac = 0u32;
+    for (i, byte) in rdata.iter().enumerate() {
+        ac += if i & 1 == 1 {
+            *byte as u32
+        } else {
+            (*byte as u32) << 8
+        };
+    }
+    ac += (ac >> 16) & 0xFFFF;
+
+    (ac & 0xFFFF) as u16
+}
+
+/// `a <= b` in RFC 1982 serial number arithmetic.
+fn serial_le(a: u32, b: u32) -> bool {
+    b.wrapping_sub(a) < 1 << 31
+}
+
+/// Canonical DNS name order (RFC 4034 section 6.1).
+pub fn canonical_cmp(a: &str, b: &str) -> Ordering {
+    let labels = |name: &str| {
+        name.rsplit('.')
+            .filter(|label| !label.is_empty())
+            .map(|label| label_bytes(label).to_ascii_lowercase())
+            .collect::<Vec<_>>()
+    };
+
+    labels(a).cmp(&labels(b))
+}
+
+/// Whether `name` falls strictly between the owner and next name of `nsec`.
+fn nsec_covers(nsec: &DnsRecord, name: &str) -> bool {
+    let DnsRecord::NSEC {
+        domain,
+        next_domain,
+        ..
+    } = nsec
+    else {
+        return false;
+    };
+
+    let after_owner = canonical_cmp(domain, name) == Ordering::Less;
+    let before_next = canonical_cmp(name, next_domain) == Ordering::Less;
+    // the last NSEC of a zone wraps around to its apex
+    if canonical_cmp(domain, next_domain) != Ordering::Less {
+        after_owner || before_next
+    } else {
+        after_owner && before_next
+    }
+}
+
+fn nsec_types<'a>(nsecs: &[&'a DnsRecord], name: &str) -> Option<&'a [QueryType]> {
+    nsecs.iter().find_map(|nsec| match nsec {
+        DnsRecord::NSEC { domain, types, .. } if domain == name => Some(types.as_slice()),
+        _ => None,
+    })
+}
+
+/// Whether a name with records of `types` has no `qtype` records, and
+/// isn't a delegation that would have to be followed instead.
+fn denies_type(types: &[QueryType], qtype: QueryType) -> bool {
+    !types.contains(&qtype)
+        && !types.contains(&QueryType::CNAME)
+        && (qtype == QueryType::DS || !is_delegation(types))
+}
+
+fn is_delegation(types: &[QueryType]) -> bool {
+    types.contains(&QueryType::NS) && !types.contains(&QueryType::SOA)
+}
+
+fn nsec_denial(name: &str, qtype: QueryType, nxdomain: bool, nsecs: &[&DnsRecord]) -> Proof {
+    if !nxdomain {
+        if let Some(types) = nsec_types(nsecs, name) {
+            return if denies_type(types, qtype) {
+                Proof::Proven
+            } else {
+                Proof::Unproven
+            };
+        }
+    }
+
+    // the name doesn't exist, and neither does a wildcard that could have
+    // been used instead, or it exists without the type. The NSEC of a
+    // delegation above the name says nothing about names below the cut.
+    let Some(covering) = nsecs.iter().find(|nsec| {
+        nsec_covers(nsec, name)
+            && !matches!(nsec, DnsRecord::NSEC { domain, types, .. }
+                if in_bailiwick(name, domain) && is_delegation(types))
+    }) else {
+        return Proof::Unproven;
+    };
+    let DnsRecord::NSEC {
+        domain,
+        next_domain,
+        ..
+    } = covering
+    else {
+        return Proof::Unproven;
+    };
+
+    // names exist below an empty non-terminal, so it has no records at all
+    if in_bailiwick(next_domain, name) {
+        return if nxdomain {
+            Proof::Unproven
+        } else {
+            Proof::Proven
+        };
+    }
+
+    let closest_encloser = (0..label_count(name))
+        .rev()
+        .map(|labels| ancestor(name, labels))
+        .find(|candidate| in_bailiwick(domain, candidate) || in_bailiwick(next_domain, candidate))
+        .unwrap_or("");
+    let wildcard = wildcard_of(closest_encloser);
+
+    if nxdomain {
+        if nsecs.iter().any(|nsec| nsec_covers(nsec, &wildcard)) {
+            return Proof::Proven;
+        }
+    } else if let Some(types) = nsec_types(nsecs, &wildcard) {
+        if denies_type(types, qtype) {
+            return Proof::Proven;
+        }
+    }
+
+    Proof::Unproven
+}
+
+/// Look for proof among validated NSEC or NSEC3 records of `zone` that
+/// `name` doesn't exist, or has no `qtype` records (RFC 8198). Returns
+/// whether the name doesn't exist and the records making up the proof.
+pub fn denial_proof<'a>(
+    zone: &str,
+    name: &str,
+    qtype: QueryType,
+    records: &[&'a DnsRecord],
+) -> Option<(bool, Vec<&'a DnsRecord>)> {
+    // the name, its ancestors in the zone and the wildcards below them
+    let names = (label_count(zone)..=label_count(name))
+        .map(|labels| ancestor(name, labels))
+        .flat_map(|name| [name.to_owned(), wildcard_of(name)])
+        .collect::<Vec<_>>();
+
+    let nsecs = of_type_ref(records, QueryType::NSEC);
+    let relevant = if !nsecs.is_empty() {
+        nsecs
+            .into_iter()
+            .filter(|nsec| {
+                names
+                    .iter()
+                    .any(|name| nsec.domain() == name || nsec_covers(nsec, name))
+            })
+            .collect::<Vec<_>>()
+    } else {
+        let chain = Nsec3Chain::new(zone, &of_type_ref(records, QueryType::NSEC3))?;
+        let hashes = names
+            .iter()
+            .map(|name| chain.hash(name))
+            .collect::<Vec<_>>();
+        chain
+            .records
+            .iter()
+            .filter(|(owner, record)| {
+                let DnsRecord::NSEC3 { next_hashed, .. } = record else {
+                    return false;
+                };
+                hashes
+                    .iter()
+                    .any(|hash| owner == hash || hash_covers(owner, next_hashed, hash))
+            })
+            .map(|(_, record)| *record)
+            .collect()
+    };
+
+    [true, false].into_iter().find_map(|nxdomain| {
+        let proof = match relevant.first() {
+            Some(DnsRecord::NSEC { .. }) => nsec_denial(name, qtype, nxdomain, &relevant),
+            Some(_) => nsec3_denial(zone, name, qtype, nxdomain, &relevant),
+            None => Proof::Unproven,
+        };
+        (proof == Proof::Proven).then(|| (nxdomain, relevant.clone()))
+    })
+}
+
+fn of_type_ref<'a>(records: &[&'a DnsRecord], qtype: QueryType) -> Vec<&'a DnsRecord> {
+    records
+        .iter()
+        .filter(|record| record.qtype() == qtype)
+        .copied()
+        .collect()
+}
+
+/// Whether the NSEC records prove `zone` is delegated without DS records
+/// (`Insecure`) or isn't delegated at all (`Proven`).
+fn nsec_delegation(zone: &str, nsecs: &[&DnsRecord]) -> Option<Proof> {
+    if let Some(types) = nsec_types(nsecs, zone) {
+        return if types.contains(&QueryType::DS) || types.contains(&QueryType::SOA) {
+            None
+        } else if types.contains(&QueryType::NS) {
+            Some(Proof::Insecure)
+        } else {
+            Some(Proof::Proven)
+        };
+    }
+
+    nsecs
+        .iter()
+        .any(|nsec| nsec_covers(nsec, zone))
+        .then_some(Proof::Proven)
+}
+
+fn wildcard_of(name: &str) -> String {
+    if name.is_empty() {
+        "*".to_owned()
+    } else {
+        format!("*.{}", name)
+    }
+}
+
+/// Hashed owner names and parameters of the NSEC3 records of `zone`.
+struct Nsec3Chain<'a> {
+    records: Vec<(Vec<u8>, &'a DnsRecord)>,
+    salt: &'a [u8],
+    iterations: u16,
+}
+
+impl<'a> Nsec3Chain<'a> {
+    /// `None` if the records use parameters that can't prove anything.
+    fn new(zone: &str, nsec3s: &[&'a DnsRecord]) -> Option<Nsec3Chain<'a>> {
+        let mut chain = None;
+        let mut records = Vec::new();
+
+        for nsec3 in nsec3s {
+            let DnsRecord::NSEC3 {
+                domain,
+                hash_algorithm,
+                iterations,
+                salt,
+                ..
+            } = nsec3
+            else {
+                continue;
+            };
+            if *hash_algorithm != 1 || *iterations > MAX_NSEC3_ITERATIONS {
+                return None;
+            }
+            if label_count(domain) != label_count(zone) + 1 || !in_bailiwick(domain, zone) {
+                continue;
+            }
+            let Some(hash) = domain.split('.').next().and_then(base32hex_decode) else {
+                continue;
+            };
+
+            chain.get_or_insert((salt.as_slice(), *iterations));
+            records.push((hash, *nsec3));
+        }
+
+        let (salt, iterations) = chain?;
+        Some(Nsec3Chain {
+            records,
+            salt,
+            iterations,
+        })
+    }
+
+    fn hash(&self, name: &str) -> Vec<u8> {
+        let mut hash = name_to_wire(name).unwrap_or_default();
+        for _ in 0..=self.iterations {
+            hash = digest::digest(
+                &digest::SHA1_FOR_LEGACY_USE_ONLY,
+                &[hash.as_slice(), self.salt].concat(),
+            )
+            .as_ref()
+            .to_vec();
+        }
+        hash
+    }
+
+    fn matching(&self, name: &str) -> Option<&'a [QueryType]> {
+        let hash = self.hash(name);
+        self.records
+            .iter()
+            .find_map(|(owner, record)| match record {
+                DnsRecord::NSEC3 { types, .. } if *owner == hash => Some(types.as_slice()),
+                _ => None,
+            })
+    }
+
+    /// The opt-out flag of the record covering `name`, if one does.
+    fn covering(&self, name: &str) -> Option<bool> {
+        let hash = self.hash(name);
+        self.records
+            .iter()
+            .find_map(|(owner, record)| match record {
+                DnsRecord::NSEC3 {
+                    next_hashed, flags, ..
+                } if hash_covers(owner, next_hashed, &hash) => Some(flags & 1 != 0),
+                _ => None,
+            })
+    }
+
+    /// The closest existing ancestor of `name`, given the next closer name
+    /// is covered, with the opt-out flag of the covering record.
+    fn closest_encloser(&self, name: &str) -> Option<(String, bool)> {
+        let labels = (0..label_count(name))
+            .rev()
+            .find(|labels| self.matching(ancestor(name, *labels)).is_some())?;
+
+        let opt_out = self.covering(ancestor(name, labels + 1))?;
+        Some((ancestor(name, labels).to_owned(), opt_out))
+    }
+}
+
+fn hash_covers(owner: &[u8], next: &[u8], hash: &[u8]) -> bool {
+    if owner < next {
+        owner < hash && hash < next
+    } else {
+        owner < hash || hash < next
+    }
+}
+
+fn nsec3_denial(
+    zone: &str,
+    name: &str,
+    qtype: QueryType,
+    nxdomain: bool,
+    nsec3s: &[&DnsRecord],
+) -> Proof {
+    let Some(chain) = Nsec3Chain::new(zone, nsec3s) else {
+        return Proof::Insecure;
+    };
+
+    if !nxdomain {
+        if let Some(types) = chain.matching(name) {
+            return if denies_type(types, qtype) {
+                Proof::Proven
+            } else {
+                Proof::Unproven
+            };
+        }
+    }
+
+    let Some((closest_encloser, opt_out)) = chain.closest_encloser(name) else {
+        return Proof::Unproven;
+    };
+    // a delegation can't be the closest encloser of names below the cut
+    if chain.matching(&closest_encloser).is_some_and(is_delegation) {
+        return Proof::Unproven;
+    }
+    let wildcard = wildcard_of(&closest_encloser);
+
+    if nxdomain {
+        if chain.covering(&wildcard).is_some() {
+            return if opt_out {
+                Proof::Insecure
+            } else {
+                Proof::Proven
+            };
+        }
+    } else if qtype == QueryType::DS && opt_out {
+        return Proof::Insecure;
+    } else if let Some(types) = chain.matching(&wildcard) {
+        if denies_type(types, qtype) {
+            return Proof::Proven;
+        }
+    }
+
+    Proof::Unproven
+}
+
+fn nsec3_delegation(parent: &str, zone: &str, nsec3s: &[&DnsRecord]) -> Option<Proof> {
+    let Some(chain) = Nsec3Chain::new(parent, nsec3s) else {
+        return Some(Proof::Insecure);
+    };
+
+    if let Some(types) = chain.matching(zone) {
+        return if types.contains(&QueryType::DS) || types.contains(&QueryType::SOA) {
+            None
+        } else if types.contains(&QueryType::NS) {
+            Some(Proof::Insecure)
+        } else {
+            Some(Proof::Proven)
+        };
+    }
+
+    match chain.closest_encloser(zone) {
+        Some((_, true)) => Some(Proof::Insecure),
+        Some((_, false)) => Some(Proof::Proven),
+        None => None,
+    }
+}
+
+fn nsec3_covers(nsec3: &DnsRecord, name: &str) -> bool {
+    let DnsRecord::NSEC3 { domain, .. } = nsec3 else {
+        return false;
+    };
+    let zone = ancestor(domain, label_count(domain).saturating_sub(1));
+
+    Nsec3Chain::new(zone, &[nsec3])
+        .and_then(|chain| chain.covering(name))
+        .is_some()
+}
+
+pub fn base32hex_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut bits = 0u32;
+    let mut bit_count = 0;
+    let mut decoded = Vec::new();
+
+    for c in encoded.bytes() {
+        let value = match c.to_ascii_lowercase() {
+            c @ b'0'..=b'9' => c - b'0',
+            c @ b'a'..=b'v' => c - b'a' + 10,
+            _ => return None,
+        };
+        bits = (bits << 5) | value as u32;
+        bit_count += 5;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            decoded.push((bits >> bit_count) as u8);
+            bits &= (1 << bit_count) - 1;
+        }
+    }
+
+    Some(decoded)
+}
+
+/// `name` in uncompressed, lowercase wire format.
+fn name_to_wire(name: &str) -> Result<Vec<u8>> {
+    let mut buffer = BytePacketBuffer::with_size(name_len(name));
+    buffer.write_qname(&name.to_ascii_lowercase())?;
+
+    Ok(buffer.buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::KeyPair;
+
+    fn nsec(domain: &str, next_domain: &str, types: &[QueryType]) -> DnsRecord {
+        DnsRecord::NSEC {
+            domain: domain.to_owned(),
+            next_domain: next_domain.to_owned(),
+            types: types.to_vec(),
+            ttl: 3600,
+        }
+    }
+
+    /// The NSEC3 chain of the zone of RFC 5155 appendix A, with the
+    /// opt-out flag cleared unless `opt_out` is set.
+    fn nsec3_chain(opt_out: bool) -> Vec<DnsRecord> {
+        use QueryType::*;
+        let chain: [(&str, &[QueryType]); 11] = [
+            (
+                "0p9mhaveqvm6t7vbl5lop2u3t2rp3tom",
+                &[NS, SOA, MX, RRSIG, DNSKEY],
+            ),
+            ("2t7b4g4vsa5smi47k61mv5bv1a22bojr", &[A, RRSIG]),
+            ("2vptu5timamqttgl4luu9kg21e0aor3s", &[MX, RRSIG]),
+            ("35mthgpgcu1qg68fab165klnsnk3dpvl", &[NS, DS, RRSIG]),
+            ("b4um86eghhds6nea196smvmlo4ors995", &[MX, RRSIG]),
+            ("gjeqe526plbf1g8mklp59enfd789njgi", &[A, AAAA, RRSIG]),
+            ("ji6neoaepv8b5o6k4ev33abha8ht9fgc", &[]),
+            ("k8udemvp1j2f7eg6jebps17vp3n8i58h", &[]),
+            ("q04jkcevqvmu85r014c7dkba38o0ji5r", &[A, RRSIG]),
+            ("r53bq7cc2uvmubfu5ocmm6pers9tk9en", &[MX, RRSIG]),
+            ("t644ebqk9bibcna874givr6joj62mlhv", &[A, AAAA, RRSIG]),
+        ];
+
+        (0..chain.len())
+            .map(|i| {
+                let (owner, types) = chain[i];
+                let (next, _) = chain[(i + 1) % chain.len()];
+                DnsRecord::NSEC3 {
+                    domain: format!("{}.example", owner),
+                    hash_algorithm: 1,
+                    flags: opt_out as u8,
+                    iterations: 12,
+                    salt: vec![0xaa, 0xbb, 0xcc, 0xdd],
+                    next_hashed: base32hex_decode(next).unwrap(),
+                    types: types.to_vec(),
+                    ttl: 3600,
+                }
+            })
+            .collect()
+    }
+
+    fn nsec3_denial_of(name: &str, qtype: QueryType, nxdomain: bool, opt_out: bool) -> Proof {
+        let chain = nsec3_chain(opt_out);
+        let records = chain.iter().collect::<Vec<_>>();
+        nsec3_denial("example", name, qtype, nxdomain, &records)
+    }
+
+    #[test]
+    fn canonical_order() {
+        // RFC 4034 section 6.1, without the names of binary labels
+        let ordered = [
+            "example",
+            "a.example",
+            "yljkjljk.a.example",
+            "Z.a.example",
+            "zABC.a.EXAMPLE",
+            "z.example",
+            "*.z.example",
+        ];
+        let mut names = ordered;
+        names.reverse();
+        names.sort_by(|a, b| canonical_cmp(a, b));
+
+        assert_eq!(names, ordered);
+        assert_eq!(canonical_cmp("Z.a.example", "z.A.example"), Ordering::Equal);
+    }
+
+    #[test]
+    fn nsec3_hashes() {
+        // RFC 5155 appendix A
+        let nsec3s = nsec3_chain(false);
+        let records = nsec3s.iter().collect::<Vec<_>>();
+        let chain = Nsec3Chain::new("example", &records).unwrap();
+
+        for (name, hash) in [
+            ("example", "0p9mhaveqvm6t7vbl5lop2u3t2rp3tom"),
+            ("a.example", "35mthgpgcu1qg68fab165klnsnk3dpvl"),
+            ("*.w.example", "r53bq7cc2uvmubfu5ocmm6pers9tk9en"),
+            ("x.y.w.example", "2vptu5timamqttgl4luu9kg21e0aor3s"),
+            ("XX.Example", "t644ebqk9bibcna874givr6joj62mlhv"),
+        ] {
+            assert_eq!(
+                chain.hash(name),
+                base32hex_decode(hash).unwrap(),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn nsec3_matching_and_covering() {
+        let nsec3s = nsec3_chain(false);
+        let records = nsec3s.iter().collect::<Vec<_>>();
+        let chain = Nsec3Chain::new("example", &records).unwrap();
+
+        assert_eq!(
+            chain.matching("ns1.example"),
+            Some(&[QueryType::A, QueryType::RRSIG][..])
+        );
+        assert_eq!(chain.matching("c.x.w.example"), None);
+        assert_eq!(chain.covering("c.x.w.example"), Some(false));
+        assert_eq!(chain.covering("ns1.example"), None);
+        // the last record wraps around to the first
+        assert!(nsec3_covers(&nsec3s[10], "a.c.x.w.example"));
+    }
+
+    #[test]
+    fn nsec3_closest_encloser() {
+        let nsec3s = nsec3_chain(false);
+        let records = nsec3s.iter().collect::<Vec<_>>();
+        let chain = Nsec3Chain::new("example", &records).unwrap();
+
+        // RFC 5155 appendix B.1
+        assert_eq!(
+            chain.closest_encloser("a.c.x.w.example"),
+            Some(("x.w.example".to_owned(), false))
+        );
+        // the empty non-terminal w.example exists too
+        assert_eq!(
+            chain.closest_encloser("a.z.w.example"),
+            Some(("w.example".to_owned(), false))
+        );
+        // a name that exists has no closest encloser covering a next closer
+        assert_eq!(chain.closest_encloser("ns1.example"), None);
+    }
+
+    #[test]
+    fn nsec3_name_error() {
+        // RFC 5155 appendix B.1
+        assert_eq!(
+            nsec3_denial_of("a.c.x.w.example", QueryType::A, true, false),
+            Proof::Proven
+        );
+        assert_eq!(
+            nsec3_denial_of("a.c.x.w.example", QueryType::A, true, true),
+            Proof::Insecure
+        );
+        // the name exists
+        assert_eq!(
+            nsec3_denial_of("ns1.example", QueryType::A, true, false),
+            Proof::Unproven
+        );
+        // x.y.w.example exists below the wildcard *.w.example would expand to
+        assert_eq!(
+            nsec3_denial_of("a.z.w.example", QueryType::MX, true, false),
+            Proof::Unproven
+        );
+    }
+
+    #[test]
+    fn nsec3_no_data() {
+        // RFC 5155 appendix B.2 and B.2.1
+        assert_eq!(
+            nsec3_denial_of("ns1.example", QueryType::MX, false, false),
+            Proof::Proven
+        );
+        assert_eq!(
+            nsec3_denial_of("y.w.example", QueryType::A, false, false),
+            Proof::Proven
+        );
+        assert_eq!(
+            nsec3_denial_of("ns1.example", QueryType::A, false, false),
+            Proof::Unproven
+        );
+        // below the delegation to a.example the parent can't tell
+        assert_eq!(
+            nsec3_denial_of("b.a.example", QueryType::A, false, false),
+            Proof::Unproven
+        );
+    }
+
+    #[test]
+    fn nsec3_wildcard() {
+        // RFC 5155 appendix B.4: the next closer of a.z.w.example, which
+        // the answer was expanded from *.w.example for, is covered
+        let nsec3s = nsec3_chain(false);
+        assert!(nsec3_covers(&nsec3s[8], "z.w.example"));
+        assert!(!nsec3s
+            .iter()
+            .any(|nsec3| nsec3_covers(nsec3, "x.w.example")));
+
+        // RFC 5155 appendix B.5: the wildcard has no AAAA records
+        assert_eq!(
+            nsec3_denial_of("a.z.w.example", QueryType::AAAA, false, false),
+            Proof::Proven
+        );
+        assert_eq!(
+            nsec3_denial_of("a.z.w.example", QueryType::MX, false, false),
+            Proof::Unproven
+        );
+    }
+
+    #[test]
+    fn nsec3_denial_proof() {
+        let nsec3s = nsec3_chain(false);
+        let records = nsec3s.iter().collect::<Vec<_>>();
+
+        let (nxdomain, proof) =
+            denial_proof("example", "a.c.x.w.example", QueryType::A, &records).unwrap();
+        assert!(nxdomain);
+        // the closest encloser, and what covers the next closer and wildcard
+        for i in [4, 0, 3] {
+            assert!(proof.contains(&&nsec3s[i]));
+        }
+        assert!(!proof.contains(&&nsec3s[1]));
+
+        let (nxdomain, _) =
+            denial_proof("example", "ns1.example", QueryType::MX, &records).unwrap();
+        assert!(!nxdomain);
+        assert!(denial_proof("example", "ns1.example", QueryType::A, &records).is_none());
+    }
+
+    #[test]
+    fn nsec_covering() {
+        let record = nsec("a.example", "d.example", &[QueryType::A]);
+        assert!(nsec_covers(&record, "b.example"));
+        assert!(nsec_covers(&record, "x.b.example"));
+        assert!(!nsec_covers(&record, "a.example"));
+        assert!(!nsec_covers(&record, "d.example"));
+        assert!(!nsec_covers(&record, "e.example"));
+
+        // the last NSEC of the zone
+        let last = nsec("z.example", "example", &[QueryType::A]);
+        assert!(nsec_covers(&last, "zz.example"));
+        assert!(!nsec_covers(&last, "b.example"));
+    }
+
+    #[test]
+    fn nsec_name_error() {
+        let apex = nsec("example", "a.example", &[QueryType::SOA, QueryType::NS]);
+        let a = nsec("a.example", "d.example", &[QueryType::A]);
+
+        assert_eq!(
+            nsec_denial("b.example", QueryType::A, true, &[&apex, &a]),
+            Proof::Proven
+        );
+        // nothing shows *.example doesn't exist
+        assert_eq!(
+            nsec_denial("b.example", QueryType::A, true, &[&a]),
+            Proof::Unproven
+        );
+    }
+
+    #[test]
+    fn nsec_no_data() {
+        let a = nsec("a.example", "b.c.example", &[QueryType::A]);
+        assert_eq!(
+            nsec_denial("a.example", QueryType::MX, false, &[&a]),
+            Proof::Proven
+        );
+        assert_eq!(
+            nsec_denial("a.example", QueryType::A, false, &[&a]),
+            Proof::Unproven
+        );
+        // c.example is an empty non-terminal
+        assert_eq!(
+            nsec_denial("c.example", QueryType::A, false, &[&a]),
+            Proof::Proven
+        );
+
+        // the NSEC of a delegation says nothing about the child zone
+        let cut = nsec("a.example", "d.example", &[QueryType::NS]);
+        assert_eq!(
+            nsec_denial("a.example", QueryType::A, false, &[&cut]),
+            Proof::Unproven
+        );
+        assert_eq!(
+            nsec_denial("b.a.example", QueryType::A, true, &[&cut]),
+            Proof::Unproven
+        );
+    }
+
+    /// An Ed25519 zone key of `example` and a signature by it over `rrset`.
+    fn signed(rrset: &[&DnsRecord]) -> (DnsRecord, DnsRecord) {
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8 = signature::Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let pair = signature::Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let key = DnsRecord::DNSKEY {
+            domain: "example".to_owned(),
+            flags: DNSKEY_ZONE,
+            protocol: 3,
+            algorithm: 15,
+            public_key: pair.public_key().as_ref().to_vec(),
+            ttl: 3600,
+        };
+
+        let now = unix_now() as u32;
+        let mut sig = DnsRecord::RRSIG {
+            domain: rrset[0].domain().to_owned(),
+            type_covered: rrset[0].qtype(),
+            algorithm: 15,
+            labels: label_count(rrset[0].domain()) as u8,
+            original_ttl: 3600,
+            expiration: now + 3600,
+            inception: now - 3600,
+            key_tag: calc_key_tag(&key.rdata().unwrap()),
+            signer_name: "example".to_owned(),
+            signature: Vec::new(),
+            ttl: 3600,
+        };
+        let data = signed_data(rrset, &sig).unwrap();
+        if let DnsRecord::RRSIG { signature, .. } = &mut sig {
+            *signature = pair.sign(&data).as_ref().to_vec();
+        }
+
+        (key, sig)
+    }
+
+    fn a(addr: &str) -> DnsRecord {
+        DnsRecord::A {
+            domain: "www.example".to_owned(),
+            addr: addr.parse().unwrap(),
+            ttl: 3600,
+        }
+    }
+
+    #[test]
+    fn signature_over_canonical_rrset() {
+        let (first, second) = (a("192.0.2.1"), a("192.0.2.2"));
+        let (key, sig) = signed(&[&first, &second]);
+        let keys = [key];
+
+        // the order the records come in doesn't matter
+        assert!(verify_signature(&[&first, &second], &sig, &keys).is_ok());
+        assert!(verify_signature(&[&second, &first], &sig, &keys).is_ok());
+
+        let forged = a("192.0.2.3");
+        assert!(verify_signature(&[&first, &forged], &sig, &keys).is_err());
+        assert!(verify_signature(&[&first], &sig, &keys).is_err());
+    }
+
+    #[test]
+    fn signature_outside_validity() {
+        let record = a("192.0.2.1");
+        let (key, mut sig) = signed(&[&record]);
+        if let DnsRecord::RRSIG { expiration, .. } = &mut sig {
+            *expiration = unix_now() as u32 - 60;
+        }
+
+        assert!(verify_signature(&[&record], &sig, &[key]).is_err());
+    }
+}
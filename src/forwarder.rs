@@ -0,0 +1,124 @@
+use crate::config::Config;
+use crate::packet::*;
+use crate::resolver::lookup;
+use crate::tsig::Key;
+use std::{
+    net::SocketAddr,
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+
+/// Consecutive failures after which an upstream is considered down.
+const MAX_FAILURES: u32 = 3;
+/// How often every upstream is probed to find out whether it's up.
+pub const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+pub struct Upstream {
+    pub addr: SocketAddr,
+    /// The key queries to the upstream are signed with.
+    key: Option<Key>,
+    failures: AtomicU32,
+}
+
+impl Upstream {
+    fn new(addr: SocketAddr, key: Option<Key>) -> Upstream {
+        Upstream {
+            addr,
+            key,
+            failures: AtomicU32::new(0),
+        }
+    }
+
+    pub fn is_up(&self) -> bool {
+        self.failures.load(Ordering::Relaxed) < MAX_FAILURES
+    }
+
+    fn succeeded(&self) {
+        if self.failures.swap(0, Ordering::Relaxed) >= MAX_FAILURES {
+            println!("Upstream {} is up again", self.addr);
+        }
+    }
+
+    fn failed(&self) {
+        if self.failures.fetch_add(1, Ordering::Relaxed) + 1 == MAX_FAILURES {
+            println!("Upstream {} is down", self.addr);
+        }
+    }
+
+    async fn query(&self, qname: &str, qtype: QueryType) -> Result<DnsPacket> {
+        let result = lookup(
+            qname,
+            qtype,
+            (self.addr.ip(), self.addr.port()),
+            self.key.as_ref(),
+        )
+        .await
+        .and_then(|response| match response.header.rescode {
+            ResultCode::NOERROR | ResultCode::NXDOMAIN => Ok(response),
+            rescode => Err(format!("{} answered {:?}", self.addr, rescode).into()),
+        });
+
+        match result {
+            Ok(_) => self.succeeded(),
+            Err(_) => self.failed(),
+        }
+
+        result
+    }
+}
+
+/// Sends queries on to a list of upstream resolvers, failing over between
+/// them in order.
+pub struct Forwarder {
+    pub upstreams: Vec<Upstream>,
+}
+
+impl Forwarder {
+    pub fn new(addrs: &[SocketAddr], config: &Config) -> Forwarder {
+        Forwarder {
+            upstreams: addrs
+                .iter()
+                .map(|addr| Upstream::new(*addr, config.server_key(addr.ip()).cloned()))
+                .collect(),
+        }
+    }
+
+    pub async fn forward(&self, qname: &str, qtype: QueryType) -> Result<DnsPacket> {
+        // skip upstreams that are down, unless that leaves nothing to try
+        let mut upstreams = self
+            .upstreams
+            .iter()
+            .filter(|upstream| upstream.is_up())
+            .collect::<Vec<_>>();
+        if upstreams.is_empty() {
+            upstreams = self.upstreams.iter().collect();
+        }
+
+        let mut last_err = None;
+        for upstream in upstreams {
+            println!("forwarding {:?} {} to {}", qtype, qname, upstream.addr);
+
+            match upstream.query(qname, qtype).await {
+                Ok(mut response) => {
+                    // a forwarder answers for any zone, but only about
+                    // the question, and has no glue we need
+                    response.scrub(qname, "");
+                    response
+                        .resources
+                        .retain(|record| record.qtype() == QueryType::OPT);
+                    return Ok(response);
+                }
+                Err(err) => last_err = Some(err),
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| "No upstream resolvers configured".into()))
+    }
+
+    /// Probe every upstream with a query for the root nameservers.
+    pub async fn check_health(&self) {
+        for upstream in &self.upstreams {
+            let _ = upstream.query("", QueryType::NS).await;
+        }
+    }
+}
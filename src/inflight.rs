@@ -0,0 +1,127 @@
+use crate::packet::Result;
+use std::{
+    collections::HashMap,
+    future::Future,
+    hash::Hash,
+    sync::{Mutex, PoisonError},
+};
+use tokio::sync::watch;
+
+/// Calls in progress by key, so that callers asking for the same thing at
+/// the same time share a single call and its result.
+pub struct Inflight<K: Eq + Hash, V> {
+    /// The result of each call, `None` until it's done.
+    calls: Mutex<HashMap<K, watch::Receiver<Option<V>>>>,
+}
+
+/// Ends the call of the caller that made it, even when it panics or is
+/// cancelled, so that the others stop waiting and the key is free again.
+struct Leader<'a, K: Eq + Hash, V> {
+    inflight: &'a Inflight<K, V>,
+    key: K,
+    done: watch::Sender<Option<V>>,
+}
+
+impl<K: Eq + Hash, V> Drop for Leader<'_, K, V> {
+    fn drop(&mut self) {
+        // the sender goes with the leader, which tells anyone still
+        // waiting that there won't be a result
+        self.inflight
+            .calls
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.key);
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> Inflight<K, V> {
+    pub fn new() -> Inflight<K, V> {
+        Inflight {
+            calls: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Run `f` for `key`, unless it's already running for the same key,
+    /// in which case wait for that call to finish and return its result.
+    /// Fails if that call panicked or was cancelled instead.
+    pub async fn run(&self, key: K, f: impl Future<Output = V>) -> Result<V> {
+        let joined = {
+            let mut calls = self.calls.lock().unwrap_or_else(PoisonError::into_inner);
+            match calls.get(&key) {
+                Some(done) => Err(done.clone()),
+                None => {
+                    let (done, receiver) = watch::channel(None);
+                    calls.insert(key.clone(), receiver);
+                    Ok(done)
+                }
+            }
+        };
+
+        let mut receiver = match joined {
+            Ok(done) => {
+                let leader = Leader {
+                    inflight: self,
+                    key,
+                    done,
+                };
+                let result = f.await;
+                leader.done.send_replace(Some(result.clone()));
+                return Ok(result);
+            }
+            Err(receiver) => receiver,
+        };
+
+        let result = receiver
+            .wait_for(Option::is_some)
+            .await
+            .map_err(|_| "Shared call failed")?
+            .clone();
+        result.ok_or_else(|| "Shared call failed".into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{future, time::Duration};
+    use tokio::{sync::oneshot, time};
+
+    #[tokio::test]
+    async fn shares_result() {
+        let inflight = Inflight::new();
+        let (finish, finished) = oneshot::channel::<()>();
+
+        // the leader is polled first and the follower joins it before the
+        // last future lets it finish
+        let (leader, follower, _) = tokio::join!(
+            inflight.run(1, async {
+                finished.await.unwrap();
+                7
+            }),
+            inflight.run(1, async { 8 }),
+            async { finish.send(()).unwrap() },
+        );
+
+        assert_eq!(leader.unwrap(), 7);
+        assert_eq!(follower.unwrap(), 7);
+        assert!(inflight.calls.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn leader_cancelled() {
+        let inflight = Inflight::new();
+
+        let (leader, follower) = tokio::join!(
+            time::timeout(
+                Duration::from_millis(10),
+                inflight.run(1, future::pending::<u32>())
+            ),
+            inflight.run(1, async { 8 }),
+        );
+
+        assert!(leader.is_err());
+        assert!(follower.is_err());
+        // the key is free for the next call
+        assert_eq!(inflight.run(1, async { 9 }).await.unwrap(), 9);
+    }
+}
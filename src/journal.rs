@@ -0,0 +1,262 @@
+use crate::packet::*;
+use crate::zone::Zone;
+use crate::zonefile;
+use std::{collections::VecDeque, fs};
+
+/// Changes kept for IXFR, older ones are forgotten and clients that far
+/// behind get the whole zone.
+const JOURNAL_SIZE: usize = 100;
+
+/// The changes from one version of a zone to the next: the old SOA and the
+/// records removed, then the new SOA and the records added.
+#[derive(Debug, Clone)]
+pub struct Delta {
+    pub removed: Vec<DnsRecord>,
+    pub added: Vec<DnsRecord>,
+}
+
+impl Delta {
+    pub fn between(old: &Zone, new: &Zone) -> Delta {
+        let mut removed = vec![old.soa().clone()];
+        removed.extend(
+            old.iter()
+                .filter(|rec| rec.qtype() != QueryType::SOA && !new.contains(rec))
+                .cloned(),
+        );
+        let mut added = vec![new.soa().clone()];
+        added.extend(
+            new.iter()
+                .filter(|rec| rec.qtype() != QueryType::SOA && !old.contains(rec))
+                .cloned(),
+        );
+
+        Delta { removed, added }
+    }
+
+    pub fn from_serial(&self) -> u32 {
+        soa_serial(&self.removed[0])
+    }
+
+    pub fn to_serial(&self) -> u32 {
+        soa_serial(&self.added[0])
+    }
+
+    /// The delta as the records of an IXFR (RFC 1995 section 4).
+    pub fn records(&self) -> impl Iterator<Item = &DnsRecord> {
+        self.removed.iter().chain(&self.added)
+    }
+}
+
+fn soa_serial(record: &DnsRecord) -> u32 {
+    match record {
+        DnsRecord::SOA { serial, .. } => *serial,
+        _ => 0,
+    }
+}
+
+/// The recent changes of a zone, kept in a file next to the zone file so
+/// that they survive restarts.
+///
+/// The file is a master file holding the records of every delta the way
+/// an IXFR sends them, oldest first.
+pub struct Journal {
+    path: Option<String>,
+    deltas: VecDeque<Delta>,
+}
+
+impl Journal {
+    /// A journal only kept in memory.
+    pub fn new() -> Journal {
+        Journal {
+            path: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    /// The journal at `path` of the zone at `origin`.
+    pub fn load(path: &str, origin: &str) -> Journal {
+        let mut journal = Journal {
+            path: Some(path.to_owned()),
+            deltas: VecDeque::new(),
+        };
+
+        let records = match zonefile::load(path, origin) {
+            Ok(records) => records,
+            Err(err) => {
+                if fs::metadata(path).is_ok() {
+                    eprintln!("Ignoring the journal {}: {}", path, err);
+                }
+                return journal;
+            }
+        };
+        let Some(deltas) = deltas(records) else {
+            eprintln!(
+                "Ignoring the journal {}: records not laid out as deltas",
+                path
+            );
+            return journal;
+        };
+        journal.deltas = deltas.into();
+
+        let contiguous = journal
+            .deltas
+            .iter()
+            .zip(journal.deltas.iter().skip(1))
+            .all(|(a, b)| a.to_serial() == b.from_serial());
+        if !contiguous {
+            eprintln!("Ignoring the journal {}: changes missing", path);
+            journal.deltas.clear();
+        }
+
+        journal
+    }
+
+    /// `zone` as loaded with the changes since made to it, like dynamic
+    /// updates. The journal is dropped when it doesn't lead up to `zone`,
+    /// which was changed some other way.
+    pub fn catch_up(&mut self, zone: Zone) -> Zone {
+        let serial = zone.serial();
+        if self
+            .deltas
+            .back()
+            .is_none_or(|delta| delta.to_serial() == serial)
+        {
+            return zone;
+        }
+
+        let Some(deltas) = self.since(serial) else {
+            self.deltas.clear();
+            return zone;
+        };
+        match zone.apply(&deltas.cloned().collect::<Vec<_>>()) {
+            Ok(zone) => zone,
+            Err(err) => {
+                eprintln!("Ignoring the journal of zone {}: {}", zone.origin, err);
+                self.deltas.clear();
+                zone
+            }
+        }
+    }
+
+    /// Record `delta` and write the journal out.
+    pub fn append(&mut self, delta: Delta) -> Result<()> {
+        self.deltas.push_back(delta);
+        if self.deltas.len() > JOURNAL_SIZE {
+            self.deltas.pop_front();
+        }
+
+        let saved = self.save();
+        if saved.is_err() {
+            self.deltas.pop_back();
+        }
+        saved
+    }
+
+    /// Forget every change, for a zone transferred anew.
+    pub fn clear(&mut self) -> Result<()> {
+        self.deltas.clear();
+        self.save()
+    }
+
+    fn save(&self) -> Result<()> {
+        if let Some(path) = &self.path {
+            let data = self
+                .deltas
+                .iter()
+                .flat_map(Delta::records)
+                .map(|rec| format!("{}\n", rec))
+                .collect::<String>();
+            fs::write(path, data)?;
+        }
+
+        Ok(())
+    }
+
+    /// The deltas taking a zone from `serial` to its latest version, unless
+    /// the journal doesn't go back that far.
+    pub fn since(&self, serial: u32) -> Option<impl Iterator<Item = &Delta>> {
+        let start = self
+            .deltas
+            .iter()
+            .position(|delta| delta.from_serial() == serial)?;
+        Some(self.deltas.iter().skip(start))
+    }
+}
+
+/// The deltas made of `records` laid out the way an IXFR sends them,
+/// unless they're laid out otherwise.
+pub fn deltas(records: impl IntoIterator<Item = DnsRecord>) -> Option<Vec<Delta>> {
+    let mut deltas: Vec<Delta> = Vec::new();
+    for record in records {
+        match (record.qtype(), deltas.last_mut()) {
+            (QueryType::SOA, Some(delta)) if delta.added.is_empty() => delta.added.push(record),
+            (QueryType::SOA, _) => deltas.push(Delta {
+                removed: vec![record],
+                added: Vec::new(),
+            }),
+            (_, Some(delta)) if delta.added.is_empty() => delta.removed.push(record),
+            (_, Some(delta)) => delta.added.push(record),
+            (_, None) => return None,
+        }
+    }
+
+    deltas
+        .iter()
+        .all(|delta| !delta.added.is_empty())
+        .then_some(deltas)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn soa(serial: u32) -> DnsRecord {
+        DnsRecord::SOA {
+            domain: "example.com".to_owned(),
+            mname: "ns.example.com".to_owned(),
+            rname: "hostmaster.example.com".to_owned(),
+            serial,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 300,
+            ttl: 3600,
+        }
+    }
+
+    fn a(domain: &str) -> DnsRecord {
+        DnsRecord::A {
+            domain: domain.to_owned(),
+            addr: "192.0.2.1".parse().unwrap(),
+            ttl: 3600,
+        }
+    }
+
+    #[test]
+    fn reads_deltas() {
+        let deltas = deltas(vec![
+            soa(1),
+            a("www.example.com"),
+            soa(2),
+            soa(2),
+            soa(3),
+            a("mail.example.com"),
+        ])
+        .unwrap();
+        assert_eq!(deltas.len(), 2);
+        assert_eq!((deltas[0].from_serial(), deltas[0].to_serial()), (1, 2));
+        assert_eq!(deltas[0].removed, [soa(1), a("www.example.com")]);
+        assert_eq!(deltas[1].added, [soa(3), a("mail.example.com")]);
+
+        let mut journal = Journal::new();
+        for delta in deltas {
+            journal.append(delta).unwrap();
+        }
+        assert_eq!(journal.since(2).unwrap().count(), 1);
+        assert!(journal.since(3).is_none());
+
+        // records before the first SOA, or a delta without its new SOA
+        assert!(super::deltas(vec![a("www.example.com"), soa(1), soa(2)]).is_none());
+        assert!(super::deltas(vec![soa(1), a("www.example.com")]).is_none());
+    }
+}
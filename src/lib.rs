@@ -0,0 +1,29 @@
+#![allow(
+    clippy::upper_case_acronyms,
+    clippy::identity_op,
+    clippy::new_without_default
+)]
+
+pub mod anchors;
+pub mod answer;
+pub mod async_resolver;
+pub mod cache;
+pub mod config;
+pub mod context;
+pub mod dnssec;
+pub mod forwarder;
+pub mod inflight;
+pub mod journal;
+pub mod metrics;
+pub mod notify;
+pub mod packet;
+pub mod resolver;
+pub mod router;
+pub mod snapshot;
+pub mod transfer;
+pub mod tsig;
+pub mod update;
+pub mod zone;
+pub mod zonefile;
+
+pub use async_resolver::Resolver;
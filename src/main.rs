@@ -1,159 +1,653 @@
-mod packet;
+use rdns::anchors;
+use rdns::answer::fetch;
+use rdns::config::Config;
+use rdns::context::Context;
+use rdns::dnssec::Security;
+use rdns::forwarder::HEALTH_CHECK_INTERVAL;
+use rdns::metrics::{Metrics, METRICS_INTERVAL};
+use rdns::notify;
+use rdns::packet::*;
+use rdns::snapshot;
+use rdns::transfer::{self, TCP_SIZE};
+use rdns::tsig::{self, Session, Verified};
+use rdns::update::Update;
+use rdns::zone::{self, REFRESH_CHECK_INTERVAL, ZONE_CHECK_INTERVAL};
+use rdns::Resolver;
+use std::{env, io, net::SocketAddr, process, sync::Arc, thread, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    runtime,
+    sync::Semaphore,
+    time,
+};
 
-use packet::*;
-use rand::Rng;
-use std::net::{Ipv4Addr, UdpSocket};
+/// How long a TCP connection is kept open waiting for another request.
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
 
 fn main() {
-    // let mut args = env::args();
-    // if args.len() < 3 {
-    //     println!("Usage: rdns <domain> <query-type>");
-    //     println!();
-    //     println!("    <query-type> is one of:");
-    //     println!("        - a");
-    //     println!("        - ns");
-    //     println!("        - cname");
-    //     println!("        - mx");
-    //     println!("        - aaaa");
-    //     process::exit(0);
-    // }
-
-    // let qname = args.nth(1).unwrap();
-    // let qtype = match args.next().unwrap().to_lowercase().as_str() {
-    //     "a" => QueryType::A,
-    //     "ns" => QueryType::NS,
-    //     "cname" => QueryType::CNAME,
-    //     "mx" => QueryType::MX,
-    //     "aaaa" => QueryType::AAAA,
-    //     _ => QueryType::UNKNOWN(0),
-    // };
-
-    if let Err(err) = server_run() {
+    let args = env::args().collect::<Vec<_>>();
+    let result = match args.get(1).map(String::as_str) {
+        Some("anchors") => anchors::run_command(&args[2..]),
+        Some("zone") => zone::run_command(&args[2..]),
+        Some(path) => Config::load(path)
+            .and_then(Resolver::new)
+            .and_then(server_run),
+        None => Resolver::new(Config::new()).and_then(server_run),
+    };
+
+    if let Err(err) = result {
         println!("Error: {}", err);
     }
 }
 
-fn lookup(qname: &str, qtype: QueryType, server: (Ipv4Addr, u16)) -> Result<DnsPacket> {
-    let socket = UdpSocket::bind(("0.0.0.0", 22222))?;
-
-    let mut req_packet = DnsPacket::new();
-
-    req_packet.header.id = rand::thread_rng().gen();
-    req_packet.header.questions = 1;
-    req_packet.header.recursion_desired = true;
-    req_packet.questions.push(DnsQuestion::new(qname, qtype));
+/// The messages answering the request in `req_buffer`, several of them
+/// for a zone transfer over TCP. Responses to a request signed with one of
+/// our keys are signed too.
+async fn handle_request(
+    mut req_buffer: BytePacketBuffer,
+    src: SocketAddr,
+    tcp: bool,
+    resolver: &Resolver,
+) -> Result<Vec<Vec<u8>>> {
+    let request = DnsPacket::from_buffer(&mut req_buffer)?;
+    req_buffer.pos = 0;
 
-    let mut req_buffer = BytePacketBuffer::new();
-    req_packet.write(&mut req_buffer)?;
+    let size = match request.edns() {
+        _ if tcp => TCP_SIZE,
+        Some((size, _)) => (size as usize).clamp(UDP_SIZE, EDNS_SIZE),
+        None => UDP_SIZE,
+    };
 
-    socket.send_to(&req_buffer.buf[..req_buffer.pos], server)?;
+    // requests failing TSIG verification get nothing but the error
+    let keys = &resolver.context().config.keys;
+    let mut session = match tsig::verify_request(&req_buffer.buf, keys) {
+        Ok(Verified::Unsigned) => None,
+        Ok(Verified::Signed(session)) => Some(session),
+        // a TSIG out of place or that can't be read (RFC 8945 section 5.2)
+        Err(err) => {
+            eprintln!("Malformed TSIG from {}: {}", src.ip(), err);
+            let response = status_response(&request, ResultCode::FORMERR);
+            return Ok(vec![write_response(response, size)?]);
+        }
+        Ok(Verified::Rejected(error, tsig)) => {
+            eprintln!(
+                "Rejected request from {}: {}",
+                src.ip(),
+                tsig::error_name(error)
+            );
+            let reserved = tsig::record_size(&tsig)?;
+            let mut response = write_response(
+                status_response(&request, ResultCode::NOTAUTH),
+                size.saturating_sub(reserved),
+            )?;
+            tsig::append(&mut response, &tsig)?;
+            return Ok(vec![response]);
+        }
+    };
+    let key = session.as_ref().map(|session| session.key().name.clone());
+    let reserved = session.as_ref().map_or(0, Session::size);
 
-    let mut res_buffer = BytePacketBuffer::new();
-    socket.recv_from(&mut res_buffer.buf)?;
+    let qtype = request.questions.first().map(|question| question.qtype);
+    let responses = match (request.header.opcode, qtype) {
+        _ if session.as_ref().is_some_and(Session::bad_time) => {
+            eprintln!("Rejected request from {}: BADTIME", src.ip());
+            vec![status_response(&request, ResultCode::NOTAUTH)]
+        }
+        (OPCODE_QUERY, Some(QueryType::AXFR | QueryType::IXFR)) => handle_transfer(
+            &request,
+            src,
+            tcp,
+            key.as_deref(),
+            reserved,
+            resolver.context(),
+        )?,
+        (OPCODE_QUERY, _) => vec![handle_query(&request, resolver).await],
+        (OPCODE_NOTIFY, _) => vec![handle_notify(
+            &request,
+            src,
+            key.as_deref(),
+            resolver.context(),
+        )],
+        (OPCODE_UPDATE, _) => vec![handle_update(
+            &request,
+            &mut req_buffer,
+            src,
+            key.as_deref(),
+            resolver.context(),
+        )],
+        _ => vec![status_response(&request, ResultCode::NOTIMP)],
+    };
 
-    DnsPacket::from_buffer(&mut res_buffer)
+    responses
+        .into_iter()
+        .map(|response| {
+            let mut message = write_response(response, size.saturating_sub(reserved))?;
+            if let Some(session) = &mut session {
+                session.sign(&mut message)?;
+            }
+            Ok(message)
+        })
+        .collect()
 }
 
-fn handle_query(socket: &UdpSocket) -> Result<()> {
-    let mut req_buffer = BytePacketBuffer::new();
-
-    let (_, src) = socket.recv_from(&mut req_buffer.buf)?;
-
-    let request = DnsPacket::from_buffer(&mut req_buffer)?;
-
+async fn handle_query(request: &DnsPacket, resolver: &Resolver) -> DnsPacket {
+    let client_edns = request.edns();
+    let dnssec_ok = client_edns.is_some_and(|(_, dnssec_ok)| dnssec_ok);
     let mut response = DnsPacket::new();
     response.header.id = request.header.id;
     response.header.recursion_desired = true;
-    response.header.recursion_available = true;
+    response.header.recursion_available = resolver.context().config.recursion;
     response.header.response = true;
+    response.header.checking_disabled = request.header.checking_disabled;
 
-    if let Some(question) = request.questions.iter().next() {
+    if let Some(question) = request.questions.first() {
         println!("Received query: {:?}", question);
 
-        if let Ok(result) = recursion_lookup(&question.name, question.qtype) {
-            response.questions.push(question.clone());
-            response.header.rescode = result.header.rescode;
-
-            for rec in result.answers {
-                println!("Answer: {:?}", rec);
-                response.answers.push(rec);
+        // our own zones come first, anything else is resolved if allowed
+        let local = resolver.context().zones.answer(question);
+        let authoritative = local.is_some();
+        let answer = match local {
+            Some(packet) => Ok((packet, None)),
+            None if !resolver.context().config.recursion => {
+                let mut packet = DnsPacket::new();
+                packet.header.rescode = ResultCode::REFUSED;
+                Ok((packet, None))
             }
-            for rec in result.authorities {
-                println!("Authority: {:?}", rec);
-                response.authorities.push(rec);
+            None => {
+                resolver
+                    .answer(question, request.header.checking_disabled)
+                    .await
             }
-            for rec in result.resources {
-                println!("Resource: {:?}", rec);
-                response.resources.push(rec);
+        };
+
+        match answer {
+            Ok((result, security)) => {
+                response.questions.push(question.clone());
+                response.header.authoritative_answer =
+                    authoritative && result.header.authoritative_answer;
+                match security {
+                    Some(Security::Bogus(err)) => {
+                        eprintln!("Bogus answer for {}: {}", question.name, err);
+                        response.header.rescode = ResultCode::SERVFAIL;
+                    }
+                    security => {
+                        // AD is only for clients that show they understand it
+                        response.header.authed_data = security == Some(Security::Secure)
+                            && (dnssec_ok || request.header.authed_data);
+                        response.header.rescode = result.header.rescode;
+
+                        // DNSSEC records are only for clients that ask for them
+                        let wanted = |rec: &DnsRecord| {
+                            rec.qtype() != QueryType::OPT
+                                && (dnssec_ok
+                                    || !rec.qtype().is_dnssec()
+                                    || rec.qtype() == question.qtype)
+                        };
+
+                        for rec in result.answers.into_iter().filter(wanted) {
+                            println!("Answer: {:?}", rec);
+                            response.answers.push(rec);
+                        }
+                        for rec in result.authorities.into_iter().filter(wanted) {
+                            println!("Authority: {:?}", rec);
+                            response.authorities.push(rec);
+                        }
+                        for rec in result.resources.into_iter().filter(wanted) {
+                            println!("Resource: {:?}", rec);
+                            response.resources.push(rec);
+                        }
+                    }
+                }
+            }
+            Err(err) => {
+                eprintln!("Failed to resolve {}: {}", question.name, err);
+                response.header.rescode = ResultCode::SERVFAIL;
             }
-        } else {
-            response.header.rescode = ResultCode::SERVFAIL;
         }
     } else {
         response.header.rescode = ResultCode::FORMERR;
     }
 
-    let mut res_buffer = BytePacketBuffer::new();
-    response.write(&mut res_buffer)?;
+    if client_edns.is_some() {
+        response.add_edns(dnssec_ok);
+    }
+
+    response
+}
+
+/// `response` in wire format, truncated if it doesn't fit in `size` bytes.
+fn write_response(mut response: DnsPacket, size: usize) -> Result<Vec<u8>> {
+    let mut res_buffer = BytePacketBuffer::with_size(size);
+    if response.write(&mut res_buffer).is_err() {
+        // too big for the client, tell it to retry over TCP
+        response.header.truncated_message = true;
+        response.answers.clear();
+        response.authorities.clear();
+        response
+            .resources
+            .retain(|rec| rec.qtype() == QueryType::OPT);
+
+        res_buffer = BytePacketBuffer::with_size(size);
+        response.write(&mut res_buffer)?;
+    }
+
+    Ok(res_buffer.get_range(0, res_buffer.pos)?.to_vec())
+}
+
+/// Answer an AXFR or IXFR of one of our zones, for the clients allowed to
+/// transfer them, leaving `reserved` bytes in each response for a TSIG.
+fn handle_transfer(
+    request: &DnsPacket,
+    src: SocketAddr,
+    tcp: bool,
+    key: Option<&str>,
+    reserved: usize,
+    context: &Context,
+) -> Result<Vec<DnsPacket>> {
+    let question = &request.questions[0];
+    let fail = |rescode| Ok(vec![status_response(request, rescode)]);
 
-    let len = res_buffer.pos;
-    let data = res_buffer.get_range(0, len)?;
+    if !context.config.allow_transfer.allows(src.ip(), key) {
+        eprintln!("Refused transfer of {} to {}", question.name, src.ip());
+        return fail(ResultCode::REFUSED);
+    }
 
-    socket.send_to(data, src)?;
+    // an IXFR carries the SOA of the version the client has
+    let serial = match question.qtype {
+        QueryType::IXFR => match request.authorities.first() {
+            Some(DnsRecord::SOA { serial, .. }) => Some(*serial),
+            _ => return fail(ResultCode::FORMERR),
+        },
+        _ if !tcp => return fail(ResultCode::FORMERR),
+        _ => None,
+    };
 
-    Ok(())
+    let Some(mut records) = context.zones.transfer(&question.name, serial) else {
+        return fail(ResultCode::REFUSED);
+    };
+    if !tcp {
+        // the SOA alone tells the client to come back over TCP (RFC 1995
+        // section 2)
+        records.truncate(1);
+    }
+
+    println!(
+        "Transferring {} to {}: {} records",
+        question.name,
+        src.ip(),
+        records.len()
+    );
+    transfer::responses(request, records, reserved)
 }
 
-fn server_run() -> Result<()> {
-    let socket = UdpSocket::bind(("0.0.0.0", 2053))?;
-    loop {
-        match handle_query(&socket) {
-            Ok(_) => {}
-            Err(e) => eprintln!("Error: {}", e),
+/// Acknowledge a NOTIFY from the primary of one of our secondary zones,
+/// which is then checked for a new version right away.
+fn handle_notify(
+    request: &DnsPacket,
+    src: SocketAddr,
+    key: Option<&str>,
+    context: &Context,
+) -> DnsPacket {
+    let Some(question) = request.questions.first() else {
+        return status_response(request, ResultCode::FORMERR);
+    };
+
+    println!("Received NOTIFY for {} from {}", question.name, src.ip());
+    if !context.zones.notified(&question.name, src.ip(), key) {
+        eprintln!("Refused NOTIFY for {} from {}", question.name, src.ip());
+        return status_response(request, ResultCode::REFUSED);
+    }
+
+    let mut response = status_response(request, ResultCode::NOERROR);
+    response.header.authoritative_answer = true;
+    response
+}
+
+/// Make the changes of an UPDATE to one of our zones, for the clients
+/// allowed to, and have its secondaries notified.
+fn handle_update(
+    request: &DnsPacket,
+    req_buffer: &mut BytePacketBuffer,
+    src: SocketAddr,
+    key: Option<&str>,
+    context: &Context,
+) -> DnsPacket {
+    if !context.config.allow_update.allows(src.ip(), key) {
+        eprintln!("Refused update from {}", src.ip());
+        return status_response(request, ResultCode::REFUSED);
+    }
+
+    let update = match Update::from_buffer(req_buffer) {
+        Ok(update) => update,
+        Err(err) => {
+            eprintln!("Malformed update from {}: {}", src.ip(), err);
+            return status_response(request, ResultCode::FORMERR);
         }
+    };
+
+    println!("Received update of {} from {}", update.zone.name, src.ip());
+    match context.zones.update(&update) {
+        Ok(changed) => {
+            if changed {
+                notify::zones_changed(context, &[update.zone.name]);
+            }
+            status_response(request, ResultCode::NOERROR)
+        }
+        Err(rescode) => status_response(request, rescode),
     }
 }
 
-fn recursion_lookup(qname: &str, qtype: QueryType) -> Result<DnsPacket> {
-    let mut ns = "192.33.4.12".parse::<Ipv4Addr>().unwrap();
+/// A response to `request` with nothing but `rescode` to say.
+fn status_response(request: &DnsPacket, rescode: ResultCode) -> DnsPacket {
+    let mut response = DnsPacket::new();
+    response.header.id = request.header.id;
+    response.header.opcode = request.header.opcode;
+    response.header.response = true;
+    response.header.rescode = rescode;
+    response.questions = request.questions.clone();
+    response
+}
 
+/// Serve the requests of a client over TCP (RFC 7766), until it closes the
+/// connection or stays idle too long.
+async fn handle_connection(
+    mut stream: TcpStream,
+    src: SocketAddr,
+    resolver: Resolver,
+) -> Result<()> {
     loop {
-        println!("attempting lookup of {:?} {} with ns {}", qtype, qname, ns);
+        let mut len = [0; 2];
+        match time::timeout(TCP_IDLE_TIMEOUT, stream.read_exact(&mut len)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(err)) if err.kind() != io::ErrorKind::UnexpectedEof => return Err(err.into()),
+            _ => return Ok(()),
+        }
+        // a client can't hold on to the connection by sending or reading
+        // slowly either (RFC 7766 section 6.2.3)
+        let mut req_buffer = BytePacketBuffer::with_size(u16::from_be_bytes(len) as usize);
+        time::timeout(TCP_IDLE_TIMEOUT, stream.read_exact(&mut req_buffer.buf)).await??;
+
+        for response in handle_request(req_buffer, src, true, &resolver).await? {
+            let mut message = (response.len() as u16).to_be_bytes().to_vec();
+            message.extend(response);
+            time::timeout(TCP_IDLE_TIMEOUT, stream.write_all(&message)).await??;
+        }
+    }
+}
+
+fn server_run(resolver: Resolver) -> Result<()> {
+    let context = resolver.context().clone();
+
+    if !context.config.zones.is_empty() {
+        // secondaries may have missed changes made while we were down
+        let origins = context
+            .config
+            .zones
+            .iter()
+            .map(|zone| zone.origin.clone())
+            .collect::<Vec<_>>();
+        notify::zones_changed(&context, &origins);
+
+        let context = context.clone();
+        thread::spawn(move || loop {
+            thread::sleep(ZONE_CHECK_INTERVAL);
+            notify::zones_changed(&context, &context.zones.reload());
+        });
+    }
+
+    {
+        let context = context.clone();
+        thread::spawn(move || loop {
+            thread::sleep(METRICS_INTERVAL);
+            context.metrics.log();
+        });
+    }
 
-        let server = (ns, 53);
+    if let Some(path) = context.config.cache_file.clone() {
+        let context = context.clone();
+        ctrlc::set_handler(move || {
+            match snapshot::save(&path, &context.cache, &context.rtts) {
+                Ok(()) => println!("Saved the cache to {}", path),
+                Err(err) => eprintln!("Failed to save the cache: {}", err),
+            }
+            process::exit(0);
+        })?;
+    }
 
-        // lookup
-        let response = lookup(qname, qtype, server)?;
+    let runtime = runtime::Builder::new_multi_thread().enable_all().build()?;
 
-        // if has answer, then down
-        if !response.answers.is_empty() && response.header.rescode == ResultCode::NOERROR {
-            return Ok(response);
+    runtime.block_on(async {
+        if context.router.forwarders().next().is_some() {
+            let context = context.clone();
+            tokio::spawn(async move {
+                loop {
+                    time::sleep(HEALTH_CHECK_INTERVAL).await;
+                    for forwarder in context.router.forwarders() {
+                        forwarder.check_health().await;
+                    }
+                }
+            });
         }
-        // else if response report has not the qname, then done
-        if response.header.rescode == ResultCode::NXDOMAIN {
-            return Ok(response);
+
+        if context
+            .validator
+            .as_ref()
+            .is_some_and(|validator| validator.manages_anchors())
+        {
+            let context = context.clone();
+            tokio::spawn(async move {
+                while let Some(validator) = &context.validator {
+                    let refresh = validator.refresh_anchors(&*fetch(&context)).await;
+                    time::sleep(refresh).await;
+                }
+            });
         }
-        // else if has new ns ip, continue lookup
-        if let Some(new_ns) = response.get_resolved_ns(qname) {
-            ns = new_ns;
-            continue;
+
+        if !context.config.secondaries.is_empty() {
+            let context = context.clone();
+            tokio::spawn(async move {
+                loop {
+                    notify::zones_changed(&context, &context.zones.refresh().await);
+                    time::sleep(REFRESH_CHECK_INTERVAL).await;
+                }
+            });
         }
 
-        // else if has new ns name, recursion lookup the new_ns's ip
-        let new_ns_name = match response.get_unresolved_ns(qname) {
-            Some(x) => x,
-            // else return response
-            None => return Ok(response),
-        };
+        let socket = Arc::new(UdpSocket::bind(("0.0.0.0", 2053)).await?);
+        let listener = TcpListener::bind(("0.0.0.0", 2053)).await?;
 
-        let recursion_response = recursion_lookup(new_ns_name, QueryType::A)?;
-        // if the new_ns has ip, then continue lookup
-        if let Some(new_ns) = recursion_response.get_random_a() {
-            ns = new_ns;
+        {
+            let resolver = resolver.clone();
+            let connections = Arc::new(Semaphore::new(context.config.tcp_connections));
+            tokio::spawn(async move {
+                loop {
+                    let (stream, src) = match listener.accept().await {
+                        Ok(connection) => connection,
+                        Err(e) => {
+                            eprintln!("Error: {}", e);
+                            continue;
+                        }
+                    };
+                    // closing the connection tells the client to try later
+                    let Ok(permit) = connections.clone().try_acquire_owned() else {
+                        continue;
+                    };
+                    let resolver = resolver.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_connection(stream, src, resolver).await {
+                            eprintln!("Error: {}", e);
+                        }
+                        drop(permit);
+                    });
+                }
+            });
         }
-        // else return response
-        else {
-            return Ok(response);
+
+        // queries beyond those being resolved wait for a free worker, and
+        // are dropped when too many are waiting already
+        let pending = Arc::new(Semaphore::new(
+            context.config.workers + context.config.queue_size,
+        ));
+
+        loop {
+            let mut req_buffer = BytePacketBuffer::with_size(EDNS_SIZE);
+            let src = match socket.recv_from(&mut req_buffer.buf).await {
+                Ok((len, src)) => {
+                    req_buffer.buf.truncate(len);
+                    src
+                }
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    continue;
+                }
+            };
+
+            let Ok(permit) = pending.clone().try_acquire_owned() else {
+                Metrics::count(&context.metrics.dropped_queries);
+                continue;
+            };
+            let socket = socket.clone();
+            let resolver = resolver.clone();
+            tokio::spawn(async move {
+                match handle_request(req_buffer, src, false, &resolver).await {
+                    Ok(responses) => {
+                        for response in responses {
+                            if let Err(e) = socket.send_to(&response, src).await {
+                                eprintln!("Error: {}", e);
+                            }
+                        }
+                    }
+                    Err(e) => eprintln!("Error: {}", e),
+                }
+                drop(permit);
+            });
         }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rdns::config::SecondaryZone;
+    use rdns::tsig::{Algorithm, Key};
+
+    fn context() -> Context {
+        let mut config = Config::new();
+        config.dnssec_validation = false;
+        config.secondaries.push(SecondaryZone {
+            origin: "example.com".to_owned(),
+            primary: "192.0.2.53:53".parse().unwrap(),
+            file: None,
+        });
+        Context::new(config).unwrap()
+    }
+
+    fn notify(name: &str) -> DnsPacket {
+        let mut request = DnsPacket::new();
+        request.header.id = 1234;
+        request.header.opcode = OPCODE_NOTIFY;
+        request.header.authoritative_answer = true;
+        request
+            .questions
+            .push(DnsQuestion::new(name, QueryType::SOA));
+        request
+    }
+
+    #[test]
+    fn notify_from_primary() {
+        let context = context();
+        let request = notify("example.com");
+
+        let response = handle_notify(&request, "192.0.2.53:5353".parse().unwrap(), None, &context);
+        assert_eq!(response.header.id, 1234);
+        assert_eq!(response.header.opcode, OPCODE_NOTIFY);
+        assert!(response.header.response);
+        assert!(response.header.authoritative_answer);
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert_eq!(response.questions, request.questions);
+    }
+
+    #[test]
+    fn notify_refused() {
+        let context = context();
+
+        // from anyone but the primary, or for a zone that isn't a secondary
+        let response = handle_notify(
+            &notify("example.com"),
+            "192.0.2.54:53".parse().unwrap(),
+            None,
+            &context,
+        );
+        assert_eq!(response.header.rescode, ResultCode::REFUSED);
+        let request = notify("example.org");
+        let response = handle_notify(&request, "192.0.2.53:53".parse().unwrap(), None, &context);
+        assert_eq!(response.header.rescode, ResultCode::REFUSED);
+        assert_eq!(response.questions, request.questions);
+
+        let mut request = notify("example.com");
+        request.questions.clear();
+        let response = handle_notify(&request, "192.0.2.53:53".parse().unwrap(), None, &context);
+        assert_eq!(response.header.rescode, ResultCode::FORMERR);
+    }
+
+    /// A UDP query for `name` signed with a key we don't know, with labels
+    /// of `labels` bytes in its name.
+    fn unknown_key_query(name: &str, labels: usize) -> BytePacketBuffer {
+        let mut request = DnsPacket::new();
+        request.header.id = 1234;
+        request.questions.push(DnsQuestion::new(name, QueryType::A));
+        let mut buffer = BytePacketBuffer::new();
+        request.write(&mut buffer).unwrap();
+
+        let label = "k".repeat(labels);
+        let key = Key {
+            name: format!("{}.{}.{}.{}", label, label, label, label),
+            algorithm: Algorithm::HmacSha256,
+            secret: b"secretsecretsecretsecret".to_vec(),
+        };
+        let mut message = buffer.buf[..buffer.pos].to_vec();
+        Session::new(key).sign(&mut message).unwrap();
+        BytePacketBuffer {
+            buf: message,
+            pos: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn rejected_response_fits() {
+        let resolver = Resolver::new(Config::new()).unwrap();
+        let src = "192.0.2.1:5353".parse().unwrap();
+
+        let request = unknown_key_query("www.example.com", 60);
+        let responses = handle_request(request, src, false, &resolver)
+            .await
+            .unwrap();
+        let [response] = responses.as_slice() else {
+            panic!("Expected a single response");
+        };
+        assert!(response.len() <= UDP_SIZE);
+        let mut buffer = BytePacketBuffer {
+            buf: response.clone(),
+            pos: 0,
+        };
+        let response = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert_eq!(response.header.rescode, ResultCode::NOTAUTH);
+        assert!(matches!(
+            response.resources.as_slice(),
+            [DnsRecord::TSIG {
+                error: tsig::BADKEY,
+                ..
+            }]
+        ));
+
+        // the question and the TSIG together being too big for the client,
+        // it gets nothing rather than a response it can't read
+        let label = "w".repeat(60);
+        let name = format!("{}.{}.{}.{}", label, label, label, label);
+        let request = unknown_key_query(&name, 60);
+        assert!(handle_request(request, src, false, &resolver)
+            .await
+            .is_err());
     }
 }
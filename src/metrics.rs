@@ -0,0 +1,57 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// How often the counters are logged.
+pub const METRICS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Counters of notable events, logged every `METRICS_INTERVAL`.
+pub struct Metrics {
+    /// Expired answers served because resolving failed (RFC 8767).
+    pub stale_after_failure: AtomicU64,
+    /// Expired answers served because resolving took too long.
+    pub stale_after_timeout: AtomicU64,
+    /// Popular answers refreshed before they expired.
+    pub prefetches: AtomicU64,
+    /// Queries dropped because the queue of queries waiting for a worker was full.
+    pub dropped_queries: AtomicU64,
+    /// Queries answered by joining the resolution of the same question
+    /// asked by another client.
+    pub coalesced_queries: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            stale_after_failure: AtomicU64::new(0),
+            stale_after_timeout: AtomicU64::new(0),
+            prefetches: AtomicU64::new(0),
+            dropped_queries: AtomicU64::new(0),
+            coalesced_queries: AtomicU64::new(0),
+        }
+    }
+
+    pub fn count(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn log(&self) {
+        let failure = self.stale_after_failure.load(Ordering::Relaxed);
+        let timeout = self.stale_after_timeout.load(Ordering::Relaxed);
+        let prefetches = self.prefetches.load(Ordering::Relaxed);
+        let dropped = self.dropped_queries.load(Ordering::Relaxed);
+        let coalesced = self.coalesced_queries.load(Ordering::Relaxed);
+
+        println!(
+            "Stale answers served: {} ({} after failures, {} after timeouts), prefetches: {}, \
+             dropped queries: {}, coalesced queries: {}",
+            failure + timeout,
+            failure,
+            timeout,
+            prefetches,
+            dropped,
+            coalesced
+        );
+    }
+}
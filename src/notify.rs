@@ -0,0 +1,164 @@
+use crate::context::Context;
+use crate::packet::*;
+use crate::tsig::{Key, Session};
+use rand::Rng;
+use std::{
+    net::{SocketAddr, UdpSocket},
+    thread,
+    time::Duration,
+};
+
+/// How long a secondary has to acknowledge a NOTIFY before it's sent again.
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(2);
+/// NOTIFY sent to a secondary before giving up on it.
+const NOTIFY_ATTEMPTS: usize = 5;
+
+/// Tell the secondaries of the zones `origins` that they changed, in the
+/// background.
+pub fn zones_changed(context: &Context, origins: &[String]) {
+    for origin in origins {
+        let Some(soa) = context.zones.soa(origin) else {
+            continue;
+        };
+        for notify in context.config.notify.iter().filter(|n| n.origin == *origin) {
+            for secondary in notify.secondaries.clone() {
+                let soa = soa.clone();
+                let key = context.config.server_key(secondary.ip()).cloned();
+                thread::spawn(move || {
+                    if let Err(err) = send(&soa, secondary, key.as_ref()) {
+                        eprintln!(
+                            "Failed to notify {} of zone {}: {}",
+                            secondary,
+                            soa.domain(),
+                            err
+                        );
+                    }
+                });
+            }
+        }
+    }
+}
+
+/// Send a NOTIFY for the zone of `soa` to `secondary` until it answers
+/// (RFC 1996 section 3.6), signed with `key` if given.
+pub fn send(soa: &DnsRecord, secondary: SocketAddr, key: Option<&Key>) -> Result<()> {
+    let socket = match secondary {
+        SocketAddr::V4(_) => UdpSocket::bind(("0.0.0.0", 0))?,
+        SocketAddr::V6(_) => UdpSocket::bind(("::", 0))?,
+    };
+    socket.set_read_timeout(Some(NOTIFY_TIMEOUT))?;
+
+    let mut request = DnsPacket::new();
+    request.header.id = rand::thread_rng().gen();
+    request.header.opcode = OPCODE_NOTIFY;
+    request.header.authoritative_answer = true;
+    request
+        .questions
+        .push(DnsQuestion::new(soa.domain(), QueryType::SOA));
+    // the new SOA, as a hint for the secondary (RFC 1996 section 3.7)
+    request.answers.push(soa.clone());
+
+    let mut req_buffer = BytePacketBuffer::new();
+    request.write(&mut req_buffer)?;
+    let mut message = req_buffer.buf[..req_buffer.pos].to_vec();
+    let mut session = key.cloned().map(Session::new);
+    if let Some(session) = &mut session {
+        session.sign(&mut message)?;
+    }
+
+    for _ in 0..NOTIFY_ATTEMPTS {
+        socket.send_to(&message, secondary)?;
+
+        let mut res_buffer = BytePacketBuffer::with_size(EDNS_SIZE);
+        let Ok((len, src)) = socket.recv_from(&mut res_buffer.buf) else {
+            continue;
+        };
+        res_buffer.buf.truncate(len);
+        let response = DnsPacket::from_buffer(&mut res_buffer)?;
+        if src != secondary
+            || response.header.id != request.header.id
+            || response.header.opcode != OPCODE_NOTIFY
+        {
+            continue;
+        }
+        if let Some(session) = &mut session {
+            session.verify(&res_buffer.buf)?;
+        }
+
+        return match response.header.rescode {
+            ResultCode::NOERROR => {
+                println!("Notified {} of zone {}", secondary, soa.domain());
+                Ok(())
+            }
+            rescode => Err(format!("{} answered {:?}", secondary, rescode).into()),
+        };
+    }
+
+    Err("No answer".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn soa() -> DnsRecord {
+        DnsRecord::SOA {
+            domain: "example.com".to_owned(),
+            mname: "ns.example.com".to_owned(),
+            rname: "hostmaster.example.com".to_owned(),
+            serial: 2,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 300,
+            ttl: 3600,
+        }
+    }
+
+    /// A secondary acknowledging the NOTIFY it gets with `rescode`, which
+    /// hands the NOTIFY back.
+    fn secondary(rescode: ResultCode) -> (SocketAddr, thread::JoinHandle<DnsPacket>) {
+        let socket = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr = socket.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let mut buffer = BytePacketBuffer::new();
+            let (_, src) = socket.recv_from(&mut buffer.buf).unwrap();
+            let request = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+            let mut response = DnsPacket::new();
+            response.header.id = request.header.id;
+            response.header.opcode = request.header.opcode;
+            response.header.response = true;
+            response.header.rescode = rescode;
+            response.questions = request.questions.clone();
+            let mut buffer = BytePacketBuffer::new();
+            response.write(&mut buffer).unwrap();
+            socket.send_to(&buffer.buf[..buffer.pos], src).unwrap();
+
+            request
+        });
+        (addr, handle)
+    }
+
+    #[test]
+    fn sends_notify_with_soa() {
+        let (addr, handle) = secondary(ResultCode::NOERROR);
+        send(&soa(), addr, None).unwrap();
+
+        let request = handle.join().unwrap();
+        assert_eq!(request.header.opcode, OPCODE_NOTIFY);
+        assert!(request.header.authoritative_answer);
+        assert_eq!(
+            request.questions,
+            [DnsQuestion::new("example.com", QueryType::SOA)]
+        );
+        assert_eq!(request.answers, [soa()]);
+    }
+
+    #[test]
+    fn fails_when_refused() {
+        let (addr, handle) = secondary(ResultCode::REFUSED);
+        assert!(send(&soa(), addr, None).is_err());
+        handle.join().unwrap();
+    }
+}
@@ -1,22 +1,41 @@
 use std::{
     fmt::Display,
-    net::{Ipv4Addr, Ipv6Addr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
 };
 
-pub type Error = Box<dyn std::error::Error>;
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
 pub type Result<T> = std::result::Result<T, Error>;
 
-const BUF_SIZE: usize = 512;
+/// Largest message allowed over UDP without EDNS.
+pub const UDP_SIZE: usize = 512;
+/// UDP payload size we advertise with EDNS and accept from others.
+pub const EDNS_SIZE: usize = 4096;
+
+/// The DO bit in the flags of an OPT record.
+pub const DNSSEC_OK: u32 = 0x8000;
+
+/// Opcodes of the requests we handle.
+pub const OPCODE_QUERY: u8 = 0;
+pub const OPCODE_NOTIFY: u8 = 4;
+pub const OPCODE_UPDATE: u8 = 5;
+
+/// Classes of records, the last two only meaningful in UPDATE.
+pub const CLASS_IN: u16 = 1;
+pub const CLASS_NONE: u16 = 254;
+pub const CLASS_ANY: u16 = 255;
 
 pub struct BytePacketBuffer {
-    pub buf: [u8; BUF_SIZE],
+    pub buf: Vec<u8>,
     pub pos: usize,
 }
 
 impl BytePacketBuffer {
     pub fn new() -> Self {
+        BytePacketBuffer::with_size(UDP_SIZE)
+    }
+    pub fn with_size(size: usize) -> Self {
         BytePacketBuffer {
-            buf: [0; BUF_SIZE],
+            buf: vec![0; size],
             pos: 0,
         }
     }
@@ -32,13 +51,12 @@ impl BytePacketBuffer {
         Ok(())
     }
     fn read(&mut self) -> Result<u8> {
-        self.not_end_of_buf(self.pos)?;
-        let res = self.buf[self.pos];
+        let res = self.get(self.pos)?;
         self.step(1)?;
         Ok(res)
     }
     fn not_end_of_buf(&self, pos: usize) -> Result<()> {
-        if pos > BUF_SIZE {
+        if pos > self.buf.len() {
             return Err("End of buffer".into());
         }
         Ok(())
@@ -46,7 +64,7 @@ impl BytePacketBuffer {
 
     /// test doc
     fn get(&self, pos: usize) -> Result<u8> {
-        self.not_end_of_buf(pos)?;
+        self.not_end_of_buf(pos + 1)?;
         Ok(self.buf[pos])
     }
     pub fn get_range(&self, start: usize, len: usize) -> Result<&[u8]> {
@@ -102,7 +120,7 @@ impl BytePacketBuffer {
 
                 outstr.push_str(delim);
                 let label = self.get_range(pos, len as usize)?;
-                outstr.push_str(&String::from_utf8_lossy(label).to_lowercase());
+                outstr.push_str(&escape_label(&label.to_ascii_lowercase()));
                 pos += len as usize;
 
                 delim = ".";
@@ -116,24 +134,46 @@ impl BytePacketBuffer {
         Ok(())
     }
 
+    fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>> {
+        let res = self.get_range(self.pos, len)?.to_vec();
+        self.step(len)?;
+        Ok(res)
+    }
+    /// Read an NSEC style type bitmap running up to `end`.
+    fn read_type_bitmap(&mut self, end: usize) -> Result<Vec<QueryType>> {
+        let mut types = Vec::new();
+        while self.pos < end {
+            let window = self.read()? as u16;
+            let len = self.read()? as usize;
+            for (i, byte) in self.read_bytes(len)?.into_iter().enumerate() {
+                for bit in 0..8 {
+                    if byte & (0x80 >> bit) != 0 {
+                        types.push(QueryType::from_num((window << 8) | (i * 8 + bit) as u16));
+                    }
+                }
+            }
+        }
+        Ok(types)
+    }
+
     fn write(&mut self, val: u8) -> Result<()> {
-        self.not_end_of_buf(self.pos)?;
+        self.not_end_of_buf(self.pos + 1)?;
         self.buf[self.pos] = val;
         self.pos += 1;
         Ok(())
     }
-    fn write_u8(&mut self, val: u8) -> Result<()> {
+    pub fn write_u8(&mut self, val: u8) -> Result<()> {
         self.write(val)?;
         Ok(())
     }
-    fn write_u16(&mut self, val: u16) -> Result<()> {
+    pub fn write_u16(&mut self, val: u16) -> Result<()> {
         let a = ((val >> 8) & 0xFF) as u8;
         let b = ((val >> 0) & 0xFF) as u8;
         self.write(a)?;
         self.write(b)?;
         Ok(())
     }
-    fn write_u32(&mut self, val: u32) -> Result<()> {
+    pub fn write_u32(&mut self, val: u32) -> Result<()> {
         let a = ((val >> 24) & 0xFF) as u8;
         let b = ((val >> 16) & 0xFF) as u8;
         let c = ((val >> 8) & 0xFF) as u8;
@@ -146,22 +186,48 @@ impl BytePacketBuffer {
         Ok(())
     }
 
-    fn write_qname(&mut self, qname: &str) -> Result<()> {
-        for label in qname.split('.') {
-            let len = label.len();
-            if len > 0x3f {
-                return Err("Single label exceeds 63 characters of length".into());
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        for byte in bytes {
+            self.write(*byte)?;
+        }
+        Ok(())
+    }
+    fn write_type_bitmap(&mut self, types: &[QueryType]) -> Result<()> {
+        let mut nums = types.iter().map(|qtype| qtype.to_num()).collect::<Vec<_>>();
+        nums.sort_unstable();
+        nums.dedup();
+
+        for window in nums.chunk_by(|a, b| a >> 8 == b >> 8) {
+            let mut bitmap = [0u8; 32];
+            for num in window {
+                let bit = (num & 0xFF) as usize;
+                bitmap[bit / 8] |= 0x80 >> (bit % 8);
             }
+            let len = bitmap.iter().rposition(|byte| *byte != 0).unwrap_or(0) + 1;
+
+            self.write_u8((window[0] >> 8) as u8)?;
             self.write_u8(len as u8)?;
-            for c in label.as_bytes() {
-                self.write_u8(*c)?;
+            self.write_bytes(&bitmap[..len])?;
+        }
+        Ok(())
+    }
+
+    pub fn write_qname(&mut self, qname: &str) -> Result<()> {
+        // the root is just the terminating empty label
+        for label in qname.split('.').filter(|label| !label.is_empty()) {
+            let label = label_bytes(label);
+            if label.len() > 0x3f {
+                return Err("Single label exceeds 63 characters of length".into());
             }
+            self.write_u8(label.len() as u8)?;
+            self.write_bytes(&label)?;
         }
         self.write_u8(0)?;
         Ok(())
     }
 
     fn set(&mut self, pos: usize, val: u8) -> Result<()> {
+        self.not_end_of_buf(pos + 1)?;
         self.buf[pos] = val;
 
         Ok(())
@@ -183,6 +249,11 @@ pub enum ResultCode {
     NXDOMAIN = 3,
     NOTIMP = 4,
     REFUSED = 5,
+    YXDOMAIN = 6,
+    YXRRSET = 7,
+    NXRRSET = 8,
+    NOTAUTH = 9,
+    NOTZONE = 10,
 }
 
 impl ResultCode {
@@ -194,7 +265,12 @@ impl ResultCode {
             3 => NXDOMAIN,
             4 => NOTIMP,
             5 => REFUSED,
-            0 | _ => NOERROR,
+            6 => YXDOMAIN,
+            7 => YXRRSET,
+            8 => NXRRSET,
+            9 => NOTAUTH,
+            10 => NOTZONE,
+            _ => NOERROR,
         }
     }
 }
@@ -302,14 +378,25 @@ impl DnsHeader {
     }
 }
 
-#[derive(PartialEq, Eq, Clone, Copy, Debug, Hash)]
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Hash, PartialOrd, Ord)]
 pub enum QueryType {
     UNKNOWN(u16),
     A,
     NS,
     CNAME,
+    SOA,
     MX,
+    TXT,
     AAAA,
+    OPT,
+    DS,
+    RRSIG,
+    NSEC,
+    DNSKEY,
+    NSEC3,
+    TSIG,
+    IXFR,
+    AXFR,
 }
 
 impl QueryType {
@@ -318,22 +405,83 @@ impl QueryType {
             1 => QueryType::A,
             2 => QueryType::NS,
             5 => QueryType::CNAME,
+            6 => QueryType::SOA,
             15 => QueryType::MX,
+            16 => QueryType::TXT,
             28 => QueryType::AAAA,
+            41 => QueryType::OPT,
+            43 => QueryType::DS,
+            46 => QueryType::RRSIG,
+            47 => QueryType::NSEC,
+            48 => QueryType::DNSKEY,
+            50 => QueryType::NSEC3,
+            250 => QueryType::TSIG,
+            251 => QueryType::IXFR,
+            252 => QueryType::AXFR,
             _ => Self::UNKNOWN(num),
         }
     }
 
-    pub fn to_num(&self) -> u16 {
-        match *self {
+    pub fn to_num(self) -> u16 {
+        match self {
             QueryType::UNKNOWN(x) => x,
             QueryType::A => 1,
             QueryType::NS => 2,
             QueryType::CNAME => 5,
+            QueryType::SOA => 6,
             QueryType::MX => 15,
+            QueryType::TXT => 16,
             QueryType::AAAA => 28,
+            QueryType::OPT => 41,
+            QueryType::DS => 43,
+            QueryType::RRSIG => 46,
+            QueryType::NSEC => 47,
+            QueryType::DNSKEY => 48,
+            QueryType::NSEC3 => 50,
+            QueryType::TSIG => 250,
+            QueryType::IXFR => 251,
+            QueryType::AXFR => 252,
         }
     }
+
+    /// The type written as a mnemonic like `AAAA`, or as `TYPE65` (RFC 3597).
+    pub fn from_name(name: &str) -> Option<QueryType> {
+        let qtype = match name.to_ascii_uppercase().as_str() {
+            "A" => QueryType::A,
+            "NS" => QueryType::NS,
+            "CNAME" => QueryType::CNAME,
+            "SOA" => QueryType::SOA,
+            "MX" => QueryType::MX,
+            "TXT" => QueryType::TXT,
+            "AAAA" => QueryType::AAAA,
+            "DS" => QueryType::DS,
+            "RRSIG" => QueryType::RRSIG,
+            "NSEC" => QueryType::NSEC,
+            "DNSKEY" => QueryType::DNSKEY,
+            "NSEC3" => QueryType::NSEC3,
+            "TSIG" => QueryType::TSIG,
+            "IXFR" => QueryType::IXFR,
+            "AXFR" => QueryType::AXFR,
+            other => {
+                let num = other.strip_prefix("TYPE")?.parse().ok()?;
+                QueryType::from_num(num)
+            }
+        };
+
+        Some(qtype)
+    }
+
+    /// Whether records of this type only exist to secure other records.
+    pub fn is_dnssec(self) -> bool {
+        matches!(
+            self,
+            QueryType::DS
+                | QueryType::RRSIG
+                | QueryType::NSEC
+                | QueryType::DNSKEY
+                | QueryType::NSEC3
+        )
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -373,7 +521,7 @@ pub enum DnsRecord {
     UNKNOWN {
         domain: String,
         qtype: u16,
-        data_len: u16,
+        data: Vec<u8>,
         ttl: u32,
     }, // 0
     A {
@@ -391,31 +539,128 @@ pub enum DnsRecord {
         host: String,
         ttl: u32,
     }, // 5
+    SOA {
+        domain: String,
+        mname: String,
+        rname: String,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+        ttl: u32,
+    }, // 6
     MX {
         domain: String,
         priority: u16,
         host: String,
         ttl: u32,
     }, // 15
+    TXT {
+        domain: String,
+        /// The character-strings, each at most 255 bytes.
+        data: Vec<Vec<u8>>,
+        ttl: u32,
+    }, // 16
     AAAA {
         domain: String,
         addr: Ipv6Addr,
         ttl: u32,
     }, // 28
+    OPT {
+        packet_len: u16,
+        flags: u32,
+        data: Vec<u8>,
+    }, // 41
+    DS {
+        domain: String,
+        key_tag: u16,
+        algorithm: u8,
+        digest_type: u8,
+        digest: Vec<u8>,
+        ttl: u32,
+    }, // 43
+    RRSIG {
+        domain: String,
+        type_covered: QueryType,
+        algorithm: u8,
+        labels: u8,
+        original_ttl: u32,
+        expiration: u32,
+        inception: u32,
+        key_tag: u16,
+        signer_name: String,
+        signature: Vec<u8>,
+        ttl: u32,
+    }, // 46
+    NSEC {
+        domain: String,
+        next_domain: String,
+        types: Vec<QueryType>,
+        ttl: u32,
+    }, // 47
+    DNSKEY {
+        domain: String,
+        flags: u16,
+        protocol: u8,
+        algorithm: u8,
+        public_key: Vec<u8>,
+        ttl: u32,
+    }, // 48
+    NSEC3 {
+        domain: String,
+        hash_algorithm: u8,
+        flags: u8,
+        iterations: u16,
+        salt: Vec<u8>,
+        next_hashed: Vec<u8>,
+        types: Vec<QueryType>,
+        ttl: u32,
+    }, // 50
+    TSIG {
+        /// The name of the key the message is signed with.
+        domain: String,
+        algorithm: String,
+        /// Seconds since the epoch, on 48 bits.
+        time_signed: u64,
+        fudge: u16,
+        mac: Vec<u8>,
+        original_id: u16,
+        error: u16,
+        other: Vec<u8>,
+    }, // 250
 }
 
 impl DnsRecord {
     pub fn read(buffer: &mut BytePacketBuffer) -> Result<DnsRecord> {
+        Ok(DnsRecord::read_with_class(buffer)?.0)
+    }
+
+    /// A record along with its class, which UPDATE gives a meaning of its
+    /// own (RFC 2136 section 2.4 and 2.5).
+    pub fn read_with_class(buffer: &mut BytePacketBuffer) -> Result<(DnsRecord, u16)> {
         let mut domain = String::new();
         buffer.read_qname(&mut domain)?;
 
         let qtype_num = buffer.read_u16()?;
         let qtype = QueryType::from_num(qtype_num);
-        let _ = buffer.read_u16()?;
+        let class = buffer.read_u16()?;
         let ttl = buffer.read_u32()?;
         let data_len = buffer.read_u16()?;
+        let data_end = buffer.pos() + data_len as usize;
 
-        match qtype {
+        // the RDATA of UPDATE's prerequisites and deletions may be empty
+        if data_len == 0 && qtype != QueryType::OPT {
+            let record = DnsRecord::UNKNOWN {
+                domain,
+                qtype: qtype_num,
+                data: Vec::new(),
+                ttl,
+            };
+            return Ok((record, class));
+        }
+
+        let record: Result<DnsRecord> = match qtype {
             QueryType::A => {
                 let raw_addr = buffer.read_u32()?;
                 let addr = Ipv4Addr::new(
@@ -425,11 +670,7 @@ impl DnsRecord {
                     ((raw_addr >> 0) & 0xFF) as u8,
                 );
 
-                Ok(DnsRecord::A {
-                    domain: domain,
-                    addr: addr,
-                    ttl: ttl,
-                })
+                Ok(DnsRecord::A { domain, addr, ttl })
             }
             QueryType::NS => {
                 let mut host = String::new();
@@ -455,6 +696,15 @@ impl DnsRecord {
                     ttl,
                 })
             }
+            QueryType::TXT => {
+                let mut data = Vec::new();
+                while buffer.pos() < data_end {
+                    let len = buffer.read()? as usize;
+                    data.push(buffer.read_bytes(len)?);
+                }
+
+                Ok(DnsRecord::TXT { domain, data, ttl })
+            }
             QueryType::AAAA => {
                 let a = buffer.read_u16()?;
                 let b = buffer.read_u16()?;
@@ -472,16 +722,293 @@ impl DnsRecord {
                     ttl,
                 })
             }
-            QueryType::UNKNOWN(_) => {
-                buffer.step(data_len as usize)?;
+            QueryType::SOA => {
+                let mut mname = String::new();
+                buffer.read_qname(&mut mname)?;
+                let mut rname = String::new();
+                buffer.read_qname(&mut rname)?;
 
-                Ok(DnsRecord::UNKNOWN {
-                    domain: domain,
-                    qtype: qtype_num,
-                    data_len: data_len,
-                    ttl: ttl,
+                Ok(DnsRecord::SOA {
+                    domain,
+                    mname,
+                    rname,
+                    serial: buffer.read_u32()?,
+                    refresh: buffer.read_u32()?,
+                    retry: buffer.read_u32()?,
+                    expire: buffer.read_u32()?,
+                    minimum: buffer.read_u32()?,
+                    ttl,
+                })
+            }
+            QueryType::OPT => Ok(DnsRecord::OPT {
+                packet_len: class,
+                flags: ttl,
+                data: buffer.read_bytes(data_len as usize)?,
+            }),
+            QueryType::DS => {
+                let key_tag = buffer.read_u16()?;
+                let algorithm = buffer.read()?;
+                let digest_type = buffer.read()?;
+                let digest = buffer.read_bytes(data_end.saturating_sub(buffer.pos()))?;
+
+                Ok(DnsRecord::DS {
+                    domain,
+                    key_tag,
+                    algorithm,
+                    digest_type,
+                    digest,
+                    ttl,
+                })
+            }
+            QueryType::RRSIG => {
+                let type_covered = QueryType::from_num(buffer.read_u16()?);
+                let algorithm = buffer.read()?;
+                let labels = buffer.read()?;
+                let original_ttl = buffer.read_u32()?;
+                let expiration = buffer.read_u32()?;
+                let inception = buffer.read_u32()?;
+                let key_tag = buffer.read_u16()?;
+                let mut signer_name = String::new();
+                buffer.read_qname(&mut signer_name)?;
+                let signature = buffer.read_bytes(data_end.saturating_sub(buffer.pos()))?;
+
+                Ok(DnsRecord::RRSIG {
+                    domain,
+                    type_covered,
+                    algorithm,
+                    labels,
+                    original_ttl,
+                    expiration,
+                    inception,
+                    key_tag,
+                    signer_name,
+                    signature,
+                    ttl,
                 })
             }
+            QueryType::NSEC => {
+                let mut next_domain = String::new();
+                buffer.read_qname(&mut next_domain)?;
+                let types = buffer.read_type_bitmap(data_end)?;
+
+                Ok(DnsRecord::NSEC {
+                    domain,
+                    next_domain,
+                    types,
+                    ttl,
+                })
+            }
+            QueryType::DNSKEY => {
+                let flags = buffer.read_u16()?;
+                let protocol = buffer.read()?;
+                let algorithm = buffer.read()?;
+                let public_key = buffer.read_bytes(data_end.saturating_sub(buffer.pos()))?;
+
+                Ok(DnsRecord::DNSKEY {
+                    domain,
+                    flags,
+                    protocol,
+                    algorithm,
+                    public_key,
+                    ttl,
+                })
+            }
+            QueryType::NSEC3 => {
+                let hash_algorithm = buffer.read()?;
+                let flags = buffer.read()?;
+                let iterations = buffer.read_u16()?;
+                let salt_len = buffer.read()? as usize;
+                let salt = buffer.read_bytes(salt_len)?;
+                let hash_len = buffer.read()? as usize;
+                let next_hashed = buffer.read_bytes(hash_len)?;
+                let types = buffer.read_type_bitmap(data_end)?;
+
+                Ok(DnsRecord::NSEC3 {
+                    domain,
+                    hash_algorithm,
+                    flags,
+                    iterations,
+                    salt,
+                    next_hashed,
+                    types,
+                    ttl,
+                })
+            }
+            QueryType::TSIG => {
+                let mut algorithm = String::new();
+                buffer.read_qname(&mut algorithm)?;
+                let time_signed = (buffer.read_u16()? as u64) << 32 | buffer.read_u32()? as u64;
+                let fudge = buffer.read_u16()?;
+                let mac_len = buffer.read_u16()? as usize;
+                let mac = buffer.read_bytes(mac_len)?;
+                let original_id = buffer.read_u16()?;
+                let error = buffer.read_u16()?;
+                let other_len = buffer.read_u16()? as usize;
+                let other = buffer.read_bytes(other_len)?;
+
+                Ok(DnsRecord::TSIG {
+                    domain,
+                    algorithm,
+                    time_signed,
+                    fudge,
+                    mac,
+                    original_id,
+                    error,
+                    other,
+                })
+            }
+            // meta types have no records of their own
+            QueryType::UNKNOWN(_) | QueryType::IXFR | QueryType::AXFR => Ok(DnsRecord::UNKNOWN {
+                domain,
+                qtype: qtype_num,
+                data: buffer.read_bytes(data_len as usize)?,
+                ttl,
+            }),
+        };
+
+        Ok((record?, class))
+    }
+
+    /// The RRSIG RDATA up to the signature, which is also what the
+    /// signature covers (RFC 4034 section 3.1.8.1).
+    pub fn write_rrsig_fields(&self, buffer: &mut BytePacketBuffer) -> Result<()> {
+        if let DnsRecord::RRSIG {
+            type_covered,
+            algorithm,
+            labels,
+            original_ttl,
+            expiration,
+            inception,
+            key_tag,
+            signer_name,
+            ..
+        } = self
+        {
+            buffer.write_u16(type_covered.to_num())?;
+            buffer.write_u8(*algorithm)?;
+            buffer.write_u8(*labels)?;
+            buffer.write_u32(*original_ttl)?;
+            buffer.write_u32(*expiration)?;
+            buffer.write_u32(*inception)?;
+            buffer.write_u16(*key_tag)?;
+            buffer.write_qname(signer_name)?;
+        }
+        Ok(())
+    }
+
+    pub fn domain(&self) -> &str {
+        match self {
+            DnsRecord::UNKNOWN { domain, .. }
+            | DnsRecord::A { domain, .. }
+            | DnsRecord::NS { domain, .. }
+            | DnsRecord::CNAME { domain, .. }
+            | DnsRecord::SOA { domain, .. }
+            | DnsRecord::MX { domain, .. }
+            | DnsRecord::TXT { domain, .. }
+            | DnsRecord::AAAA { domain, .. }
+            | DnsRecord::DS { domain, .. }
+            | DnsRecord::RRSIG { domain, .. }
+            | DnsRecord::NSEC { domain, .. }
+            | DnsRecord::DNSKEY { domain, .. }
+            | DnsRecord::NSEC3 { domain, .. }
+            | DnsRecord::TSIG { domain, .. } => domain,
+            DnsRecord::OPT { .. } => "",
+        }
+    }
+
+    pub fn qtype(&self) -> QueryType {
+        match self {
+            DnsRecord::UNKNOWN { qtype, .. } => QueryType::from_num(*qtype),
+            DnsRecord::A { .. } => QueryType::A,
+            DnsRecord::NS { .. } => QueryType::NS,
+            DnsRecord::CNAME { .. } => QueryType::CNAME,
+            DnsRecord::SOA { .. } => QueryType::SOA,
+            DnsRecord::MX { .. } => QueryType::MX,
+            DnsRecord::TXT { .. } => QueryType::TXT,
+            DnsRecord::AAAA { .. } => QueryType::AAAA,
+            DnsRecord::OPT { .. } => QueryType::OPT,
+            DnsRecord::DS { .. } => QueryType::DS,
+            DnsRecord::RRSIG { .. } => QueryType::RRSIG,
+            DnsRecord::NSEC { .. } => QueryType::NSEC,
+            DnsRecord::DNSKEY { .. } => QueryType::DNSKEY,
+            DnsRecord::NSEC3 { .. } => QueryType::NSEC3,
+            DnsRecord::TSIG { .. } => QueryType::TSIG,
+        }
+    }
+
+    pub fn ttl(&self) -> u32 {
+        match self {
+            DnsRecord::UNKNOWN { ttl, .. }
+            | DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::TXT { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. }
+            | DnsRecord::DS { ttl, .. }
+            | DnsRecord::RRSIG { ttl, .. }
+            | DnsRecord::NSEC { ttl, .. }
+            | DnsRecord::DNSKEY { ttl, .. }
+            | DnsRecord::NSEC3 { ttl, .. } => *ttl,
+            DnsRecord::OPT { .. } | DnsRecord::TSIG { .. } => 0,
+        }
+    }
+
+    pub fn set_ttl(&mut self, new_ttl: u32) {
+        match self {
+            DnsRecord::UNKNOWN { ttl, .. }
+            | DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::TXT { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. }
+            | DnsRecord::DS { ttl, .. }
+            | DnsRecord::RRSIG { ttl, .. }
+            | DnsRecord::NSEC { ttl, .. }
+            | DnsRecord::DNSKEY { ttl, .. }
+            | DnsRecord::NSEC3 { ttl, .. } => *ttl = new_ttl,
+            DnsRecord::OPT { .. } | DnsRecord::TSIG { .. } => {}
+        }
+    }
+
+    pub fn set_domain(&mut self, name: &str) {
+        match self {
+            DnsRecord::UNKNOWN { domain, .. }
+            | DnsRecord::A { domain, .. }
+            | DnsRecord::NS { domain, .. }
+            | DnsRecord::CNAME { domain, .. }
+            | DnsRecord::SOA { domain, .. }
+            | DnsRecord::MX { domain, .. }
+            | DnsRecord::TXT { domain, .. }
+            | DnsRecord::AAAA { domain, .. }
+            | DnsRecord::DS { domain, .. }
+            | DnsRecord::RRSIG { domain, .. }
+            | DnsRecord::NSEC { domain, .. }
+            | DnsRecord::DNSKEY { domain, .. }
+            | DnsRecord::NSEC3 { domain, .. }
+            | DnsRecord::TSIG { domain, .. } => *domain = name.to_owned(),
+            DnsRecord::OPT { .. } => {}
+        }
+    }
+
+    /// The record's RDATA in wire format. Names are never compressed and
+    /// always lowercase, so this is also the canonical form of RFC 4034.
+    pub fn rdata(&self) -> Result<Vec<u8>> {
+        let mut buffer = BytePacketBuffer::with_size(0xFFFF);
+        self.write(&mut buffer)?;
+
+        let header_len = name_len(self.domain()) + 10;
+        Ok(buffer.buf[header_len..buffer.pos].to_vec())
+    }
+
+    pub fn addr(&self) -> Option<IpAddr> {
+        match self {
+            DnsRecord::A { addr, .. } => Some(IpAddr::V4(*addr)),
+            DnsRecord::AAAA { addr, .. } => Some(IpAddr::V6(*addr)),
+            _ => None,
         }
     }
 
@@ -550,6 +1077,19 @@ impl DnsRecord {
                 let size = buffer.pos() - (pos + 2);
                 buffer.set_u16(pos, size as u16)?;
             }
+            DnsRecord::TXT { domain, data, ttl } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::TXT.to_num())?;
+                buffer.write_u16(1)?; // class
+                buffer.write_u32(*ttl)?;
+                let len = data.iter().map(|string| 1 + string.len()).sum::<usize>();
+                buffer.write_u16(len as u16)?; // len
+
+                for string in data {
+                    buffer.write_u8(string.len() as u8)?;
+                    buffer.write_bytes(string)?;
+                }
+            }
             DnsRecord::AAAA { domain, addr, ttl } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::AAAA.to_num())?;
@@ -561,8 +1101,205 @@ impl DnsRecord {
                     buffer.write_u16(octet)?;
                 }
             }
-            DnsRecord::UNKNOWN { .. } => {
-                println!("Skpping record: {:?}", self);
+            DnsRecord::SOA {
+                domain,
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::SOA.to_num())?;
+                buffer.write_u16(1)?; // class
+                buffer.write_u32(*ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?; // len
+
+                buffer.write_qname(mname)?;
+                buffer.write_qname(rname)?;
+                buffer.write_u32(*serial)?;
+                buffer.write_u32(*refresh)?;
+                buffer.write_u32(*retry)?;
+                buffer.write_u32(*expire)?;
+                buffer.write_u32(*minimum)?;
+
+                // fill back the len
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::OPT {
+                packet_len,
+                flags,
+                data,
+            } => {
+                buffer.write_qname("")?;
+                buffer.write_u16(QueryType::OPT.to_num())?;
+                buffer.write_u16(*packet_len)?;
+                buffer.write_u32(*flags)?;
+                buffer.write_u16(data.len() as u16)?;
+                buffer.write_bytes(data)?;
+            }
+            DnsRecord::DS {
+                domain,
+                key_tag,
+                algorithm,
+                digest_type,
+                digest,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::DS.to_num())?;
+                buffer.write_u16(1)?; // class
+                buffer.write_u32(*ttl)?;
+                buffer.write_u16(4 + digest.len() as u16)?; // len
+
+                buffer.write_u16(*key_tag)?;
+                buffer.write_u8(*algorithm)?;
+                buffer.write_u8(*digest_type)?;
+                buffer.write_bytes(digest)?;
+            }
+            DnsRecord::RRSIG {
+                domain,
+                signature,
+                ttl,
+                ..
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::RRSIG.to_num())?;
+                buffer.write_u16(1)?; // class
+                buffer.write_u32(*ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?; // len
+
+                self.write_rrsig_fields(buffer)?;
+                buffer.write_bytes(signature)?;
+
+                // fill back the len
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::NSEC {
+                domain,
+                next_domain,
+                types,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::NSEC.to_num())?;
+                buffer.write_u16(1)?; // class
+                buffer.write_u32(*ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?; // len
+
+                buffer.write_qname(next_domain)?;
+                buffer.write_type_bitmap(types)?;
+
+                // fill back the len
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::DNSKEY {
+                domain,
+                flags,
+                protocol,
+                algorithm,
+                public_key,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::DNSKEY.to_num())?;
+                buffer.write_u16(1)?; // class
+                buffer.write_u32(*ttl)?;
+                buffer.write_u16(4 + public_key.len() as u16)?; // len
+
+                buffer.write_u16(*flags)?;
+                buffer.write_u8(*protocol)?;
+                buffer.write_u8(*algorithm)?;
+                buffer.write_bytes(public_key)?;
+            }
+            DnsRecord::NSEC3 {
+                domain,
+                hash_algorithm,
+                flags,
+                iterations,
+                salt,
+                next_hashed,
+                types,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::NSEC3.to_num())?;
+                buffer.write_u16(1)?; // class
+                buffer.write_u32(*ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?; // len
+
+                buffer.write_u8(*hash_algorithm)?;
+                buffer.write_u8(*flags)?;
+                buffer.write_u16(*iterations)?;
+                buffer.write_u8(salt.len() as u8)?;
+                buffer.write_bytes(salt)?;
+                buffer.write_u8(next_hashed.len() as u8)?;
+                buffer.write_bytes(next_hashed)?;
+                buffer.write_type_bitmap(types)?;
+
+                // fill back the len
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::TSIG {
+                domain,
+                algorithm,
+                time_signed,
+                fudge,
+                mac,
+                original_id,
+                error,
+                other,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::TSIG.to_num())?;
+                buffer.write_u16(CLASS_ANY)?;
+                buffer.write_u32(0)?; // ttl
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?; // len
+
+                buffer.write_qname(algorithm)?;
+                buffer.write_u16((time_signed >> 32) as u16)?;
+                buffer.write_u32(*time_signed as u32)?;
+                buffer.write_u16(*fudge)?;
+                buffer.write_u16(mac.len() as u16)?;
+                buffer.write_bytes(mac)?;
+                buffer.write_u16(*original_id)?;
+                buffer.write_u16(*error)?;
+                buffer.write_u16(other.len() as u16)?;
+                buffer.write_bytes(other)?;
+
+                // fill back the len
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::UNKNOWN {
+                domain,
+                qtype,
+                data,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(*qtype)?;
+                buffer.write_u16(1)?; // class
+                buffer.write_u32(*ttl)?;
+                buffer.write_u16(data.len() as u16)?; // len
+                buffer.write_bytes(data)?;
             }
         }
 
@@ -639,68 +1376,737 @@ impl DnsPacket {
         Ok(())
     }
 
-    pub fn get_random_a(&self) -> Option<Ipv4Addr> {
+    /// The UDP payload size and DNSSEC OK bit of the OPT record, if any.
+    pub fn edns(&self) -> Option<(u16, bool)> {
+        self.resources.iter().find_map(|record| match record {
+            DnsRecord::OPT {
+                packet_len, flags, ..
+            } => Some((*packet_len, flags & DNSSEC_OK != 0)),
+            _ => None,
+        })
+    }
+
+    pub fn add_edns(&mut self, dnssec_ok: bool) {
+        self.resources.push(DnsRecord::OPT {
+            packet_len: EDNS_SIZE as u16,
+            flags: if dnssec_ok { DNSSEC_OK } else { 0 },
+            data: Vec::new(),
+        });
+    }
+
+    /// All IPv4 and IPv6 addresses in the answer section.
+    pub fn get_addrs(&self) -> Vec<IpAddr> {
+        self.answers.iter().filter_map(DnsRecord::addr).collect()
+    }
+
+    pub fn has_cname(&self, qname: &str) -> bool {
         self.answers
             .iter()
-            .filter_map(|record| match record {
-                DnsRecord::A { addr, .. } => Some(*addr),
-                _ => None,
-            })
-            .next()
+            .any(|record| matches!(record, DnsRecord::CNAME { domain, .. } if domain == qname))
     }
 
-    pub fn get_ns<'a>(&'a self, qname: &'a str) -> impl Iterator<Item = (&str, &str)> {
+    pub fn get_ns<'a>(&'a self, qname: &'a str) -> impl Iterator<Item = (&'a str, &'a str)> {
         self.authorities
             .iter()
             .filter_map(|record| match record {
                 DnsRecord::NS { domain, host, .. } => Some((domain.as_str(), host.as_str())),
                 _ => None,
             })
-            .filter(|(domain, _)| qname.ends_with(*domain))
+            .filter(|(domain, _)| in_bailiwick(qname, domain))
     }
 
-    pub fn get_resolved_ns(&self, qname: &str) -> Option<Ipv4Addr> {
+    /// The glue addresses, IPv4 and IPv6, of the nameservers for `qname`.
+    pub fn get_resolved_ns(&self, qname: &str) -> Vec<IpAddr> {
         self.get_ns(qname)
             .flat_map(|(_, host)| {
                 self.resources
                     .iter()
-                    .filter_map(move |record| match record {
-                        DnsRecord::A { domain, addr, .. } if host == domain => Some(addr),
-                        _ => None,
-                    })
+                    .filter(move |record| record.domain() == host)
+                    .filter_map(DnsRecord::addr)
             })
-            .map(|addr| *addr)
-            .next()
+            .collect()
     }
 
-    pub fn get_unresolved_ns<'a>(&'a self, qname: &'a str) -> Option<&str> {
-        self.get_ns(qname).map(|(_, host)| host).next()
+    /// Remove every record that a server authoritative for `zone` has no
+    /// business telling us about when asked for `qname`, so nothing outside
+    /// its bailiwick is ever used.
+    pub fn scrub(&mut self, qname: &str, zone: &str) {
+        // answers must be the qname itself or follow its CNAME chain, and
+        // the chain is only trusted while it stays inside the zone
+        let mut chain = vec![qname.to_owned()];
+        let mut i = 0;
+        while i < chain.len() && chain.len() <= self.answers.len() {
+            let target = self.answers.iter().find_map(|record| match record {
+                DnsRecord::CNAME { domain, host, .. } if domain.eq_ignore_ascii_case(&chain[i]) => {
+                    Some(host)
+                }
+                _ => None,
+            });
+            if let Some(host) = target {
+                if in_bailiwick(host, zone)
+                    && !chain.iter().any(|name| name.eq_ignore_ascii_case(host))
+                {
+                    chain.push(host.clone());
+                }
+            }
+            i += 1;
+        }
+        self.answers.retain(|record| {
+            let domain = record.domain();
+            in_bailiwick(domain, zone) && chain.iter().any(|name| name.eq_ignore_ascii_case(domain))
+        });
+
+        // authority records must sit between the zone and a name of the
+        // chain, apart from the denial of existence records which can be
+        // anywhere in it
+        self.authorities.retain(|record| {
+            let domain = record.domain();
+            let anywhere = matches!(
+                record.qtype(),
+                QueryType::NSEC | QueryType::NSEC3 | QueryType::RRSIG
+            );
+            in_bailiwick(domain, zone)
+                && (anywhere || chain.iter().any(|name| in_bailiwick(name, domain)))
+        });
+
+        // glue is only accepted for names inside the zone
+        self.resources
+            .retain(|record| in_bailiwick(record.domain(), zone));
     }
 }
 
-impl Display for DnsPacket {
+/// Length of `name` in uncompressed wire format.
+pub fn name_len(name: &str) -> usize {
+    name.split('.')
+        .filter(|label| !label.is_empty())
+        .map(|label| label_bytes(label).len() + 1)
+        .sum::<usize>()
+        + 1
+}
+
+/// A label of a name as the name holds it, bytes that would be mistaken
+/// for something else or aren't printable escaped as `\DDD`, so that a dot
+/// always separates labels.
+pub fn escape_label(label: &[u8]) -> String {
+    let mut escaped = String::new();
+    for byte in label {
+        match byte {
+            b'!'..=b'~' if *byte != b'.' && *byte != b'\\' => escaped.push(*byte as char),
+            _ => escaped += &format!("\\{:03}", byte),
+        }
+    }
+    escaped
+}
+
+/// The bytes of a label as a name holds it, see `escape_label`.
+pub fn label_bytes(label: &str) -> Vec<u8> {
+    if !label.contains('\\') {
+        return label.as_bytes().to_vec();
+    }
+
+    let mut bytes = Vec::new();
+    let mut rest = label.as_bytes();
+    while let Some((byte, tail)) = rest.split_first() {
+        match (byte, tail) {
+            (b'\\', [a, b, c, tail @ ..]) if [a, b, c].iter().all(|d| d.is_ascii_digit()) => {
+                let value = [a, b, c]
+                    .iter()
+                    .fold(0u32, |value, digit| value * 10 + (**digit - b'0') as u32);
+                bytes.push(value as u8);
+                rest = tail;
+            }
+            (b'\\', [escaped, tail @ ..]) => {
+                bytes.push(*escaped);
+                rest = tail;
+            }
+            _ => {
+                bytes.push(*byte);
+                rest = tail;
+            }
+        }
+    }
+    bytes
+}
+
+/// Whether `name` is `zone` itself or lies below it. Names are compared
+/// label by label, so `evilexample.com` is not within `example.com`.
+pub fn in_bailiwick(name: &str, zone: &str) -> bool {
+    if zone.is_empty() {
+        return true;
+    }
+    if name.len() < zone.len() {
+        return false;
+    }
+
+    let (prefix, suffix) = name.as_bytes().split_at(name.len() - zone.len());
+    suffix.eq_ignore_ascii_case(zone.as_bytes()) && (prefix.is_empty() || prefix.ends_with(b"."))
+}
+
+pub fn label_count(name: &str) -> usize {
+    if name.is_empty() {
+        0
+    } else {
+        name.split('.').count()
+    }
+}
+
+/// The ancestor of `name` made of its last `labels` labels, or `name` itself
+/// if it isn't that long.
+pub fn ancestor(name: &str, labels: usize) -> &str {
+    let total = label_count(name);
+    if labels >= total {
+        return name;
+    }
+
+    name.splitn(total - labels + 1, '.').last().unwrap_or(name)
+}
+
+impl Display for QueryType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        writeln!(f, "{:#?}", self.header)?;
+        match self {
+            QueryType::UNKNOWN(num) => write!(f, "TYPE{}", num),
+            qtype => write!(f, "{:?}", qtype),
+        }
+    }
+}
 
-        writeln!(f, "### Question Section({}):", self.questions.len())?;
-        for q in &self.questions {
-            writeln!(f, "{:#?}", q)?;
+/// `name` in presentation format: absolute, with special characters
+/// escaped (RFC 1035 section 5.1).
+pub fn display_name(name: &str) -> String {
+    if name.is_empty() {
+        return ".".to_owned();
+    }
+
+    let mut display = String::new();
+    for label in name.split('.') {
+        for byte in label_bytes(label) {
+            match byte {
+                b'.' | b'"' | b'(' | b')' | b';' | b'\\' | b'@' | b'$' => {
+                    display.push('\\');
+                    display.push(byte as char);
+                }
+                b'!'..=b'~' => display.push(byte as char),
+                _ => display += &format!("\\{:03}", byte),
+            }
         }
+        display.push('.');
+    }
+    display
+}
 
-        writeln!(f, "### Answer Section({}):", self.answers.len())?;
-        for rec in &self.answers {
-            writeln!(f, "{:#?}", rec)?;
+/// `string` as a quoted character-string (RFC 1035 section 5.1).
+fn display_string(string: &[u8]) -> String {
+    let mut display = String::from("\"");
+    for &byte in string {
+        match byte {
+            b'"' | b'\\' => {
+                display.push('\\');
+                display.push(byte as char);
+            }
+            b' '..=b'~' => display.push(byte as char),
+            _ => display += &format!("\\{:03}", byte),
         }
+    }
+    display.push('"');
+    display
+}
 
-        writeln!(f, "### Authority Section({}):", self.authorities.len())?;
-        for rec in &self.authorities {
-            writeln!(f, "{:#?}", rec)?;
+impl Display for DnsRecord {
+    /// The record as a line of a zone file, `name TTL class type data`,
+    /// the fields separated by tabs as dig has them with `{:#}`.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let types = |types: &[QueryType]| {
+            types
+                .iter()
+                .map(|qtype| format!(" {}", qtype))
+                .collect::<String>()
+        };
+
+        let rdata = match self {
+            DnsRecord::UNKNOWN { data, .. } => format!("\\# {} {}", data.len(), to_hex(data)),
+            DnsRecord::A { addr, .. } => addr.to_string(),
+            DnsRecord::AAAA { addr, .. } => addr.to_string(),
+            DnsRecord::NS { host, .. } | DnsRecord::CNAME { host, .. } => display_name(host),
+            DnsRecord::MX { priority, host, .. } => format!("{} {}", priority, display_name(host)),
+            DnsRecord::TXT { data, .. } => data
+                .iter()
+                .map(|string| display_string(string))
+                .collect::<Vec<_>>()
+                .join(" "),
+            DnsRecord::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ..
+            } => format!(
+                "{} {} {} {} {} {} {}",
+                display_name(mname),
+                display_name(rname),
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum
+            ),
+            DnsRecord::OPT {
+                packet_len,
+                flags,
+                data,
+            } => {
+                // a pseudo record, the class and TTL fields carrying the
+                // payload size and flags (RFC 6891)
+                let sep = if f.alternate() { "\t" } else { " " };
+                return write!(
+                    f,
+                    ".{sep}{}{sep}CLASS{}{sep}OPT{sep}\\# {} {}",
+                    flags,
+                    packet_len,
+                    data.len(),
+                    to_hex(data)
+                );
+            }
+            DnsRecord::DS {
+                key_tag,
+                algorithm,
+                digest_type,
+                digest,
+                ..
+            } => format!(
+                "{} {} {} {}",
+                key_tag,
+                algorithm,
+                digest_type,
+                to_hex(digest).to_uppercase()
+            ),
+            DnsRecord::RRSIG {
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                expiration,
+                inception,
+                key_tag,
+                signer_name,
+                signature,
+                ..
+            } => format!(
+                "{} {} {} {} {} {} {} {} {}",
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                display_time(*expiration),
+                display_time(*inception),
+                key_tag,
+                display_name(signer_name),
+                base64_encode(signature)
+            ),
+            DnsRecord::NSEC {
+                next_domain,
+                types: type_list,
+                ..
+            } => format!("{}{}", display_name(next_domain), types(type_list)),
+            DnsRecord::DNSKEY {
+                flags,
+                protocol,
+                algorithm,
+                public_key,
+                ..
+            } => format!(
+                "{} {} {} {}",
+                flags,
+                protocol,
+                algorithm,
+                base64_encode(public_key)
+            ),
+            DnsRecord::NSEC3 {
+                hash_algorithm,
+                flags,
+                iterations,
+                salt,
+                next_hashed,
+                types: type_list,
+                ..
+            } => format!(
+                "{} {} {} {} {}{}",
+                hash_algorithm,
+                flags,
+                iterations,
+                if salt.is_empty() {
+                    "-".to_owned()
+                } else {
+                    to_hex(salt).to_uppercase()
+                },
+                base32hex_encode(next_hashed),
+                types(type_list)
+            ),
+            DnsRecord::TSIG {
+                domain,
+                algorithm,
+                time_signed,
+                fudge,
+                mac,
+                original_id,
+                error,
+                other,
+            } => {
+                // a pseudo record, always of class ANY (RFC 8945 section 4.2)
+                let sep = if f.alternate() { "\t" } else { " " };
+                return write!(
+                    f,
+                    "{}{sep}0{sep}ANY{sep}TSIG{sep}{} {} {} {} {} {} {} {} {}",
+                    display_name(domain),
+                    display_name(algorithm),
+                    time_signed,
+                    fudge,
+                    mac.len(),
+                    base64_encode(mac),
+                    original_id,
+                    error,
+                    other.len(),
+                    base64_encode(other)
+                );
+            }
+        };
+
+        let sep = if f.alternate() { "\t" } else { " " };
+        write!(
+            f,
+            "{}{sep}{}{sep}IN{sep}{}{sep}{}",
+            display_name(self.domain()),
+            self.ttl(),
+            self.qtype(),
+            rdata
+        )
+    }
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, byte)| {
+            bits | (*byte as u32) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
         }
+    }
+    encoded
+}
+
+fn base32hex_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHIJKLMNOPQRSTUV";
+
+    let mut encoded = String::new();
+    let mut bits = 0u32;
+    let mut bit_count = 0;
+    for byte in data {
+        bits = (bits << 8) | *byte as u32;
+        bit_count += 8;
+        while bit_count >= 5 {
+            bit_count -= 5;
+            encoded.push(ALPHABET[(bits >> bit_count & 0x1f) as usize] as char);
+        }
+        bits &= (1 << bit_count) - 1;
+    }
+    if bit_count > 0 {
+        encoded.push(ALPHABET[(bits << (5 - bit_count) & 0x1f) as usize] as char);
+    }
+    encoded
+}
+
+/// An RRSIG time as `YYYYMMDDHHmmSS` in UTC (RFC 4034 section 3.2).
+fn display_time(time: u32) -> String {
+    let days = (time / 86400) as i64;
+    let seconds = time % 86400;
+
+    // the civil date of days since the epoch, proleptic Gregorian
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+    format!(
+        "{:04}{:02}{:02}{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+impl Display for DnsPacket {
+    /// The packet the way dig shows it, so the two can be compared.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let header = &self.header;
+        let opcode = match header.opcode {
+            0 => "QUERY".to_owned(),
+            1 => "IQUERY".to_owned(),
+            2 => "STATUS".to_owned(),
+            4 => "NOTIFY".to_owned(),
+            5 => "UPDATE".to_owned(),
+            opcode => opcode.to_string(),
+        };
+        writeln!(
+            f,
+            ";; ->>HEADER<<- opcode: {}, status: {:?}, id: {}",
+            opcode, header.rescode, header.id
+        )?;
+
+        let flags = [
+            (header.response, "qr"),
+            (header.authoritative_answer, "aa"),
+            (header.truncated_message, "tc"),
+            (header.recursion_desired, "rd"),
+            (header.recursion_available, "ra"),
+            (header.authed_data, "ad"),
+            (header.checking_disabled, "cd"),
+        ];
+        let flags = flags
+            .iter()
+            .filter(|(set, _)| *set)
+            .map(|(_, flag)| format!(" {}", flag))
+            .collect::<String>();
+        writeln!(
+            f,
+            ";; flags:{}; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+            flags,
+            self.questions.len(),
+            self.answers.len(),
+            self.authorities.len(),
+            self.resources.len()
+        )?;
+        writeln!(f)?;
 
-        writeln!(f, "### Additional Section({}):", self.resources.len())?;
         for rec in &self.resources {
-            writeln!(f, "{:#?}", rec)?;
+            if let DnsRecord::OPT {
+                packet_len, flags, ..
+            } = rec
+            {
+                let dnssec_ok = if flags & DNSSEC_OK != 0 { " do" } else { "" };
+                writeln!(f, ";; OPT PSEUDOSECTION:")?;
+                writeln!(
+                    f,
+                    "; EDNS: version: {}, flags:{}; udp: {}",
+                    (flags >> 16) & 0xFF,
+                    dnssec_ok,
+                    packet_len
+                )?;
+            }
         }
+
+        if !self.questions.is_empty() {
+            writeln!(f, ";; QUESTION SECTION:")?;
+            for q in &self.questions {
+                writeln!(f, ";{}\t\tIN\t{}", display_name(&q.name), q.qtype)?;
+            }
+        }
+
+        let sections = [
+            ("ANSWER", &self.answers),
+            ("AUTHORITY", &self.authorities),
+            ("ADDITIONAL", &self.resources),
+        ];
+        for (section, records) in sections {
+            let mut records = records
+                .iter()
+                .filter(|rec| rec.qtype() != QueryType::OPT)
+                .peekable();
+            if records.peek().is_none() {
+                continue;
+            }
+
+            writeln!(f)?;
+            writeln!(f, ";; {} SECTION:", section)?;
+            for rec in records {
+                writeln!(f, "{:#}", rec)?;
+            }
+        }
+
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn a(domain: &str) -> DnsRecord {
+        DnsRecord::A {
+            domain: domain.to_owned(),
+            addr: Ipv4Addr::new(192, 0, 2, 1),
+            ttl: 300,
+        }
+    }
+
+    fn ns(domain: &str, host: &str) -> DnsRecord {
+        DnsRecord::NS {
+            domain: domain.to_owned(),
+            host: host.to_owned(),
+            ttl: 300,
+        }
+    }
+
+    fn cname(domain: &str, host: &str) -> DnsRecord {
+        DnsRecord::CNAME {
+            domain: domain.to_owned(),
+            host: host.to_owned(),
+            ttl: 300,
+        }
+    }
+
+    #[test]
+    fn bailiwick() {
+        assert!(in_bailiwick("example.com", "example.com"));
+        assert!(in_bailiwick("www.example.com", "example.com"));
+        assert!(in_bailiwick("WWW.Example.COM", "example.com"));
+        assert!(in_bailiwick("example.com", ""));
+        assert!(!in_bailiwick("evilexample.com", "example.com"));
+        assert!(!in_bailiwick("www.evilexample.com", "example.com"));
+        assert!(!in_bailiwick("com", "example.com"));
+    }
+
+    #[test]
+    fn scrub_out_of_zone_records() {
+        let mut packet = DnsPacket::new();
+        packet.answers = vec![a("www.example.com"), a("www.evilexample.com")];
+        packet.authorities = vec![
+            ns("example.com", "ns.example.com"),
+            ns("evilexample.com", "ns.evilexample.com"),
+            // not between the zone and the qname
+            ns("other.example.com", "ns.other.example.com"),
+        ];
+        packet.resources = vec![a("ns.example.com"), a("ns.evilexample.com")];
+        packet.scrub("www.example.com", "example.com");
+
+        assert_eq!(packet.answers, [a("www.example.com")]);
+        assert_eq!(packet.authorities, [ns("example.com", "ns.example.com")]);
+        assert_eq!(packet.resources, [a("ns.example.com")]);
+    }
+
+    #[test]
+    fn scrub_cname_chain() {
+        let mut packet = DnsPacket::new();
+        packet.answers = vec![
+            cname("www.example.com", "Web.Example.com"),
+            cname("web.example.com", "cdn.example.com"),
+            a("CDN.example.com"),
+            // off the chain
+            a("mail.example.com"),
+            // the chain leaves the zone, so it isn't followed
+            cname("cdn.example.com", "cdn.example.net"),
+            a("cdn.example.net"),
+        ];
+        packet.scrub("WWW.example.com", "example.com");
+
+        assert_eq!(
+            packet.answers,
+            [
+                cname("www.example.com", "Web.Example.com"),
+                cname("web.example.com", "cdn.example.com"),
+                a("CDN.example.com"),
+                cname("cdn.example.com", "cdn.example.net"),
+            ]
+        );
+    }
+
+    #[test]
+    fn scrub_forwarded_answer() {
+        // a forwarder answers for any zone, the chain included
+        let mut packet = DnsPacket::new();
+        packet.header.rescode = ResultCode::NXDOMAIN;
+        packet.answers = vec![
+            cname("www.example.com", "www.example.net"),
+            a("www.example.org"),
+        ];
+        packet.authorities = vec![
+            DnsRecord::SOA {
+                domain: "example.net".to_owned(),
+                mname: "ns.example.net".to_owned(),
+                rname: "hostmaster.example.net".to_owned(),
+                serial: 1,
+                refresh: 3600,
+                retry: 600,
+                expire: 86400,
+                minimum: 300,
+                ttl: 300,
+            },
+            ns("example.org", "ns.example.org"),
+        ];
+        packet.scrub("www.example.com", "");
+
+        assert_eq!(
+            packet.answers,
+            [cname("www.example.com", "www.example.net")]
+        );
+        assert_eq!(packet.authorities.len(), 1);
+        assert_eq!(packet.authorities[0].domain(), "example.net");
+    }
+
+    #[test]
+    fn displays_like_dig() {
+        let mut packet = DnsPacket::new();
+        packet.header.id = 4321;
+        packet.header.response = true;
+        packet.header.recursion_desired = true;
+        packet.header.recursion_available = true;
+        packet.header.authed_data = true;
+        packet
+            .questions
+            .push(DnsQuestion::new("www.example.com", QueryType::A));
+        packet.answers.push(a("www.example.com"));
+        packet.authorities.push(ns("example.com", "ns.example.com"));
+        packet.add_edns(true);
+
+        assert_eq!(
+            packet.to_string(),
+            ";; ->>HEADER<<- opcode: QUERY, status: NOERROR, id: 4321\n\
+             ;; flags: qr rd ra ad; QUERY: 1, ANSWER: 1, AUTHORITY: 1, ADDITIONAL: 1\n\
+             \n\
+             ;; OPT PSEUDOSECTION:\n\
+             ; EDNS: version: 0, flags: do; udp: 4096\n\
+             ;; QUESTION SECTION:\n\
+             ;www.example.com.\t\tIN\tA\n\
+             \n\
+             ;; ANSWER SECTION:\n\
+             www.example.com.\t300\tIN\tA\t192.0.2.1\n\
+             \n\
+             ;; AUTHORITY SECTION:\n\
+             example.com.\t300\tIN\tNS\tns.example.com.\n"
+        );
+
+        // empty sections are left out
+        let mut packet = DnsPacket::new();
+        packet.header.id = 1;
+        packet.header.opcode = OPCODE_NOTIFY;
+        packet.header.rescode = ResultCode::REFUSED;
+        assert_eq!(
+            packet.to_string(),
+            ";; ->>HEADER<<- opcode: NOTIFY, status: REFUSED, id: 1\n\
+             ;; flags:; QUERY: 0, ANSWER: 0, AUTHORITY: 0, ADDITIONAL: 0\n\
+             \n"
+        );
+    }
+}
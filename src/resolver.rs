@@ -0,0 +1,727 @@
+use crate::config::{AddressFamily, Config, QnameMinimisation};
+use crate::inflight::Inflight;
+use crate::packet::*;
+use crate::tsig::{Key, Session};
+use rand::Rng;
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{net::UdpSocket, sync::mpsc, time};
+
+/// c.root-servers.net
+const ROOT_SERVERS: [IpAddr; 2] = [
+    IpAddr::V4(Ipv4Addr::new(192, 33, 4, 12)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0x2, 0, 0, 0, 0, 0xc)),
+];
+
+/// How long to wait for an upstream server to answer.
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(3);
+/// Head start a server gets before we also try the next one, as in Happy Eyeballs.
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+/// Addresses of a zone's nameservers tried for a single query.
+const MAX_SERVER_ATTEMPTS: usize = 4;
+/// Round trip time assumed for servers we haven't heard from yet, low
+/// enough that they get tried before known slow ones.
+const UNKNOWN_RTT: u32 = 300;
+
+/// A question asked of a server, and its answer once it comes.
+type UpstreamQuery = (SocketAddr, String, QueryType);
+type UpstreamAnswer = std::result::Result<DnsPacket, String>;
+
+/// Queries currently waiting on an upstream server, shared by every worker.
+static UPSTREAM_QUERIES: LazyLock<Inflight<UpstreamQuery, UpstreamAnswer>> =
+    LazyLock::new(Inflight::new);
+
+/// Referrals followed while walking down from the root for a single name.
+const MAX_REFERRAL_DEPTH: usize = 16;
+/// Nameserver names without glue tried for a single referral.
+const MAX_NS_FANOUT: usize = 3;
+/// How deeply nameserver name resolutions may nest inside each other.
+const MAX_SUBQUERY_DEPTH: usize = 4;
+/// Upstream queries one client request may cause in total.
+const MAX_UPSTREAM_QUERIES: usize = 48;
+/// Minimised queries sent for one name before giving away the rest of it.
+const MAX_MINIMISE_STEPS: usize = 10;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResolveError {
+    ReferralDepthExceeded(String),
+    NsFanoutExceeded(String),
+    SubQueryDepthExceeded(String),
+    UpstreamQueriesExceeded(String),
+    DelegationLoop(String),
+    MinimisationFailed(String, ResultCode),
+}
+
+impl ResolveError {
+    /// Whether the error means the whole client request is out of budget,
+    /// as opposed to just this one path through the delegation graph.
+    fn is_fatal(&self) -> bool {
+        !matches!(
+            self,
+            ResolveError::DelegationLoop(_) | ResolveError::MinimisationFailed(..)
+        )
+    }
+}
+
+impl Display for ResolveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResolveError::ReferralDepthExceeded(name) => {
+                write!(
+                    f,
+                    "Limit of {} referrals exceeded for {}",
+                    MAX_REFERRAL_DEPTH, name
+                )
+            }
+            ResolveError::NsFanoutExceeded(name) => write!(
+                f,
+                "None of the first {} nameservers of {} could be resolved",
+                MAX_NS_FANOUT, name
+            ),
+            ResolveError::SubQueryDepthExceeded(name) => write!(
+                f,
+                "Limit of {} nested nameserver lookups exceeded for {}",
+                MAX_SUBQUERY_DEPTH, name
+            ),
+            ResolveError::UpstreamQueriesExceeded(name) => write!(
+                f,
+                "Limit of {} upstream queries exceeded for {}",
+                MAX_UPSTREAM_QUERIES, name
+            ),
+            ResolveError::DelegationLoop(name) => {
+                write!(f, "Cyclic delegation while resolving {}", name)
+            }
+            ResolveError::MinimisationFailed(name, rescode) => {
+                write!(f, "Minimised query for {} failed with {:?}", name, rescode)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ResolveError {}
+
+/// Work done so far on behalf of one client request.
+struct Budget {
+    queries: usize,
+    /// Names currently being resolved, outermost first.
+    stack: Vec<(String, QueryType)>,
+}
+
+impl Budget {
+    fn new() -> Budget {
+        Budget {
+            queries: 0,
+            stack: Vec::new(),
+        }
+    }
+}
+
+/// How much of a qname the servers of a zone are shown, one more label at
+/// a time (RFC 9156).
+struct Minimiser {
+    mode: QnameMinimisation,
+    /// Labels of the qname below the zone about to be revealed.
+    revealed: usize,
+    steps: usize,
+    /// Whether the qname is still minimised, or given away in full.
+    enabled: bool,
+}
+
+/// What a minimised query that didn't refer us on leads to.
+#[derive(Debug, PartialEq, Eq)]
+enum MinimiseStep {
+    /// Query again, with as much of the qname as is now revealed.
+    Retry,
+    /// The response holds for the qname too.
+    Answer,
+    /// The server misbehaved, which fails the lookup.
+    Fail,
+}
+
+impl Minimiser {
+    fn new(mode: QnameMinimisation) -> Minimiser {
+        Minimiser {
+            mode,
+            revealed: 1,
+            steps: 0,
+            enabled: mode != QnameMinimisation::Off,
+        }
+    }
+
+    fn strict(&self) -> bool {
+        self.mode == QnameMinimisation::Strict
+    }
+
+    /// The name and type to ask the servers of `zone` about the qname.
+    fn query<'a>(&self, qname: &'a str, qtype: QueryType, zone: &str) -> (&'a str, QueryType) {
+        match ancestor(qname, label_count(zone) + self.revealed) {
+            name if self.enabled && name != qname => (name, QueryType::A),
+            _ => (qname, qtype),
+        }
+    }
+
+    /// Move on after a minimised query answered `rescode`, with a CNAME
+    /// for the name asked if `cname`.
+    fn answered(&mut self, rescode: ResultCode, cname: bool) -> MinimiseStep {
+        match rescode {
+            // nothing exists below a name that doesn't exist (RFC 8020),
+            // but some servers wrongly say so for empty non-terminals
+            ResultCode::NXDOMAIN if self.strict() => MinimiseStep::Answer,
+            // the name exists inside this zone, so reveal one more label
+            ResultCode::NOERROR if !cname => {
+                self.revealed += 1;
+                self.steps += 1;
+                self.enabled = self.steps < MAX_MINIMISE_STEPS;
+                MinimiseStep::Retry
+            }
+            _ if self.strict() => MinimiseStep::Fail,
+            _ => {
+                self.enabled = false;
+                MinimiseStep::Retry
+            }
+        }
+    }
+
+    /// Give the qname away in full after a minimised query failed, unless
+    /// strict. Returns whether to query again.
+    fn failed(&mut self) -> bool {
+        if self.strict() {
+            return false;
+        }
+        self.enabled = false;
+        true
+    }
+
+    /// Start over with the servers of a zone we were referred to.
+    fn referred(&mut self) {
+        self.revealed = 1;
+    }
+}
+
+/// Smoothed round trip times of nameservers in milliseconds, so that the
+/// fastest servers of a zone are tried first.
+pub struct RttTable {
+    rtts: Mutex<HashMap<IpAddr, u32>>,
+}
+
+impl RttTable {
+    pub fn new() -> RttTable {
+        RttTable {
+            rtts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn rtt(&self, server: &IpAddr) -> u32 {
+        self.rtts
+            .lock()
+            .ok()
+            .and_then(|rtts| rtts.get(server).copied())
+            .unwrap_or(UNKNOWN_RTT)
+    }
+
+    fn answered(&self, server: IpAddr, elapsed: Duration) {
+        let sample = elapsed.as_millis().min(u32::MAX as u128) as u32;
+        if let Ok(mut rtts) = self.rtts.lock() {
+            let rtt = rtts.entry(server).or_insert(sample);
+            *rtt = (*rtt * 7 + sample * 3) / 10;
+        }
+    }
+
+    fn failed(&self, server: IpAddr) {
+        let limit = UPSTREAM_TIMEOUT.as_millis() as u32;
+        if let Ok(mut rtts) = self.rtts.lock() {
+            let rtt = rtts.entry(server).or_insert(UNKNOWN_RTT);
+            *rtt = (*rtt * 2).min(limit);
+        }
+    }
+
+    pub fn entries(&self) -> Vec<(IpAddr, u32)> {
+        self.rtts
+            .lock()
+            .map(|rtts| rtts.iter().map(|(addr, rtt)| (*addr, *rtt)).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn restore(&self, entries: Vec<(IpAddr, u32)>) {
+        if let Ok(mut rtts) = self.rtts.lock() {
+            rtts.extend(entries);
+        }
+    }
+}
+
+/// Ask `server` about `qname`, sharing the answer with anyone asking it
+/// the same question at the same time rather than asking it again.
+///
+/// With a `key` the query is signed, and so must the answer be.
+pub async fn lookup(
+    qname: &str,
+    qtype: QueryType,
+    server: (IpAddr, u16),
+    key: Option<&Key>,
+) -> Result<DnsPacket> {
+    let call = (SocketAddr::from(server), qname.to_owned(), qtype);
+    let query = async {
+        match time::timeout(UPSTREAM_TIMEOUT, query_server(qname, qtype, server, key)).await {
+            Ok(result) => result.map_err(|err| err.to_string()),
+            Err(_) => Err(format!("No answer from {}", server.0)),
+        }
+    };
+    UPSTREAM_QUERIES.run(call, query).await?.map_err(Into::into)
+}
+
+async fn query_server(
+    qname: &str,
+    qtype: QueryType,
+    server: (IpAddr, u16),
+    key: Option<&Key>,
+) -> Result<DnsPacket> {
+    let socket = match server.0 {
+        IpAddr::V4(_) => UdpSocket::bind(("0.0.0.0", 0)).await?,
+        IpAddr::V6(_) => UdpSocket::bind(("::", 0)).await?,
+    };
+
+    let mut req_packet = DnsPacket::new();
+
+    req_packet.header.id = rand::thread_rng().gen();
+    req_packet.header.questions = 1;
+    req_packet.header.recursion_desired = true;
+    req_packet.questions.push(DnsQuestion::new(qname, qtype));
+    req_packet.add_edns(true);
+
+    let mut req_buffer = BytePacketBuffer::new();
+    req_packet.write(&mut req_buffer)?;
+    let mut request = req_buffer.buf[..req_buffer.pos].to_vec();
+    let mut session = key.cloned().map(Session::new);
+    if let Some(session) = &mut session {
+        session.sign(&mut request)?;
+    }
+
+    socket.send_to(&request, server).await?;
+
+    let mut res_buffer = BytePacketBuffer::with_size(EDNS_SIZE);
+    let (len, src) = socket.recv_from(&mut res_buffer.buf).await?;
+    res_buffer.buf.truncate(len);
+
+    let res_packet = DnsPacket::from_buffer(&mut res_buffer)?;
+
+    // a reply that doesn't match what we asked is most likely spoofed
+    if src != SocketAddr::from(server)
+        || res_packet.header.id != req_packet.header.id
+        || res_packet.questions != req_packet.questions
+    {
+        return Err(format!("Mismatched response from {}", src).into());
+    }
+    if let Some(session) = &mut session {
+        session
+            .verify(&res_buffer.buf)
+            .map_err(|err| format!("{} from {}", err, src))?;
+    }
+
+    Ok(res_packet)
+}
+
+/// Query whichever of `servers` answers first. Servers are tried fastest
+/// first in the order of the configured address families, alternating
+/// between them, and each gets a short head start before the next one is
+/// tried too.
+async fn lookup_servers(
+    qname: &str,
+    qtype: QueryType,
+    servers: &[IpAddr],
+    config: &Config,
+    rtts: &RttTable,
+) -> Result<DnsPacket> {
+    let servers = order_servers(servers, &config.address_families, rtts);
+    if servers.is_empty() {
+        return Err(format!("No usable nameserver address for {}", qname).into());
+    }
+
+    // attempts run as tasks of their own, so that those still waiting when
+    // another server answers finish and share their answer with whoever
+    // else asked the same question
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut attempts = servers.into_iter().take(MAX_SERVER_ATTEMPTS).peekable();
+    let mut pending = 0;
+    let mut last_err = String::new();
+    loop {
+        if let Some(server) = attempts.next() {
+            let tx = tx.clone();
+            let qname = qname.to_owned();
+            let key = config.server_key(server).cloned();
+            tokio::spawn(async move {
+                let start = Instant::now();
+                let result = lookup(&qname, qtype, (server, 53), key.as_ref())
+                    .await
+                    .map(|response| (response, start.elapsed()))
+                    .map_err(|err| err.to_string());
+                let _ = tx.send((server, result));
+            });
+            pending += 1;
+        } else if pending == 0 {
+            break;
+        }
+
+        // the next server is tried after the delay, or as soon as this
+        // one fails
+        let more = attempts.peek().is_some();
+        let answer = tokio::select! {
+            answer = rx.recv() => answer,
+            _ = time::sleep(ATTEMPT_DELAY), if more => continue,
+        };
+        let Some((server, result)) = answer else {
+            break;
+        };
+        pending -= 1;
+        // note how long the server took, or that it failed
+        match result {
+            Ok((response, elapsed)) => {
+                rtts.answered(server, elapsed);
+                return Ok(response);
+            }
+            Err(err) => {
+                rtts.failed(server);
+                last_err = err;
+            }
+        }
+    }
+
+    Err(last_err.into())
+}
+
+/// Keep the addresses of enabled families, fastest first within each and
+/// interleaving them so that the preferred family comes first but a broken
+/// one can't starve the other.
+fn order_servers(servers: &[IpAddr], families: &[AddressFamily], rtts: &RttTable) -> Vec<IpAddr> {
+    let mut servers = servers.to_vec();
+    servers.sort_by_key(|server| rtts.rtt(server));
+
+    let by_family = families
+        .iter()
+        .map(|family| {
+            servers
+                .iter()
+                .filter(|addr| AddressFamily::of(addr) == *family)
+                .copied()
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let longest = by_family.iter().map(Vec::len).max().unwrap_or(0);
+
+    (0..longest)
+        .flat_map(|i| {
+            by_family
+                .iter()
+                .filter_map(move |addrs| addrs.get(i).copied())
+        })
+        .collect()
+}
+
+pub async fn recursion_lookup(
+    qname: &str,
+    qtype: QueryType,
+    config: &Config,
+    rtts: &RttTable,
+) -> Result<DnsPacket> {
+    resolve(qname, qtype, config, rtts, &mut Budget::new()).await
+}
+
+async fn resolve(
+    qname: &str,
+    qtype: QueryType,
+    config: &Config,
+    rtts: &RttTable,
+    budget: &mut Budget,
+) -> Result<DnsPacket> {
+    let key = (qname.to_owned(), qtype);
+    if budget.stack.contains(&key) {
+        return Err(ResolveError::DelegationLoop(qname.to_owned()).into());
+    }
+    if budget.stack.len() > MAX_SUBQUERY_DEPTH {
+        return Err(ResolveError::SubQueryDepthExceeded(qname.to_owned()).into());
+    }
+
+    budget.stack.push(key);
+    // boxed, as walking may resolve nameserver names in turn
+    let result = Box::pin(walk(qname, qtype, config, rtts, budget)).await;
+    budget.stack.pop();
+
+    result
+}
+
+async fn walk(
+    qname: &str,
+    qtype: QueryType,
+    config: &Config,
+    rtts: &RttTable,
+    budget: &mut Budget,
+) -> Result<DnsPacket> {
+    let mut ns = ROOT_SERVERS.to_vec();
+    // the zone `ns` is authoritative for, which bounds what we accept from it
+    let mut zone = String::new();
+    let mut referrals = 0;
+
+    let mut minimiser = Minimiser::new(config.qname_minimisation);
+
+    loop {
+        budget.queries += 1;
+        if budget.queries > MAX_UPSTREAM_QUERIES {
+            return Err(ResolveError::UpstreamQueriesExceeded(qname.to_owned()).into());
+        }
+
+        // only show each server as much of the qname as it needs to refer us on
+        let (query_name, query_type) = minimiser.query(qname, qtype, &zone);
+        let minimised = query_name != qname;
+
+        println!(
+            "attempting lookup of {:?} {} with ns {:?} for zone {:?}",
+            query_type, query_name, ns, zone
+        );
+
+        // lookup
+        let mut response = match lookup_servers(query_name, query_type, &ns, config, rtts).await {
+            Ok(response) => response,
+            Err(_) if minimised && minimiser.failed() => continue,
+            Err(err) => return Err(err),
+        };
+        response.scrub(query_name, &zone);
+
+        // a referral has to take us further down towards the qname
+        let new_zone = response
+            .get_ns(query_name)
+            .map(|(domain, _)| domain.to_owned())
+            .find(|domain| domain.len() > zone.len());
+
+        if minimised && new_zone.is_none() {
+            let rescode = response.header.rescode;
+            match minimiser.answered(rescode, response.has_cname(query_name)) {
+                MinimiseStep::Retry => continue,
+                MinimiseStep::Answer => return Ok(response),
+                MinimiseStep::Fail => {
+                    return Err(
+                        ResolveError::MinimisationFailed(query_name.to_owned(), rescode).into(),
+                    )
+                }
+            }
+        }
+
+        if !minimised {
+            // if has answer, then down
+            if !response.answers.is_empty() && response.header.rescode == ResultCode::NOERROR {
+                return Ok(response);
+            }
+            // else if response report has not the qname, then done
+            if response.header.rescode == ResultCode::NXDOMAIN {
+                return Ok(response);
+            }
+        }
+        let Some(new_zone) = new_zone else {
+            return Ok(response);
+        };
+        // DS records live on the parent side of a zone cut (RFC 4035 4.2)
+        if qtype == QueryType::DS && new_zone == qname {
+            return Ok(response);
+        }
+
+        referrals += 1;
+        if referrals > MAX_REFERRAL_DEPTH {
+            return Err(ResolveError::ReferralDepthExceeded(qname.to_owned()).into());
+        }
+
+        // else if has new ns ip, continue lookup
+        let new_ns = usable(response.get_resolved_ns(query_name), config);
+        if !new_ns.is_empty() {
+            ns = new_ns;
+            zone = new_zone;
+            minimiser.referred();
+            continue;
+        }
+
+        // else if has new ns names, recursion lookup the new_ns's ip, trying
+        // only a few of them so a referral can't make us resolve hundreds
+        let new_ns_names = response
+            .get_ns(query_name)
+            .map(|(_, host)| host.to_owned())
+            .collect::<Vec<_>>();
+
+        let mut new_ns = Vec::new();
+        let mut last_err = None;
+        'names: for new_ns_name in new_ns_names.iter().take(MAX_NS_FANOUT) {
+            for family in &config.address_families {
+                let addr_type = match family {
+                    AddressFamily::Ipv4 => QueryType::A,
+                    AddressFamily::Ipv6 => QueryType::AAAA,
+                };
+                match resolve(new_ns_name, addr_type, config, rtts, budget).await {
+                    Ok(recursion_response) => {
+                        new_ns = usable(recursion_response.get_addrs(), config);
+                        if !new_ns.is_empty() {
+                            break 'names;
+                        }
+                    }
+                    Err(err) => match err.downcast_ref::<ResolveError>() {
+                        Some(resolve_err) if resolve_err.is_fatal() => return Err(err),
+                        _ => last_err = Some(err),
+                    },
+                }
+            }
+        }
+
+        // if the new_ns has ip, then continue lookup
+        if !new_ns.is_empty() {
+            ns = new_ns;
+            zone = new_zone;
+            minimiser.referred();
+        } else if new_ns_names.len() > MAX_NS_FANOUT {
+            return Err(ResolveError::NsFanoutExceeded(new_zone).into());
+        } else if let Some(err) = last_err {
+            return Err(err);
+        }
+        // else return response
+        else {
+            return Ok(response);
+        }
+    }
+}
+
+/// Drop addresses of disabled address families.
+fn usable(addrs: Vec<IpAddr>, config: &Config) -> Vec<IpAddr> {
+    addrs
+        .into_iter()
+        .filter(|addr| config.address_families.contains(&AddressFamily::of(addr)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QNAME: &str = "www.a.b.example.com";
+
+    fn query(minimiser: &Minimiser, zone: &str) -> (String, QueryType) {
+        let (name, qtype) = minimiser.query(QNAME, QueryType::MX, zone);
+        (name.to_owned(), qtype)
+    }
+
+    #[test]
+    fn orders_servers() {
+        let v4 = |last: u8| IpAddr::from([192, 0, 2, last]);
+        let v6 = |last: u16| IpAddr::from([0x2001, 0xdb8, 0, 0, 0, 0, 0, last]);
+        let servers = [v4(1), v4(2), v4(3), v6(1), v6(2)];
+        let rtts = RttTable::new();
+        rtts.answered(v4(3), Duration::from_millis(20));
+        rtts.answered(v4(1), Duration::from_millis(100));
+        rtts.answered(v6(1), Duration::from_millis(500));
+        rtts.failed(v4(2));
+
+        // known fast servers before unknown ones, and slow and failed ones
+        // last, alternating families from the preferred one
+        assert_eq!(
+            order_servers(&servers, &[AddressFamily::Ipv6, AddressFamily::Ipv4], &rtts),
+            [v6(2), v4(3), v6(1), v4(1), v4(2)]
+        );
+        assert_eq!(
+            order_servers(&servers, &[AddressFamily::Ipv4, AddressFamily::Ipv6], &rtts),
+            [v4(3), v6(2), v4(1), v6(1), v4(2)]
+        );
+        assert_eq!(
+            order_servers(&servers, &[AddressFamily::Ipv4], &rtts),
+            [v4(3), v4(1), v4(2)]
+        );
+    }
+
+    #[test]
+    fn minimises_label_by_label() {
+        let mut minimiser = Minimiser::new(QnameMinimisation::Relaxed);
+        assert_eq!(query(&minimiser, ""), ("com".to_owned(), QueryType::A));
+
+        minimiser.referred();
+        assert_eq!(
+            query(&minimiser, "com"),
+            ("example.com".to_owned(), QueryType::A)
+        );
+
+        // b.example.com is in example.com itself, rather than delegated
+        minimiser.referred();
+        assert_eq!(
+            query(&minimiser, "example.com"),
+            ("b.example.com".to_owned(), QueryType::A)
+        );
+        assert_eq!(
+            minimiser.answered(ResultCode::NOERROR, false),
+            MinimiseStep::Retry
+        );
+        assert_eq!(
+            query(&minimiser, "example.com"),
+            ("a.b.example.com".to_owned(), QueryType::A)
+        );
+        minimiser.answered(ResultCode::NOERROR, false);
+        assert_eq!(
+            query(&minimiser, "example.com"),
+            (QNAME.to_owned(), QueryType::MX)
+        );
+
+        let minimiser = Minimiser::new(QnameMinimisation::Off);
+        assert_eq!(query(&minimiser, ""), (QNAME.to_owned(), QueryType::MX));
+    }
+
+    #[test]
+    fn relaxed_falls_back_to_qname() {
+        for rescode in [ResultCode::NXDOMAIN, ResultCode::REFUSED] {
+            let mut minimiser = Minimiser::new(QnameMinimisation::Relaxed);
+            assert_eq!(minimiser.answered(rescode, false), MinimiseStep::Retry);
+            assert_eq!(query(&minimiser, ""), (QNAME.to_owned(), QueryType::MX));
+        }
+
+        // a CNAME says nothing about the names below it
+        let mut minimiser = Minimiser::new(QnameMinimisation::Relaxed);
+        assert_eq!(
+            minimiser.answered(ResultCode::NOERROR, true),
+            MinimiseStep::Retry
+        );
+        assert_eq!(query(&minimiser, ""), (QNAME.to_owned(), QueryType::MX));
+
+        let mut minimiser = Minimiser::new(QnameMinimisation::Relaxed);
+        assert!(minimiser.failed());
+        assert_eq!(query(&minimiser, ""), (QNAME.to_owned(), QueryType::MX));
+    }
+
+    #[test]
+    fn strict_keeps_minimising() {
+        let mut minimiser = Minimiser::new(QnameMinimisation::Strict);
+        assert_eq!(
+            minimiser.answered(ResultCode::NXDOMAIN, false),
+            MinimiseStep::Answer
+        );
+        assert_eq!(
+            minimiser.answered(ResultCode::REFUSED, false),
+            MinimiseStep::Fail
+        );
+        assert!(!minimiser.failed());
+        assert_eq!(query(&minimiser, ""), ("com".to_owned(), QueryType::A));
+    }
+
+    #[test]
+    fn minimises_a_few_labels_at_most() {
+        let qname = (0..20)
+            .map(|i| format!("l{}", i))
+            .collect::<Vec<_>>()
+            .join(".");
+        let mut minimiser = Minimiser::new(QnameMinimisation::Relaxed);
+
+        for step in 0..MAX_MINIMISE_STEPS {
+            let (name, qtype) = minimiser.query(&qname, QueryType::MX, "");
+            assert_eq!((label_count(name), qtype), (step + 1, QueryType::A));
+            minimiser.answered(ResultCode::NOERROR, false);
+        }
+        // the rest of the qname is given away at once
+        assert_eq!(
+            minimiser.query(&qname, QueryType::MX, ""),
+            (qname.as_str(), QueryType::MX)
+        );
+    }
+}
@@ -0,0 +1,127 @@
+use crate::config::{Config, RouteAction};
+use crate::forwarder::Forwarder;
+use crate::packet::in_bailiwick;
+
+pub enum Route {
+    Forward(Forwarder),
+    Recurse,
+    Refuse,
+}
+
+/// Decides per domain suffix whether a query is forwarded, recursed or
+/// refused. The most specific matching suffix wins.
+pub struct Router {
+    routes: Vec<(String, Route)>,
+}
+
+impl Router {
+    pub fn new(config: &Config) -> Router {
+        let mut routes = config
+            .routes
+            .iter()
+            .map(|route| {
+                let action = match &route.action {
+                    RouteAction::Forward(addrs) => Route::Forward(Forwarder::new(addrs, config)),
+                    RouteAction::Recurse => Route::Recurse,
+                    RouteAction::Refuse => Route::Refuse,
+                };
+                (route.suffix.clone(), action)
+            })
+            .collect::<Vec<_>>();
+
+        // everything else goes to the global forwarders, or is recursed
+        if !routes.iter().any(|(suffix, _)| suffix.is_empty()) {
+            let action = if config.forwarders.is_empty() {
+                Route::Recurse
+            } else {
+                Route::Forward(Forwarder::new(&config.forwarders, config))
+            };
+            routes.push((String::new(), action));
+        }
+
+        Router { routes }
+    }
+
+    pub fn route(&self, qname: &str) -> &Route {
+        self.routes
+            .iter()
+            .filter(|(suffix, _)| in_bailiwick(qname, suffix))
+            .max_by_key(|(suffix, _)| suffix.len())
+            .map(|(_, route)| route)
+            .unwrap_or(&Route::Recurse)
+    }
+
+    pub fn forwarders(&self) -> impl Iterator<Item = &Forwarder> {
+        self.routes.iter().filter_map(|(_, route)| match route {
+            Route::Forward(forwarder) => Some(forwarder),
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Route as ConfigRoute;
+
+    fn route(suffix: &str, action: RouteAction) -> ConfigRoute {
+        ConfigRoute {
+            suffix: suffix.to_owned(),
+            action,
+        }
+    }
+
+    /// Where `qname` goes: the forwarder's first upstream, "recurse" or
+    /// "refuse".
+    fn target(router: &Router, qname: &str) -> String {
+        match router.route(qname) {
+            Route::Forward(forwarder) => forwarder.upstreams[0].addr.to_string(),
+            Route::Recurse => "recurse".to_owned(),
+            Route::Refuse => "refuse".to_owned(),
+        }
+    }
+
+    #[test]
+    fn longest_suffix_wins() {
+        let mut config = Config::new();
+        config.routes = vec![
+            route("example.com", RouteAction::Refuse),
+            route(
+                "corp.example.com",
+                RouteAction::Forward(vec!["192.0.2.1:53".parse().unwrap()]),
+            ),
+            route("public.corp.example.com", RouteAction::Recurse),
+        ];
+        config.forwarders = vec!["192.0.2.53:53".parse().unwrap()];
+        let router = Router::new(&config);
+
+        assert_eq!(target(&router, "example.com"), "refuse");
+        assert_eq!(target(&router, "www.example.com"), "refuse");
+        assert_eq!(target(&router, "corp.example.com"), "192.0.2.1:53");
+        assert_eq!(target(&router, "a.b.corp.example.com"), "192.0.2.1:53");
+        assert_eq!(target(&router, "www.public.corp.example.com"), "recurse");
+
+        // suffixes match whole labels only
+        assert_eq!(target(&router, "notexample.com"), "192.0.2.53:53");
+        assert_eq!(target(&router, "www.notcorp.example.com"), "refuse");
+        // and anything else goes to the global forwarders
+        assert_eq!(target(&router, "example.org"), "192.0.2.53:53");
+        assert_eq!(target(&router, ""), "192.0.2.53:53");
+    }
+
+    #[test]
+    fn default_route() {
+        // recursion without global forwarders
+        let router = Router::new(&Config::new());
+        assert_eq!(target(&router, "example.com"), "recurse");
+        assert_eq!(router.forwarders().count(), 0);
+
+        // and a route for the root overrides them
+        let mut config = Config::new();
+        config.forwarders = vec!["192.0.2.53:53".parse().unwrap()];
+        config.routes = vec![route("", RouteAction::Refuse)];
+        let router = Router::new(&config);
+        assert_eq!(target(&router, "example.com"), "refuse");
+        assert_eq!(router.forwarders().count(), 0);
+    }
+}
@@ -0,0 +1,232 @@
+use crate::anchors::unix_now;
+use crate::cache::{Cache, SavedEntry};
+use crate::dnssec::Security;
+use crate::packet::*;
+use crate::resolver::RttTable;
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::Duration,
+};
+
+const MAGIC: &[u8; 4] = b"RDNS";
+/// Bumped whenever the layout below changes, older snapshots are ignored.
+const VERSION: u16 = 1;
+
+// A snapshot is, with every integer big endian:
+//
+//   magic "RDNS", version u16, time saved as u64 seconds since the epoch
+//   u32 count of nameservers, each:
+//     u8 address family 4 or 6, the address, u32 RTT in milliseconds
+//   u32 count of answers, each:
+//     u8 security 0 unvalidated, 1 secure, 2 insecure, u32 hits,
+//     i64 seconds left, u16 length, the answer in wire format with the
+//     name and type it answers as its question
+
+/// Write the cache and nameserver RTTs to `path`.
+pub fn save(path: &str, cache: &Cache, rtts: &RttTable) -> Result<()> {
+    let mut data = Vec::new();
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&VERSION.to_be_bytes());
+    data.extend_from_slice(&unix_now().to_be_bytes());
+
+    let rtts = rtts.entries();
+    data.extend_from_slice(&(rtts.len() as u32).to_be_bytes());
+    for (addr, rtt) in rtts {
+        match addr {
+            IpAddr::V4(addr) => {
+                data.push(4);
+                data.extend_from_slice(&addr.octets());
+            }
+            IpAddr::V6(addr) => {
+                data.push(6);
+                data.extend_from_slice(&addr.octets());
+            }
+        }
+        data.extend_from_slice(&rtt.to_be_bytes());
+    }
+
+    let entries = cache.save();
+    data.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+    for entry in entries {
+        let mut packet = entry.packet;
+        packet.questions = vec![DnsQuestion::new(&entry.qname, entry.qtype)];
+        let mut buffer = BytePacketBuffer::with_size(0xFFFF);
+        packet.write(&mut buffer)?;
+
+        data.push(match entry.security {
+            None | Some(Security::Bogus(_)) => 0,
+            Some(Security::Secure) => 1,
+            Some(Security::Insecure) => 2,
+        });
+        data.extend_from_slice(&entry.hits.to_be_bytes());
+        data.extend_from_slice(&entry.remaining.to_be_bytes());
+        data.extend_from_slice(&(buffer.pos as u16).to_be_bytes());
+        data.extend_from_slice(&buffer.buf[..buffer.pos]);
+    }
+
+    let tmp = format!("{}.tmp", path);
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, path)?;
+
+    Ok(())
+}
+
+/// Fill the cache and nameserver RTTs from the snapshot at `path`, if
+/// there is one.
+pub fn load(path: &str, cache: &Cache, rtts: &RttTable) -> Result<()> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    let mut reader = Reader {
+        data: &data,
+        pos: 0,
+    };
+
+    if reader.bytes(MAGIC.len())? != MAGIC {
+        return Err(format!("{} is not a cache snapshot", path).into());
+    }
+    let version = reader.u16()?;
+    if version != VERSION {
+        return Err(format!("Unsupported cache snapshot version {}", version).into());
+    }
+    let age = Duration::from_secs(unix_now().saturating_sub(reader.u64()?));
+
+    let mut servers = Vec::new();
+    for _ in 0..reader.u32()? {
+        let addr = match reader.u8()? {
+            4 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(reader.bytes(4)?)?)),
+            6 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(reader.bytes(16)?)?)),
+            family => return Err(format!("Unknown address family {}", family).into()),
+        };
+        servers.push((addr, reader.u32()?));
+    }
+
+    let mut entries = Vec::new();
+    for _ in 0..reader.u32()? {
+        let security = match reader.u8()? {
+            1 => Some(Security::Secure),
+            2 => Some(Security::Insecure),
+            _ => None,
+        };
+        let hits = reader.u32()?;
+        let remaining = reader.u64()? as i64;
+        let len = reader.u16()? as usize;
+
+        let mut buffer = BytePacketBuffer::new();
+        buffer.buf = reader.bytes(len)?.to_vec();
+        let mut packet = DnsPacket::from_buffer(&mut buffer)?;
+        let Some(question) = packet.questions.pop() else {
+            return Err("Cached answer without a question".into());
+        };
+
+        entries.push(SavedEntry {
+            qname: question.name,
+            qtype: question.qtype,
+            packet,
+            security,
+            remaining,
+            hits,
+        });
+    }
+
+    println!(
+        "Restoring {} cached answers and {} nameserver RTTs saved {}s ago",
+        entries.len(),
+        servers.len(),
+        age.as_secs()
+    );
+    rtts.restore(servers);
+    cache.restore(entries, age);
+
+    Ok(())
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or("Truncated cache snapshot")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.bytes(8)?.try_into()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    #[test]
+    fn round_trip() {
+        let cache = Cache::new(100, 0, Duration::ZERO);
+        let mut packet = DnsPacket::new();
+        packet.answers.push(DnsRecord::A {
+            domain: "www.example.com".to_owned(),
+            addr: "192.0.2.1".parse().unwrap(),
+            ttl: 300,
+        });
+        cache.insert(
+            "www.example.com",
+            QueryType::A,
+            &packet,
+            Some(Security::Secure),
+        );
+        let rtts = RttTable::new();
+        rtts.restore(vec![
+            ("192.0.2.53".parse().unwrap(), 40),
+            ("2001:db8::53".parse().unwrap(), 120),
+        ]);
+
+        let path = std::env::temp_dir().join(format!("rdns-test-{}.snapshot", process::id()));
+        let path = path.to_str().unwrap_or_default();
+        save(path, &cache, &rtts).unwrap();
+        let restored = Cache::new(100, 0, Duration::ZERO);
+        let restored_rtts = RttTable::new();
+        let loaded = load(path, &restored, &restored_rtts);
+        let _ = fs::remove_file(path);
+        loaded.unwrap();
+
+        let (packet, security) = restored.get("www.example.com", QueryType::A, true).unwrap();
+        assert_eq!(security, Some(Security::Secure));
+        assert!(packet.questions.is_empty());
+        // a second may have gone by since it was saved
+        assert!(matches!(
+            packet.answers.as_slice(),
+            [DnsRecord::A { domain, ttl: 299..=300, .. }] if domain == "www.example.com"
+        ));
+        let mut entries = restored_rtts.entries();
+        entries.sort();
+        let mut expected = rtts.entries();
+        expected.sort();
+        assert_eq!(entries, expected);
+
+        // a missing snapshot is an empty cache, anything else an error
+        assert!(load("/nonexistent/rdns.snapshot", &restored, &restored_rtts).is_ok());
+        assert!(load("Cargo.toml", &restored, &restored_rtts).is_err());
+    }
+}
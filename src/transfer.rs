@@ -0,0 +1,326 @@
+use crate::journal::{self, Delta};
+use crate::packet::*;
+use crate::tsig::{Key, Session};
+use crate::zone::serial_newer;
+use rand::Rng;
+use std::{net::SocketAddr, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time,
+};
+
+/// Largest message over TCP, which has a two byte length in front.
+pub const TCP_SIZE: usize = 0xFFFF;
+
+/// How long a primary may keep us waiting during a transfer.
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a primary sent us for a zone.
+pub enum Transfer {
+    /// Nothing, we have its latest version.
+    UpToDate,
+    /// Every record of the zone.
+    Full(Vec<DnsRecord>),
+    /// The changes since the version we have.
+    Incremental(Vec<Delta>),
+}
+
+/// Responses to the zone transfer `request` carrying `records`, as few as
+/// TCP messages allow (RFC 5936 section 2.2) with `reserved` bytes left in
+/// each for a TSIG.
+pub fn responses(
+    request: &DnsPacket,
+    records: Vec<DnsRecord>,
+    reserved: usize,
+) -> Result<Vec<DnsPacket>> {
+    let mut response = DnsPacket::new();
+    response.header.id = request.header.id;
+    response.header.response = true;
+    response.header.authoritative_answer = true;
+    response.questions = request.questions.clone();
+    if let Some((_, dnssec_ok)) = request.edns() {
+        response.add_edns(dnssec_ok);
+    }
+
+    let mut buffer = BytePacketBuffer::with_size(TCP_SIZE);
+    response.write(&mut buffer)?;
+    let empty_size = buffer.pos;
+
+    let mut responses = Vec::new();
+    let mut current = response.clone();
+    let mut size = empty_size;
+    for record in records {
+        let mut buffer = BytePacketBuffer::with_size(TCP_SIZE);
+        let record_size = record.write(&mut buffer)?;
+        if size + record_size + reserved > TCP_SIZE && !current.answers.is_empty() {
+            responses.push(current);
+            current = response.clone();
+            size = empty_size;
+        }
+        current.answers.push(record);
+        size += record_size;
+    }
+    responses.push(current);
+
+    Ok(responses)
+}
+
+/// Transfer the zone `origin` from `primary`, with IXFR from the version
+/// `serial` if given and with AXFR otherwise. With a `key` the request is
+/// signed, and so must every response be.
+pub async fn fetch(
+    primary: SocketAddr,
+    origin: &str,
+    serial: Option<u32>,
+    key: Option<&Key>,
+) -> Result<Transfer> {
+    let mut request = DnsPacket::new();
+    request.header.id = rand::thread_rng().gen();
+    match serial {
+        Some(serial) => {
+            request
+                .questions
+                .push(DnsQuestion::new(origin, QueryType::IXFR));
+            request.authorities.push(DnsRecord::SOA {
+                domain: origin.to_owned(),
+                mname: String::new(),
+                rname: String::new(),
+                serial,
+                refresh: 0,
+                retry: 0,
+                expire: 0,
+                minimum: 0,
+                ttl: 0,
+            });
+        }
+        None => request
+            .questions
+            .push(DnsQuestion::new(origin, QueryType::AXFR)),
+    }
+
+    let mut stream = time::timeout(TRANSFER_TIMEOUT, TcpStream::connect(primary))
+        .await
+        .map_err(|_| format!("Timed out connecting to {}", primary))??;
+    let mut session = key.cloned().map(Session::new);
+    write_message(&mut stream, &mut request, &mut session).await?;
+
+    // the transfer ends with the SOA it started with, which comes an even
+    // number of times whether the zone is sent whole or as deltas
+    let mut records = Vec::<DnsRecord>::new();
+    let first_serial = loop {
+        let response = time::timeout(TRANSFER_TIMEOUT, read_message(&mut stream, &mut session))
+            .await
+            .map_err(|_| format!("Timed out waiting for {}", primary))?
+            .map_err(|err| format!("{} from {}", err, primary))?;
+        // only the first response needs to repeat the question
+        if response.header.id != request.header.id
+            || (!response.questions.is_empty() && response.questions != request.questions)
+        {
+            return Err(format!("Mismatched response from {}", primary).into());
+        }
+        if response.header.rescode != ResultCode::NOERROR {
+            return Err(format!("{} answered {:?}", primary, response.header.rescode).into());
+        }
+        let single = response.answers.len() == 1;
+        records.extend(response.answers);
+
+        let Some(DnsRecord::SOA {
+            serial: first_serial,
+            ..
+        }) = records.first()
+        else {
+            return Err(format!("{} didn't start the transfer with an SOA", primary).into());
+        };
+        if single && records.len() == 1 {
+            match serial {
+                Some(serial) if !serial_newer(*first_serial, serial) => {
+                    return Ok(Transfer::UpToDate)
+                }
+                _ => return Err(format!("{} only sent its SOA", primary).into()),
+            }
+        }
+
+        let soas = records
+            .iter()
+            .filter(|rec| rec.qtype() == QueryType::SOA)
+            .count();
+        match records.last() {
+            Some(DnsRecord::SOA { serial, .. }) if serial == first_serial && soas % 2 == 0 => {
+                break *first_serial
+            }
+            _ => continue,
+        }
+    };
+
+    records.pop();
+    match records.get(1) {
+        Some(DnsRecord::SOA { .. }) => {
+            let deltas = journal::deltas(records.into_iter().skip(1))
+                .ok_or_else(|| format!("Malformed IXFR from {}", primary))?;
+            match deltas.last() {
+                Some(delta) if delta.to_serial() == first_serial => {
+                    Ok(Transfer::Incremental(deltas))
+                }
+                _ => Err(format!("Malformed IXFR from {}", primary).into()),
+            }
+        }
+        _ => Ok(Transfer::Full(records)),
+    }
+}
+
+async fn write_message(
+    stream: &mut TcpStream,
+    packet: &mut DnsPacket,
+    session: &mut Option<Session>,
+) -> Result<()> {
+    let mut buffer = BytePacketBuffer::with_size(TCP_SIZE);
+    packet.write(&mut buffer)?;
+    let mut message = buffer.get_range(0, buffer.pos)?.to_vec();
+    if let Some(session) = session {
+        session.sign(&mut message)?;
+    }
+
+    let mut framed = (message.len() as u16).to_be_bytes().to_vec();
+    framed.extend(message);
+    stream.write_all(&framed).await?;
+
+    Ok(())
+}
+
+async fn read_message(stream: &mut TcpStream, session: &mut Option<Session>) -> Result<DnsPacket> {
+    let mut len = [0; 2];
+    stream.read_exact(&mut len).await?;
+    let mut buffer = BytePacketBuffer::with_size(u16::from_be_bytes(len) as usize);
+    stream.read_exact(&mut buffer.buf).await?;
+    if let Some(session) = session {
+        session.verify(&buffer.buf)?;
+    }
+
+    DnsPacket::from_buffer(&mut buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn soa(serial: u32) -> DnsRecord {
+        DnsRecord::SOA {
+            domain: "example.com".to_owned(),
+            mname: "ns.example.com".to_owned(),
+            rname: "hostmaster.example.com".to_owned(),
+            serial,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 300,
+            ttl: 3600,
+        }
+    }
+
+    fn a(domain: &str) -> DnsRecord {
+        DnsRecord::A {
+            domain: domain.to_owned(),
+            addr: "192.0.2.1".parse().unwrap(),
+            ttl: 3600,
+        }
+    }
+
+    /// A primary answering a single transfer request with `messages`, each
+    /// holding the records given for it.
+    async fn primary(messages: Vec<Vec<DnsRecord>>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let request = read_message(&mut stream, &mut None).await.unwrap();
+            for (i, answers) in messages.into_iter().enumerate() {
+                let mut response = DnsPacket::new();
+                response.header.id = request.header.id;
+                response.header.response = true;
+                if i == 0 {
+                    response.questions = request.questions.clone();
+                }
+                response.answers = answers;
+                write_message(&mut stream, &mut response, &mut None)
+                    .await
+                    .unwrap();
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn splits_across_messages() {
+        let mut request = DnsPacket::new();
+        request
+            .questions
+            .push(DnsQuestion::new("example.com", QueryType::AXFR));
+        let records = (0..5000)
+            .map(|i| a(&format!("host-{}.example.com", i)))
+            .collect::<Vec<_>>();
+
+        let responses = responses(&request, records.clone(), 100).unwrap();
+        assert!(responses.len() > 1);
+        for response in &responses {
+            let mut response = response.clone();
+            let mut buffer = BytePacketBuffer::with_size(TCP_SIZE);
+            response.write(&mut buffer).unwrap();
+            assert!(buffer.pos + 100 <= TCP_SIZE);
+            assert_eq!(response.questions, request.questions);
+        }
+        let sent = responses
+            .into_iter()
+            .flat_map(|response| response.answers)
+            .collect::<Vec<_>>();
+        assert_eq!(sent, records);
+    }
+
+    #[tokio::test]
+    async fn fetches_whole_zone() {
+        let primary = primary(vec![
+            vec![soa(2), a("www.example.com")],
+            vec![a("mail.example.com"), soa(2)],
+        ])
+        .await;
+
+        match fetch(primary, "example.com", None, None).await.unwrap() {
+            Transfer::Full(records) => assert_eq!(
+                records,
+                [soa(2), a("www.example.com"), a("mail.example.com")]
+            ),
+            _ => panic!("Expected the whole zone"),
+        }
+    }
+
+    #[tokio::test]
+    async fn fetches_deltas() {
+        let primary = primary(vec![
+            vec![soa(3), soa(1), a("www.example.com"), soa(2)],
+            vec![a("mail.example.com"), soa(2), soa(3), soa(3)],
+        ])
+        .await;
+
+        match fetch(primary, "example.com", Some(1), None).await.unwrap() {
+            Transfer::Incremental(deltas) => {
+                assert_eq!(deltas.len(), 2);
+                assert_eq!(deltas[0].removed, [soa(1), a("www.example.com")]);
+                assert_eq!(deltas[0].added, [soa(2), a("mail.example.com")]);
+                assert_eq!(deltas[1].removed, [soa(2)]);
+                assert_eq!(deltas[1].added, [soa(3)]);
+            }
+            _ => panic!("Expected deltas"),
+        }
+    }
+
+    #[tokio::test]
+    async fn fetches_up_to_date() {
+        let primary = primary(vec![vec![soa(1)]]).await;
+
+        assert!(matches!(
+            fetch(primary, "example.com", Some(1), None).await.unwrap(),
+            Transfer::UpToDate
+        ));
+    }
+}
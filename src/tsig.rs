@@ -0,0 +1,556 @@
+use crate::anchors::unix_now;
+use crate::packet::*;
+use crate::transfer::TCP_SIZE;
+use ring::hmac;
+
+/// How far apart our clock and the signer's may be, in seconds.
+pub const FUDGE: u16 = 300;
+
+/// Errors of a TSIG, telling why a request couldn't be verified (RFC 8945
+/// section 3).
+pub const BADSIG: u16 = 16;
+pub const BADKEY: u16 = 17;
+pub const BADTIME: u16 = 18;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    HmacSha256,
+    HmacSha384,
+    HmacSha512,
+}
+
+impl Algorithm {
+    pub fn from_name(name: &str) -> Option<Algorithm> {
+        match name.trim_end_matches('.').to_lowercase().as_str() {
+            "hmac-sha256" => Some(Algorithm::HmacSha256),
+            "hmac-sha384" => Some(Algorithm::HmacSha384),
+            "hmac-sha512" => Some(Algorithm::HmacSha512),
+            _ => None,
+        }
+    }
+
+    /// The name of the algorithm in the TSIG, a domain name.
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::HmacSha256 => "hmac-sha256",
+            Algorithm::HmacSha384 => "hmac-sha384",
+            Algorithm::HmacSha512 => "hmac-sha512",
+        }
+    }
+
+    fn hmac(self) -> hmac::Algorithm {
+        match self {
+            Algorithm::HmacSha256 => hmac::HMAC_SHA256,
+            Algorithm::HmacSha384 => hmac::HMAC_SHA384,
+            Algorithm::HmacSha512 => hmac::HMAC_SHA512,
+        }
+    }
+
+    fn mac_len(self) -> usize {
+        self.hmac().digest_algorithm().output_len()
+    }
+}
+
+/// A secret shared with another server, which both know by its name.
+#[derive(Debug, Clone)]
+pub struct Key {
+    pub name: String,
+    pub algorithm: Algorithm,
+    pub secret: Vec<u8>,
+}
+
+impl Key {
+    fn mac(&self, data: &[u8]) -> Vec<u8> {
+        let key = hmac::Key::new(self.algorithm.hmac(), &self.secret);
+        hmac::sign(&key, data).as_ref().to_vec()
+    }
+
+    /// Whether `mac` is the MAC of `data`, which must be the whole of it:
+    /// truncated MACs aren't accepted.
+    fn verify(&self, data: &[u8], mac: &[u8]) -> bool {
+        let key = hmac::Key::new(self.algorithm.hmac(), &self.secret);
+        hmac::verify(&key, data, mac).is_ok()
+    }
+}
+
+/// The messages of an exchange signed with a key, a request and its
+/// responses, the MAC of each one covering the MAC of the one before
+/// (RFC 8945 section 5.3.1).
+pub struct Session {
+    key: Key,
+    /// The MAC of the last message signed or verified.
+    mac: Vec<u8>,
+    /// Whether the first response went by already, the MACs of the next
+    /// ones in a zone transfer only covering the time they were signed.
+    timers_only: bool,
+    /// When a request signed at the wrong time claims it was signed, which
+    /// its response echoes (RFC 8945 section 5.2.3).
+    bad_time: Option<u64>,
+}
+
+impl Session {
+    /// A session for requests we sign with `key`.
+    pub fn new(key: Key) -> Session {
+        Session {
+            key,
+            mac: Vec::new(),
+            timers_only: false,
+            bad_time: None,
+        }
+    }
+
+    pub fn key(&self) -> &Key {
+        &self.key
+    }
+
+    /// Whether the request was signed too long ago or in the future, so
+    /// that its response can only tell it so.
+    pub fn bad_time(&self) -> bool {
+        self.bad_time.is_some()
+    }
+
+    /// Bytes the TSIG takes at the end of a message.
+    pub fn size(&self) -> usize {
+        let other = if self.bad_time() { 6 } else { 0 };
+        name_len(&self.key.name)
+            + 10
+            + name_len(self.key.algorithm.name())
+            + 16
+            + self.key.algorithm.mac_len()
+            + other
+    }
+
+    /// Sign `message`, appending a TSIG to it.
+    pub fn sign(&mut self, message: &mut Vec<u8>) -> Result<()> {
+        // a client with a wrong clock is told the time it is for us
+        let (time_signed, error, other) = match self.bad_time {
+            Some(time_signed) => (time_signed, BADTIME, unix_now().to_be_bytes()[2..].to_vec()),
+            None => (unix_now(), 0, Vec::new()),
+        };
+        let mut tsig = DnsRecord::TSIG {
+            domain: self.key.name.clone(),
+            algorithm: self.key.algorithm.name().to_owned(),
+            time_signed,
+            fudge: FUDGE,
+            mac: Vec::new(),
+            original_id: u16::from_be_bytes([message[0], message[1]]),
+            error,
+            other,
+        };
+
+        let signed_mac = self.key.mac(&self.signed_data(message, &tsig)?);
+        if let DnsRecord::TSIG { mac, .. } = &mut tsig {
+            mac.clone_from(&signed_mac);
+        }
+        append(message, &tsig)?;
+
+        self.timers_only = !self.mac.is_empty();
+        self.mac = signed_mac;
+        Ok(())
+    }
+
+    /// Check the TSIG of `message`, a response to a request signed in
+    /// this session.
+    pub fn verify(&mut self, message: &[u8]) -> Result<()> {
+        let (unsigned, tsig) = split(message)?.ok_or("Unsigned response")?;
+        let DnsRecord::TSIG {
+            domain,
+            algorithm,
+            time_signed,
+            fudge,
+            mac,
+            error,
+            ..
+        } = &tsig
+        else {
+            return Err("Malformed TSIG".into());
+        };
+
+        if *domain != self.key.name || Algorithm::from_name(algorithm) != Some(self.key.algorithm) {
+            return Err(format!("Response signed with another key {}", domain).into());
+        }
+        if *error != 0 {
+            return Err(format!("Request rejected with {}", error_name(*error)).into());
+        }
+        if !self.key.verify(&self.signed_data(&unsigned, &tsig)?, mac) {
+            return Err("Bad signature of the response".into());
+        }
+        if !in_time(*time_signed, *fudge) {
+            return Err("Response signed at the wrong time".into());
+        }
+
+        self.timers_only = true;
+        self.mac.clone_from(mac);
+        Ok(())
+    }
+
+    /// What the MAC of `message` covers, along with the TSIG variables of
+    /// `tsig` (RFC 8945 section 4.3).
+    fn signed_data(&self, message: &[u8], tsig: &DnsRecord) -> Result<Vec<u8>> {
+        let DnsRecord::TSIG {
+            domain,
+            algorithm,
+            time_signed,
+            fudge,
+            error,
+            other,
+            ..
+        } = tsig
+        else {
+            return Err("Malformed TSIG".into());
+        };
+
+        let mut buffer = BytePacketBuffer::with_size(message.len() + TCP_SIZE);
+        if !self.mac.is_empty() {
+            buffer.write_u16(self.mac.len() as u16)?;
+            buffer.write_bytes(&self.mac)?;
+        }
+        buffer.write_bytes(message)?;
+        if !self.timers_only {
+            buffer.write_qname(domain)?;
+            buffer.write_u16(CLASS_ANY)?;
+            buffer.write_u32(0)?; // ttl
+            buffer.write_qname(algorithm)?;
+        }
+        buffer.write_u16((time_signed >> 32) as u16)?;
+        buffer.write_u32(*time_signed as u32)?;
+        buffer.write_u16(*fudge)?;
+        if !self.timers_only {
+            buffer.write_u16(*error)?;
+            buffer.write_u16(other.len() as u16)?;
+            buffer.write_bytes(other)?;
+        }
+
+        Ok(buffer.buf[..buffer.pos].to_vec())
+    }
+}
+
+/// What the TSIG of a request tells about it.
+pub enum Verified {
+    Unsigned,
+    /// Signed with one of our keys, responses are signed with it too.
+    Signed(Session),
+    /// Not signed with any of our keys, the TSIG error and the unsigned
+    /// TSIG telling the client so (RFC 8945 section 5.3.2).
+    Rejected(u16, DnsRecord),
+}
+
+/// Check the TSIG of the request `message` against `keys` (RFC 8945
+/// section 5.2).
+pub fn verify_request(message: &[u8], keys: &[Key]) -> Result<Verified> {
+    let Some((unsigned, tsig)) = split(message)? else {
+        return Ok(Verified::Unsigned);
+    };
+    let DnsRecord::TSIG {
+        domain,
+        algorithm,
+        time_signed,
+        fudge,
+        mac,
+        original_id,
+        ..
+    } = &tsig
+    else {
+        return Err("Malformed TSIG".into());
+    };
+
+    let reject = |error| {
+        let tsig = DnsRecord::TSIG {
+            domain: domain.clone(),
+            algorithm: algorithm.clone(),
+            time_signed: *time_signed,
+            fudge: *fudge,
+            mac: Vec::new(),
+            original_id: *original_id,
+            error,
+            other: Vec::new(),
+        };
+        Ok(Verified::Rejected(error, tsig))
+    };
+
+    let Some(key) = keys
+        .iter()
+        .find(|key| key.name == *domain && Some(key.algorithm) == Algorithm::from_name(algorithm))
+    else {
+        return reject(BADKEY);
+    };
+    let mut session = Session::new(key.clone());
+    if !key.verify(&session.signed_data(&unsigned, &tsig)?, mac) {
+        return reject(BADSIG);
+    }
+
+    session.mac.clone_from(mac);
+    if !in_time(*time_signed, *fudge) {
+        session.bad_time = Some(*time_signed);
+    }
+    Ok(Verified::Signed(session))
+}
+
+/// `message` as it was signed, without its TSIG and with the ID it had
+/// then, along with the TSIG if it has one. The TSIG must be the last
+/// record of the message (RFC 8945 section 5.1).
+fn split(message: &[u8]) -> Result<Option<(Vec<u8>, DnsRecord)>> {
+    let mut buffer = BytePacketBuffer {
+        buf: message.to_vec(),
+        pos: 0,
+    };
+    let mut header = DnsHeader::new();
+    header.read(&mut buffer)?;
+    for _ in 0..header.questions {
+        DnsQuestion::new("", QueryType::UNKNOWN(0)).read(&mut buffer)?;
+    }
+
+    let records = header.answers as usize
+        + header.authoritative_entries as usize
+        + header.resource_entries as usize;
+    let mut found = None;
+    for i in 0..records {
+        let start = buffer.pos;
+        let record = DnsRecord::read(&mut buffer)?;
+        if record.qtype() == QueryType::TSIG {
+            if i + 1 != records || header.resource_entries == 0 {
+                return Err("TSIG that isn't the last record".into());
+            }
+            found = Some((start, record));
+        }
+    }
+
+    let Some((start, tsig)) = found else {
+        return Ok(None);
+    };
+    let DnsRecord::TSIG { original_id, .. } = &tsig else {
+        return Err("Malformed TSIG".into());
+    };
+    let mut unsigned = message[..start].to_vec();
+    unsigned[0..2].copy_from_slice(&original_id.to_be_bytes());
+    unsigned[10..12].copy_from_slice(&(header.resource_entries - 1).to_be_bytes());
+
+    Ok(Some((unsigned, tsig)))
+}
+
+/// Bytes `tsig` takes at the end of a message.
+pub fn record_size(tsig: &DnsRecord) -> Result<usize> {
+    tsig.write(&mut BytePacketBuffer::with_size(TCP_SIZE))
+}
+
+/// Add `tsig` to the end of `message`.
+pub fn append(message: &mut Vec<u8>, tsig: &DnsRecord) -> Result<()> {
+    let mut buffer = BytePacketBuffer::with_size(TCP_SIZE);
+    let len = tsig.write(&mut buffer)?;
+    message.extend_from_slice(&buffer.buf[..len]);
+
+    let count = u16::from_be_bytes([message[10], message[11]]) + 1;
+    message[10..12].copy_from_slice(&count.to_be_bytes());
+    Ok(())
+}
+
+/// Whether a message signed at `time_signed` is recent enough, and not
+/// from too far in the future.
+fn in_time(time_signed: u64, fudge: u16) -> bool {
+    unix_now().abs_diff(time_signed) <= fudge as u64
+}
+
+pub fn error_name(error: u16) -> String {
+    match error {
+        BADSIG => "BADSIG".to_owned(),
+        BADKEY => "BADKEY".to_owned(),
+        BADTIME => "BADTIME".to_owned(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> Key {
+        Key {
+            name: "transfer-key".to_owned(),
+            algorithm: Algorithm::HmacSha256,
+            secret: b"secretsecretsecretsecret".to_vec(),
+        }
+    }
+
+    /// A message with a single question and `answers` A records.
+    fn message(id: u16, answers: u8) -> Vec<u8> {
+        let mut packet = DnsPacket::new();
+        packet.header.id = id;
+        packet
+            .questions
+            .push(DnsQuestion::new("example.com", QueryType::AXFR));
+        for i in 0..answers {
+            packet.answers.push(DnsRecord::A {
+                domain: format!("host{}.example.com", i),
+                addr: [192, 0, 2, i].into(),
+                ttl: 3600,
+            });
+        }
+
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        buffer.buf[..buffer.pos].to_vec()
+    }
+
+    /// The session of the server for `request`, which must verify.
+    fn server_session(request: &[u8]) -> Session {
+        match verify_request(request, &[key()]).unwrap() {
+            Verified::Signed(session) => session,
+            Verified::Unsigned => panic!("request not signed"),
+            Verified::Rejected(error, _) => panic!("request rejected: {}", error_name(error)),
+        }
+    }
+
+    fn rejection(request: &[u8], keys: &[Key]) -> Option<u16> {
+        match verify_request(request, keys).unwrap() {
+            Verified::Rejected(error, _) => Some(error),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut client = Session::new(key());
+        let mut request = message(1, 0);
+        client.sign(&mut request).unwrap();
+
+        let mut server = server_session(&request);
+        assert!(!server.bad_time());
+        let mut response = message(1, 2);
+        server.sign(&mut response).unwrap();
+        assert_eq!(response.len(), message(1, 2).len() + server.size());
+
+        client.verify(&response).unwrap();
+        assert!(matches!(
+            verify_request(&message(1, 0), &[key()]).unwrap(),
+            Verified::Unsigned
+        ));
+    }
+
+    #[test]
+    fn tampered_request() {
+        let mut request = message(1, 0);
+        Session::new(key()).sign(&mut request).unwrap();
+
+        let mut tampered = request.clone();
+        tampered[13] ^= 0x20; // a letter of the question
+        assert_eq!(rejection(&tampered, &[key()]), Some(BADSIG));
+
+        let other = Key {
+            secret: b"another secret".to_vec(),
+            ..key()
+        };
+        assert_eq!(rejection(&request, &[other]), Some(BADSIG));
+        let renamed = Key {
+            name: "other-key".to_owned(),
+            ..key()
+        };
+        assert_eq!(rejection(&request, &[renamed]), Some(BADKEY));
+    }
+
+    #[test]
+    fn tampered_response() {
+        let mut client = Session::new(key());
+        let mut request = message(1, 0);
+        client.sign(&mut request).unwrap();
+
+        let mut response = message(1, 1);
+        server_session(&request).sign(&mut response).unwrap();
+        // the address of the answer, right before the TSIG
+        response[message(1, 1).len() - 1] ^= 1;
+        assert!(client.verify(&response).is_err());
+    }
+
+    #[test]
+    fn bad_time() {
+        // sign the request as if it were 20 minutes ago
+        let client = Session::new(key());
+        let mut request = message(1, 0);
+        let time_signed = unix_now() - 1200;
+        let mut tsig = DnsRecord::TSIG {
+            domain: key().name,
+            algorithm: key().algorithm.name().to_owned(),
+            time_signed,
+            fudge: FUDGE,
+            mac: Vec::new(),
+            original_id: 1,
+            error: 0,
+            other: Vec::new(),
+        };
+        let signed_mac = key().mac(&client.signed_data(&request, &tsig).unwrap());
+        if let DnsRecord::TSIG { mac, .. } = &mut tsig {
+            *mac = signed_mac;
+        }
+        append(&mut request, &tsig).unwrap();
+
+        let mut server = server_session(&request);
+        assert!(server.bad_time());
+        let mut response = message(1, 0);
+        server.sign(&mut response).unwrap();
+
+        // the response echoes the request's time and tells the server's
+        let (_, tsig) = split(&response).unwrap().unwrap();
+        let DnsRecord::TSIG {
+            time_signed: echoed,
+            error,
+            other,
+            ..
+        } = tsig
+        else {
+            panic!("not a TSIG");
+        };
+        assert_eq!(error, BADTIME);
+        assert_eq!(echoed, time_signed);
+        let mut now = [0; 8];
+        now[2..].copy_from_slice(&other);
+        assert!(u64::from_be_bytes(now).abs_diff(unix_now()) <= 1);
+    }
+
+    #[test]
+    fn transfer_chain() {
+        let mut client = Session::new(key());
+        let mut request = message(1, 0);
+        client.sign(&mut request).unwrap();
+
+        // the second message is only signed over its timers
+        let mut server = server_session(&request);
+        let mut first = message(1, 3);
+        server.sign(&mut first).unwrap();
+        assert!(server.timers_only);
+        let mut second = message(1, 2);
+        server.sign(&mut second).unwrap();
+
+        // each MAC covers the one before, so the order matters
+        let mut out_of_order = Session::new(key());
+        out_of_order.mac.clone_from(&client.mac);
+        assert!(out_of_order.verify(&second).is_err());
+
+        client.verify(&first).unwrap();
+        client.verify(&second).unwrap();
+    }
+
+    #[test]
+    fn split_restores_original_id() {
+        let mut request = message(0x1234, 1);
+        Session::new(key()).sign(&mut request).unwrap();
+        assert_eq!(&request[10..12], &[0, 1]);
+
+        // a forwarder gave it an ID of its own
+        request[0..2].copy_from_slice(&0x5678u16.to_be_bytes());
+        let (unsigned, tsig) = split(&request).unwrap().unwrap();
+        assert_eq!(unsigned, message(0x1234, 1));
+        assert!(matches!(
+            tsig,
+            DnsRecord::TSIG {
+                original_id: 0x1234,
+                ..
+            }
+        ));
+        server_session(&request);
+
+        // the TSIG has to be last, in the additional section
+        let mut moved = request.clone();
+        moved[6..8].copy_from_slice(&[0, 2]);
+        moved[10..12].copy_from_slice(&[0, 0]);
+        assert!(split(&moved).is_err());
+    }
+}
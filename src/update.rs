@@ -0,0 +1,418 @@
+use crate::packet::*;
+use crate::zone::{serial_newer, Zone};
+
+/// The type standing for every type in prerequisites and deletions.
+const TYPE_ANY: u16 = 255;
+
+/// A dynamic update of a zone (RFC 2136), its prerequisites and changes
+/// being records whose class tells what they mean.
+pub struct Update {
+    pub zone: DnsQuestion,
+    pub prerequisites: Vec<(DnsRecord, u16)>,
+    pub updates: Vec<(DnsRecord, u16)>,
+}
+
+impl Update {
+    pub fn from_buffer(buffer: &mut BytePacketBuffer) -> Result<Update> {
+        let mut header = DnsHeader::new();
+        header.read(buffer)?;
+
+        // the zone section is where a query has its question
+        if header.questions != 1 {
+            return Err("An update needs exactly one zone".into());
+        }
+        let mut zone = DnsQuestion::new("", QueryType::UNKNOWN(0));
+        zone.read(buffer)?;
+        if zone.qtype != QueryType::SOA {
+            return Err("The zone of an update must be given with type SOA".into());
+        }
+
+        let prerequisites = (0..header.answers)
+            .map(|_| DnsRecord::read_with_class(buffer))
+            .collect::<Result<_>>()?;
+        let updates = (0..header.authoritative_entries)
+            .map(|_| DnsRecord::read_with_class(buffer))
+            .collect::<Result<_>>()?;
+
+        Ok(Update {
+            zone,
+            prerequisites,
+            updates,
+        })
+    }
+
+    /// Check the prerequisites against `zone` (RFC 2136 section 3.2).
+    pub fn check(&self, zone: &Zone) -> std::result::Result<(), ResultCode> {
+        // RRsets that must exist exactly as given, grouped by name and type
+        let mut rrsets: Vec<Vec<&DnsRecord>> = Vec::new();
+
+        for (record, class) in &self.prerequisites {
+            let name = record.domain();
+            let qtype = record.qtype();
+            let any_type = qtype.to_num() == TYPE_ANY;
+            if record.ttl() != 0 {
+                return Err(ResultCode::FORMERR);
+            }
+            if !in_bailiwick(name, &zone.origin) {
+                return Err(ResultCode::NOTZONE);
+            }
+
+            let records = zone.records(name);
+            let rrset_exists = records.iter().any(|rec| rec.qtype() == qtype);
+            match *class {
+                CLASS_ANY | CLASS_NONE if !is_empty(record) => return Err(ResultCode::FORMERR),
+                CLASS_ANY if any_type && records.is_empty() => return Err(ResultCode::NXDOMAIN),
+                CLASS_ANY if !any_type && !rrset_exists => return Err(ResultCode::NXRRSET),
+                CLASS_NONE if any_type && !records.is_empty() => return Err(ResultCode::YXDOMAIN),
+                CLASS_NONE if !any_type && rrset_exists => return Err(ResultCode::YXRRSET),
+                CLASS_ANY | CLASS_NONE => {}
+                CLASS_IN if any_type => return Err(ResultCode::FORMERR),
+                CLASS_IN => match rrsets.iter_mut().find(|rrset| {
+                    rrset[0].domain().eq_ignore_ascii_case(name) && rrset[0].qtype() == qtype
+                }) {
+                    Some(rrset) => rrset.push(record),
+                    None => rrsets.push(vec![record]),
+                },
+                _ => return Err(ResultCode::FORMERR),
+            }
+        }
+
+        for rrset in rrsets {
+            let records = zone
+                .records(rrset[0].domain())
+                .iter()
+                .filter(|rec| rec.qtype() == rrset[0].qtype())
+                .collect::<Vec<_>>();
+            let same = records.len() == rrset.len()
+                && records
+                    .iter()
+                    .all(|rec| rrset.iter().any(|wanted| same_data(rec, wanted)));
+            if !same {
+                return Err(ResultCode::NXRRSET);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// `zone` with the changes made to it (RFC 2136 section 3.4), its
+    /// serial increased, or nothing if they change nothing.
+    pub fn apply(&self, zone: &Zone) -> std::result::Result<Option<Zone>, ResultCode> {
+        // nothing is changed unless every change makes sense
+        for (record, class) in &self.updates {
+            if !in_bailiwick(record.domain(), &zone.origin) {
+                return Err(ResultCode::NOTZONE);
+            }
+            let qtype = record.qtype();
+            let meta = matches!(
+                qtype,
+                QueryType::OPT
+                    | QueryType::TSIG
+                    | QueryType::IXFR
+                    | QueryType::AXFR
+                    | QueryType::UNKNOWN(249..)
+            );
+            let valid = match *class {
+                CLASS_IN => !meta && !is_empty(record),
+                CLASS_ANY => {
+                    record.ttl() == 0 && is_empty(record) && (!meta || qtype.to_num() == TYPE_ANY)
+                }
+                CLASS_NONE => record.ttl() == 0 && !meta && !is_empty(record),
+                _ => false,
+            };
+            if !valid {
+                return Err(ResultCode::FORMERR);
+            }
+        }
+
+        let mut records = zone.iter().cloned().collect::<Vec<_>>();
+        for (record, class) in &self.updates {
+            let name = record.domain();
+            let qtype = record.qtype();
+            let apex = name.eq_ignore_ascii_case(&zone.origin);
+            match *class {
+                CLASS_IN => add(&mut records, record, apex),
+                CLASS_ANY => records.retain(|rec| {
+                    !rec.domain().eq_ignore_ascii_case(name)
+                        || (qtype.to_num() != TYPE_ANY && rec.qtype() != qtype)
+                        || (apex && matches!(rec.qtype(), QueryType::SOA | QueryType::NS))
+                }),
+                _ => {
+                    let last_ns = apex
+                        && qtype == QueryType::NS
+                        && records
+                            .iter()
+                            .filter(|rec| {
+                                rec.domain().eq_ignore_ascii_case(name)
+                                    && rec.qtype() == QueryType::NS
+                            })
+                            .count()
+                            == 1;
+                    if qtype != QueryType::SOA && !last_ns {
+                        records.retain(|rec| !same_data(rec, record));
+                    }
+                }
+            }
+        }
+
+        let mut updated = Zone::new(&zone.origin, records).map_err(|_| ResultCode::SERVFAIL)?;
+        if updated.iter().count() == zone.iter().count()
+            && updated.iter().all(|rec| zone.contains(rec))
+        {
+            return Ok(None);
+        }
+
+        // an update that didn't set a new serial itself gets the next one
+        if !serial_newer(updated.serial(), zone.serial()) {
+            let mut soa = updated.soa().clone();
+            if let DnsRecord::SOA { serial, .. } = &mut soa {
+                *serial = zone.serial().wrapping_add(1);
+            }
+            let records = updated
+                .iter()
+                .filter(|rec| rec.qtype() != QueryType::SOA)
+                .cloned()
+                .chain([soa])
+                .collect();
+            updated = Zone::new(&zone.origin, records).map_err(|_| ResultCode::SERVFAIL)?;
+        }
+
+        Ok(Some(updated))
+    }
+}
+
+/// Add `record` to `records` unless it conflicts with them: a CNAME can't
+/// share its name with other data (RFC 2136 section 3.4.2.2), and an SOA
+/// only replaces the one of the zone when its serial is newer.
+fn add(records: &mut Vec<DnsRecord>, record: &DnsRecord, apex: bool) {
+    let name = record.domain();
+    let at_name = || {
+        records
+            .iter()
+            .filter(move |rec| rec.domain().eq_ignore_ascii_case(name))
+    };
+
+    match record {
+        DnsRecord::SOA { serial, .. } => {
+            let newer = at_name().any(|rec| match rec {
+                DnsRecord::SOA {
+                    serial: current, ..
+                } => serial_newer(*serial, *current),
+                _ => false,
+            });
+            if !apex || !newer {
+                return;
+            }
+            records.retain(|rec| rec.qtype() != QueryType::SOA);
+        }
+        DnsRecord::CNAME { .. } => {
+            if at_name().any(|rec| rec.qtype() != QueryType::CNAME) {
+                return;
+            }
+            // a name has a single CNAME, which is replaced
+            records.retain(|rec| !rec.domain().eq_ignore_ascii_case(name));
+        }
+        _ => {
+            if at_name().any(|rec| rec.qtype() == QueryType::CNAME) {
+                return;
+            }
+            // the same data again only changes the TTL
+            records.retain(|rec| !same_data(rec, record));
+        }
+    }
+
+    records.push(record.clone());
+}
+
+/// Whether two records are the same but for their TTL and the case of
+/// their names.
+fn same_data(a: &DnsRecord, b: &DnsRecord) -> bool {
+    a.domain().eq_ignore_ascii_case(b.domain())
+        && a.qtype() == b.qtype()
+        && a.rdata().ok() == b.rdata().ok()
+}
+
+/// Whether `record` came with empty RDATA, as deletions and prerequisites
+/// that aren't about a specific record do.
+fn is_empty(record: &DnsRecord) -> bool {
+    matches!(record, DnsRecord::UNKNOWN { data, .. } if data.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn soa(serial: u32) -> DnsRecord {
+        DnsRecord::SOA {
+            domain: "example.com".to_owned(),
+            mname: "ns.example.com".to_owned(),
+            rname: "hostmaster.example.com".to_owned(),
+            serial,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 300,
+            ttl: 3600,
+        }
+    }
+
+    fn ns(domain: &str) -> DnsRecord {
+        DnsRecord::NS {
+            domain: domain.to_owned(),
+            host: "ns.example.com".to_owned(),
+            ttl: 3600,
+        }
+    }
+
+    fn a(domain: &str, addr: &str) -> DnsRecord {
+        DnsRecord::A {
+            domain: domain.to_owned(),
+            addr: addr.parse().unwrap(),
+            ttl: 3600,
+        }
+    }
+
+    /// A record with empty RDATA, as prerequisites and deletions have them.
+    fn empty(domain: &str, qtype: u16) -> DnsRecord {
+        DnsRecord::UNKNOWN {
+            domain: domain.to_owned(),
+            qtype,
+            data: Vec::new(),
+            ttl: 0,
+        }
+    }
+
+    /// `record` with the TTL of zero prerequisites and deletions have.
+    fn zero_ttl(mut record: DnsRecord) -> DnsRecord {
+        record.set_ttl(0);
+        record
+    }
+
+    fn zone() -> Zone {
+        Zone::new(
+            "example.com",
+            vec![soa(1), ns("example.com"), a("www.example.com", "192.0.2.1")],
+        )
+        .unwrap()
+    }
+
+    fn update(prerequisites: Vec<(DnsRecord, u16)>, updates: Vec<(DnsRecord, u16)>) -> Update {
+        Update {
+            zone: DnsQuestion::new("example.com", QueryType::SOA),
+            prerequisites,
+            updates,
+        }
+    }
+
+    fn check(prerequisite: DnsRecord, class: u16) -> std::result::Result<(), ResultCode> {
+        update(vec![(prerequisite, class)], Vec::new()).check(&zone())
+    }
+
+    #[test]
+    fn prerequisites() {
+        let a_type = QueryType::A.to_num();
+
+        assert_eq!(check(empty("www.example.com", TYPE_ANY), CLASS_ANY), Ok(()));
+        assert_eq!(
+            check(empty("new.example.com", TYPE_ANY), CLASS_ANY),
+            Err(ResultCode::NXDOMAIN)
+        );
+        assert_eq!(
+            check(empty("WWW.example.com", TYPE_ANY), CLASS_NONE),
+            Err(ResultCode::YXDOMAIN)
+        );
+        assert_eq!(
+            check(empty("www.example.com", 28), CLASS_ANY),
+            Err(ResultCode::NXRRSET)
+        );
+        assert_eq!(
+            check(empty("www.example.com", a_type), CLASS_NONE),
+            Err(ResultCode::YXRRSET)
+        );
+
+        // an RRset given in full has to match, whatever the case of its name
+        let record = zero_ttl(a("WWW.Example.com", "192.0.2.1"));
+        assert_eq!(check(record, CLASS_IN), Ok(()));
+        let record = zero_ttl(a("www.example.com", "192.0.2.2"));
+        assert_eq!(check(record, CLASS_IN), Err(ResultCode::NXRRSET));
+    }
+
+    #[test]
+    fn keeps_last_ns_and_soa_at_apex() {
+        let zone = zone();
+
+        let last_ns = update(Vec::new(), vec![(zero_ttl(ns("Example.COM")), CLASS_NONE)]);
+        assert!(last_ns.apply(&zone).unwrap().is_none());
+
+        let everything = update(
+            Vec::new(),
+            vec![(empty("EXAMPLE.com", TYPE_ANY), CLASS_ANY)],
+        );
+        assert!(everything.apply(&zone).unwrap().is_none());
+
+        // with another NS left, the first can go
+        let mut other = ns("example.com");
+        if let DnsRecord::NS { host, .. } = &mut other {
+            *host = "ns2.example.com".to_owned();
+        }
+        let replaced = update(
+            Vec::new(),
+            vec![
+                (other.clone(), CLASS_IN),
+                (zero_ttl(ns("Example.COM")), CLASS_NONE),
+            ],
+        );
+        let updated = replaced.apply(&zone).unwrap().unwrap();
+        let apex_ns = updated
+            .records("example.com")
+            .iter()
+            .filter(|rec| rec.qtype() == QueryType::NS)
+            .collect::<Vec<_>>();
+        assert_eq!(apex_ns, [&other]);
+    }
+
+    #[test]
+    fn deletes_whatever_the_case() {
+        let deleted = update(
+            Vec::new(),
+            vec![(empty("WWW.Example.com", QueryType::A.to_num()), CLASS_ANY)],
+        );
+        let updated = deleted.apply(&zone()).unwrap().unwrap();
+        assert!(updated.records("www.example.com").is_empty());
+
+        let record = zero_ttl(a("WWW.example.COM", "192.0.2.1"));
+        let deleted = update(Vec::new(), vec![(record, CLASS_NONE)]);
+        let updated = deleted.apply(&zone()).unwrap().unwrap();
+        assert!(updated.records("www.example.com").is_empty());
+    }
+
+    #[test]
+    fn bumps_serial() {
+        let zone = zone();
+
+        let added = update(
+            Vec::new(),
+            vec![(a("new.example.com", "192.0.2.2"), CLASS_IN)],
+        );
+        let updated = added.apply(&zone).unwrap().unwrap();
+        assert_eq!(updated.serial(), 2);
+        assert!(updated.contains(&a("new.example.com", "192.0.2.2")));
+
+        // unless the update sets a newer one itself
+        let with_soa = update(
+            Vec::new(),
+            vec![
+                (a("new.example.com", "192.0.2.2"), CLASS_IN),
+                (soa(10), CLASS_IN),
+            ],
+        );
+        assert_eq!(with_soa.apply(&zone).unwrap().unwrap().serial(), 10);
+
+        // and a change to nothing leaves the zone as it is
+        let same = update(
+            Vec::new(),
+            vec![(a("www.example.com", "192.0.2.1"), CLASS_IN)],
+        );
+        assert!(same.apply(&zone).unwrap().is_none());
+    }
+}
@@ -0,0 +1,1199 @@
+use crate::config::{parse_name, Config};
+use crate::journal::{Delta, Journal};
+use crate::packet::*;
+use crate::resolver;
+use crate::transfer::{self, Transfer};
+use crate::tsig::Key;
+use crate::update::Update;
+use crate::zonefile;
+use std::{
+    collections::BTreeMap,
+    fs,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    time::{Duration, Instant, SystemTime},
+};
+
+/// How often zone files are checked for changes.
+pub const ZONE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// How often secondary zones are checked for being due for a refresh.
+pub const REFRESH_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How soon to try again to transfer a secondary zone we don't have yet.
+const INITIAL_RETRY: Duration = Duration::from_secs(60);
+
+/// Owner names as their labels from the root down, which sorts names in
+/// canonical order (RFC 4034 section 6.1) and every name's descendants
+/// right after it.
+type TreeKey = Vec<String>;
+
+fn tree_key(name: &str) -> TreeKey {
+    name.rsplit('.')
+        .filter(|label| !label.is_empty())
+        .map(str::to_ascii_lowercase)
+        .collect()
+}
+
+/// CNAMEs followed within our own zones for a single answer.
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// What a zone holds for a name and type.
+pub enum Lookup<'a> {
+    /// The records asked for, or the CNAME of the name, synthesized from a
+    /// wildcard when the name itself doesn't exist.
+    Answer(Vec<DnsRecord>),
+    /// The name lies in a child zone, served by these NS and their glue.
+    Referral(Vec<&'a DnsRecord>, Vec<&'a DnsRecord>),
+    NoData,
+    NxDomain,
+}
+
+/// A zone we're authoritative for, held in memory.
+pub struct Zone {
+    pub origin: String,
+    names: BTreeMap<TreeKey, Vec<DnsRecord>>,
+}
+
+impl Zone {
+    pub fn new(origin: &str, records: Vec<DnsRecord>) -> Result<Zone> {
+        let mut zone = Zone {
+            origin: origin.to_owned(),
+            names: BTreeMap::new(),
+        };
+
+        for record in records {
+            if !in_bailiwick(record.domain(), origin) {
+                return Err(format!("{} is outside of the zone", record.domain()).into());
+            }
+            let records = zone.names.entry(tree_key(record.domain())).or_default();
+            if !records.contains(&record) {
+                records.push(record);
+            }
+        }
+
+        let apex = zone.records(origin);
+        if apex
+            .iter()
+            .filter(|rec| rec.qtype() == QueryType::SOA)
+            .count()
+            != 1
+        {
+            return Err("A zone needs exactly one SOA record at its apex".into());
+        }
+        if !apex.iter().any(|rec| rec.qtype() == QueryType::NS) {
+            return Err("A zone needs NS records at its apex".into());
+        }
+
+        Ok(zone)
+    }
+
+    pub fn soa(&self) -> &DnsRecord {
+        self.records(&self.origin)
+            .iter()
+            .find(|rec| rec.qtype() == QueryType::SOA)
+            .expect("zones are created with an SOA")
+    }
+
+    pub fn serial(&self) -> u32 {
+        match self.soa() {
+            DnsRecord::SOA { serial, .. } => *serial,
+            _ => unreachable!(),
+        }
+    }
+
+    /// How often secondaries check for a new version of the zone, how soon
+    /// they try again when that fails, and how long they keep serving the
+    /// zone without reaching us.
+    pub fn timers(&self) -> (Duration, Duration, Duration) {
+        match self.soa() {
+            DnsRecord::SOA {
+                refresh,
+                retry,
+                expire,
+                ..
+            } => (
+                Duration::from_secs(*refresh as u64),
+                Duration::from_secs(*retry as u64),
+                Duration::from_secs(*expire as u64),
+            ),
+            _ => unreachable!(),
+        }
+    }
+
+    /// The zone with the changes of `deltas` made to it, which must take
+    /// it from its version on.
+    pub fn apply(&self, deltas: &[Delta]) -> Result<Zone> {
+        let mut names = self.names.clone();
+        let mut serial = self.serial();
+        for delta in deltas {
+            if delta.from_serial() != serial {
+                return Err(format!("No changes from serial {}", serial).into());
+            }
+            serial = delta.to_serial();
+
+            for record in &delta.removed {
+                let key = tree_key(record.domain());
+                let records = names.entry(key.clone()).or_default();
+                let Some(i) = records.iter().position(|rec| rec == record) else {
+                    return Err(format!("Can't remove missing record {}", record).into());
+                };
+                records.remove(i);
+                if records.is_empty() {
+                    names.remove(&key);
+                }
+            }
+            for record in &delta.added {
+                let records = names.entry(tree_key(record.domain())).or_default();
+                if !records.contains(record) {
+                    records.push(record.clone());
+                }
+            }
+        }
+
+        Zone::new(&self.origin, names.into_values().flatten().collect())
+    }
+
+    /// The SOA to prove a negative answer with, its TTL being how long the
+    /// answer may be cached (RFC 2308 section 3).
+    pub fn negative_soa(&self) -> DnsRecord {
+        let mut soa = self.soa().clone();
+        if let DnsRecord::SOA { minimum, ttl, .. } = &mut soa {
+            *ttl = (*ttl).min(*minimum);
+        }
+        soa
+    }
+
+    /// Every record of the zone, in canonical order of their names.
+    pub fn iter(&self) -> impl Iterator<Item = &DnsRecord> {
+        self.names.values().flatten()
+    }
+
+    pub fn contains(&self, record: &DnsRecord) -> bool {
+        self.records(record.domain()).contains(record)
+    }
+
+    /// The records owned by `name`.
+    pub fn records(&self, name: &str) -> &[DnsRecord] {
+        self.names
+            .get(&tree_key(name))
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Whether `name` owns records or has descendants that do, in which
+    /// case it's an empty non-terminal.
+    fn exists(&self, name: &str) -> bool {
+        let key = tree_key(name);
+        self.names
+            .range(key.clone()..)
+            .next()
+            .is_some_and(|(next, _)| next.starts_with(&key))
+    }
+
+    /// The NS records of the zone cut closest to the apex on the way down
+    /// to `qname`, if any. The DS records of a child live on our side of
+    /// the cut, so they don't count as below it.
+    fn delegation(&self, qname: &str, qtype: QueryType) -> Option<Vec<&DnsRecord>> {
+        let apex_labels = label_count(&self.origin);
+        (apex_labels + 1..=label_count(qname))
+            .map(|labels| ancestor(qname, labels))
+            .filter(|name| !(*name == qname && qtype == QueryType::DS))
+            .map(|name| {
+                self.records(name)
+                    .iter()
+                    .filter(|rec| rec.qtype() == QueryType::NS)
+                    .collect::<Vec<_>>()
+            })
+            .find(|ns| !ns.is_empty())
+    }
+
+    /// Addresses of the nameservers in `ns` that lie within the zone, which
+    /// resolvers couldn't find otherwise.
+    fn glue(&self, ns: &[&DnsRecord]) -> Vec<&DnsRecord> {
+        ns.iter()
+            .filter_map(|rec| match rec {
+                DnsRecord::NS { host, .. } if in_bailiwick(host, &self.origin) => Some(host),
+                _ => None,
+            })
+            .flat_map(|host| self.records(host))
+            .filter(|rec| matches!(rec.qtype(), QueryType::A | QueryType::AAAA))
+            .collect()
+    }
+
+    pub fn lookup(&self, qname: &str, qtype: QueryType) -> Lookup<'_> {
+        if let Some(ns) = self.delegation(qname, qtype) {
+            let glue = self.glue(&ns);
+            return Lookup::Referral(ns, glue);
+        }
+
+        if self.exists(qname) {
+            return match self.matching(qname, qtype) {
+                Some(records) => Lookup::Answer(records.into_iter().cloned().collect()),
+                None => Lookup::NoData,
+            };
+        }
+
+        // a name that doesn't exist is answered by the wildcard of its
+        // closest existing ancestor, if there's one (RFC 4592 section 3.3)
+        let closest_encloser = (label_count(&self.origin)..label_count(qname))
+            .rev()
+            .map(|labels| ancestor(qname, labels))
+            .find(|name| self.exists(name))
+            .unwrap_or(&self.origin);
+        let wildcard = match closest_encloser {
+            "" => "*".to_owned(),
+            encloser => format!("*.{}", encloser),
+        };
+        if !self.exists(&wildcard) {
+            return Lookup::NxDomain;
+        }
+
+        match self.matching(&wildcard, qtype) {
+            Some(records) => Lookup::Answer(
+                records
+                    .into_iter()
+                    .map(|rec| {
+                        let mut rec = rec.clone();
+                        rec.set_domain(qname);
+                        rec
+                    })
+                    .collect(),
+            ),
+            None => Lookup::NoData,
+        }
+    }
+
+    /// The records of `name` of type `qtype`, or else its CNAME.
+    fn matching(&self, name: &str, qtype: QueryType) -> Option<Vec<&DnsRecord>> {
+        let records = self.records(name);
+        [qtype, QueryType::CNAME].into_iter().find_map(|qtype| {
+            let matching = records
+                .iter()
+                .filter(|rec| rec.qtype() == qtype)
+                .collect::<Vec<_>>();
+            (!matching.is_empty()).then_some(matching)
+        })
+    }
+}
+
+/// Where a zone we serve comes from.
+enum Source {
+    /// A zone file, read again when it changes.
+    File {
+        file: String,
+        modified: Option<SystemTime>,
+    },
+    /// A primary the zone is transferred from (RFC 1034 section 4.3.5),
+    /// kept in `file` between restarts if given.
+    Primary {
+        primary: SocketAddr,
+        /// The key requests to the primary are signed with.
+        key: Option<Key>,
+        file: Option<String>,
+        /// When to check the primary for a new version.
+        refresh_at: Instant,
+        /// When to stop serving the zone unless it was refreshed by then.
+        expire_at: Instant,
+    },
+}
+
+/// A zone we serve, and the changes it went through.
+struct Served {
+    origin: String,
+    source: Source,
+    /// The zone, unless it's a secondary that has yet to be transferred or
+    /// that expired.
+    zone: Option<Arc<Zone>>,
+    journal: Journal,
+}
+
+/// The zones we're authoritative for.
+pub struct Zones {
+    served: RwLock<Vec<Served>>,
+}
+
+impl Zones {
+    pub fn load(config: &Config) -> Result<Zones> {
+        let mut served = Vec::new();
+        for local in &config.zones {
+            let modified = modified(&local.file);
+            let zone = load_zone(&local.origin, &local.file)?;
+            let mut journal = Journal::load(&journal_path(&local.file), &zone.origin);
+            let zone = journal.catch_up(zone);
+            println!(
+                "Loaded zone {} from {} at serial {}",
+                local.origin,
+                local.file,
+                zone.serial()
+            );
+            served.push(Served {
+                origin: local.origin.clone(),
+                source: Source::File {
+                    file: local.file.clone(),
+                    modified,
+                },
+                zone: Some(Arc::new(zone)),
+                journal,
+            });
+        }
+
+        for secondary in &config.secondaries {
+            let now = Instant::now();
+            let mut expire_at = now;
+            let mut zone = None;
+            let mut journal = Journal::new();
+
+            // a copy of the zone is served until it would have expired,
+            // counting from when it was saved
+            if let Some(file) = &secondary.file {
+                if let Some(saved) = modified(file) {
+                    match load_zone(&secondary.origin, file) {
+                        Ok(loaded) => {
+                            let (_, _, expire) = loaded.timers();
+                            let age = saved.elapsed().unwrap_or_default();
+                            expire_at = now + expire.saturating_sub(age);
+                            println!("Loaded zone {} from {}", secondary.origin, file);
+                            zone = Some(loaded);
+                        }
+                        Err(err) => eprintln!("Ignoring the copy of a secondary zone: {}", err),
+                    }
+                }
+                journal = Journal::load(&journal_path(file), &secondary.origin);
+                zone = zone.map(|zone| journal.catch_up(zone));
+            }
+
+            served.push(Served {
+                origin: secondary.origin.clone(),
+                source: Source::Primary {
+                    primary: secondary.primary,
+                    key: config.server_key(secondary.primary.ip()).cloned(),
+                    file: secondary.file.clone(),
+                    refresh_at: now,
+                    expire_at,
+                },
+                zone: zone.map(Arc::new),
+                journal,
+            });
+        }
+
+        Ok(Zones {
+            served: RwLock::new(served),
+        })
+    }
+
+    fn served(&self) -> RwLockReadGuard<'_, Vec<Served>> {
+        self.served.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn served_mut(&self) -> RwLockWriteGuard<'_, Vec<Served>> {
+        self.served.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The most specific of our zones `qname` falls in, `None` inside if
+    /// we have no data for it: a secondary that was never transferred or
+    /// that expired.
+    fn find_served(&self, qname: &str) -> Option<Option<Arc<Zone>>> {
+        self.served()
+            .iter()
+            .filter(|served| in_bailiwick(qname, &served.origin))
+            .max_by_key(|served| served.origin.len())
+            .map(|served| served.zone.clone())
+    }
+
+    /// The most specific of our zones `qname` falls in, if we have its data.
+    pub fn find(&self, qname: &str) -> Option<Arc<Zone>> {
+        self.find_served(qname).flatten()
+    }
+
+    /// The SOA of our zone `origin`.
+    pub fn soa(&self, origin: &str) -> Option<DnsRecord> {
+        self.served()
+            .iter()
+            .find(|served| served.origin == origin)
+            .and_then(|served| Some(served.zone.as_ref()?.soa().clone()))
+    }
+
+    /// Have the secondary zone `origin` checked for a new version right
+    /// away, when `from` is its primary telling us it changed (RFC 1996),
+    /// signing with the key named `key` if we sign our requests to it.
+    /// Returns whether it was.
+    pub fn notified(&self, origin: &str, from: IpAddr, key: Option<&str>) -> bool {
+        let mut served = self.served_mut();
+        let Some(served) = served.iter_mut().find(|served| served.origin == origin) else {
+            return false;
+        };
+        match &mut served.source {
+            Source::Primary {
+                primary,
+                key: primary_key,
+                refresh_at,
+                ..
+            } if primary.ip() == from
+                && primary_key
+                    .as_ref()
+                    .is_none_or(|primary_key| Some(primary_key.name.as_str()) == key) =>
+            {
+                *refresh_at = Instant::now();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Answer `question` from our zones, if it falls in one of them.
+    pub fn answer(&self, question: &DnsQuestion) -> Option<DnsPacket> {
+        let mut packet = DnsPacket::new();
+        // the zone is ours even when we can't answer for it, so neither
+        // recursion nor a parent zone may answer instead
+        let Some(mut zone) = self.find_served(&question.name)? else {
+            packet.header.rescode = ResultCode::SERVFAIL;
+            return Some(packet);
+        };
+        packet.header.authoritative_answer = true;
+
+        let mut qname = question.name.clone();
+        for _ in 0..MAX_CNAME_CHAIN {
+            match zone.lookup(&qname, question.qtype) {
+                Lookup::Answer(records) => {
+                    packet.answers.extend(records.iter().cloned());
+
+                    let target = match records.as_slice() {
+                        [DnsRecord::CNAME { host, .. }] if question.qtype != QueryType::CNAME => {
+                            host.clone()
+                        }
+                        _ => break,
+                    };
+                    // chase the CNAME only as far as our own zones go
+                    match self.find(&target) {
+                        Some(next) => zone = next,
+                        None => break,
+                    }
+                    qname = target;
+                }
+                Lookup::Referral(ns, glue) => {
+                    // only the answers before the cut are ours
+                    packet.header.authoritative_answer = !packet.answers.is_empty();
+                    packet.authorities.extend(ns.into_iter().cloned());
+                    packet.resources.extend(glue.into_iter().cloned());
+                    break;
+                }
+                Lookup::NoData => {
+                    packet.authorities.push(zone.negative_soa());
+                    break;
+                }
+                Lookup::NxDomain => {
+                    packet.header.rescode = ResultCode::NXDOMAIN;
+                    packet.authorities.push(zone.negative_soa());
+                    break;
+                }
+            }
+        }
+
+        Some(packet)
+    }
+
+    /// The records transferring our zone `origin` to a client, either all
+    /// of them (RFC 5936) or, when the client has the version `serial` and
+    /// the journal goes back that far, the changes since (RFC 1995).
+    pub fn transfer(&self, origin: &str, serial: Option<u32>) -> Option<Vec<DnsRecord>> {
+        let served = self.served();
+        let served = served.iter().find(|served| served.origin == origin)?;
+        let zone = served.zone.as_ref()?;
+        let soa = zone.soa().clone();
+
+        if let Some(serial) = serial {
+            // the SOA alone tells a client that it's up to date
+            if !serial_newer(zone.serial(), serial) {
+                return Some(vec![soa]);
+            }
+            if let Some(deltas) = served.journal.since(serial) {
+                let mut records = vec![soa.clone()];
+                records.extend(deltas.flat_map(Delta::records).cloned());
+                records.push(soa);
+                return Some(records);
+            }
+        }
+
+        let mut records = vec![soa.clone()];
+        records.extend(
+            zone.iter()
+                .filter(|rec| rec.qtype() != QueryType::SOA)
+                .cloned(),
+        );
+        records.push(soa);
+        Some(records)
+    }
+
+    /// Make the changes of `update` to our zone if its prerequisites hold,
+    /// all of them or none (RFC 2136 section 3). Returns whether the zone
+    /// changed.
+    pub fn update(&self, update: &Update) -> std::result::Result<bool, ResultCode> {
+        let mut served = self.served_mut();
+        let Some(served) = served
+            .iter_mut()
+            .find(|served| served.origin == update.zone.name)
+        else {
+            return Err(ResultCode::NOTAUTH);
+        };
+        // secondary zones only change with their primary
+        let (Source::File { .. }, Some(zone)) = (&served.source, &served.zone) else {
+            return Err(ResultCode::REFUSED);
+        };
+
+        update.check(zone)?;
+        let Some(updated) = update.apply(zone)? else {
+            return Ok(false);
+        };
+
+        // the change only counts once it's safely in the journal
+        if let Err(err) = served.journal.append(Delta::between(zone, &updated)) {
+            eprintln!(
+                "Failed to write the journal of zone {}: {}",
+                served.origin, err
+            );
+            return Err(ResultCode::SERVFAIL);
+        }
+        println!(
+            "Updated zone {} to serial {}",
+            served.origin,
+            updated.serial()
+        );
+        served.zone = Some(Arc::new(updated));
+
+        Ok(true)
+    }
+
+    /// Read again the zone files changed since they were loaded, keeping
+    /// the changes in the journal. Returns the zones that changed.
+    pub fn reload(&self) -> Vec<String> {
+        let changed = self
+            .served()
+            .iter()
+            .enumerate()
+            .filter_map(|(i, served)| match &served.source {
+                Source::File { file, modified: at } if modified(file) != *at => {
+                    Some((i, served.origin.clone(), file.clone()))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        let mut reloaded = Vec::new();
+        for (i, origin, file) in changed {
+            let now_modified = modified(&file);
+            let zone = load_zone(&origin, &file);
+
+            let mut served = self.served_mut();
+            let served = &mut served[i];
+            if let Source::File { modified, .. } = &mut served.source {
+                *modified = now_modified;
+            }
+            let zone = match zone {
+                Ok(zone) => zone,
+                Err(err) => {
+                    eprintln!("Failed to reload zone {}: {}", origin, err);
+                    continue;
+                }
+            };
+            let Some(old) = &served.zone else {
+                continue;
+            };
+            if !serial_newer(zone.serial(), old.serial()) {
+                eprintln!(
+                    "Zone {} changed without its serial increasing, keeping serial {}",
+                    origin,
+                    old.serial()
+                );
+                continue;
+            }
+
+            let delta = Delta::between(old, &zone);
+            if let Err(err) = served.journal.append(delta) {
+                eprintln!("Failed to write the journal of zone {}: {}", origin, err);
+            }
+            println!("Reloaded zone {} at serial {}", origin, zone.serial());
+            served.zone = Some(Arc::new(zone));
+            reloaded.push(origin);
+        }
+
+        reloaded
+    }
+
+    /// Check the primaries of the secondary zones due for a refresh, and
+    /// transfer the zones that changed. Returns the zones that changed.
+    pub async fn refresh(&self) -> Vec<String> {
+        let now = Instant::now();
+
+        // a zone expires on time however long its primary takes to answer
+        for served in self.served_mut().iter_mut() {
+            if let Source::Primary { expire_at, .. } = served.source {
+                if now >= expire_at && served.zone.take().is_some() {
+                    eprintln!("Zone {} expired, failing queries for it", served.origin);
+                }
+            }
+        }
+
+        let due = self
+            .served()
+            .iter()
+            .enumerate()
+            .filter_map(|(i, served)| match &served.source {
+                Source::Primary {
+                    primary,
+                    key,
+                    refresh_at,
+                    ..
+                } if *refresh_at <= now => Some((
+                    i,
+                    served.origin.clone(),
+                    *primary,
+                    key.clone(),
+                    served.zone.clone(),
+                )),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        let mut refreshed = Vec::new();
+        for (i, origin, primary, key, current) in due {
+            let update = fetch_zone(&origin, primary, key.as_ref(), current.as_deref()).await;
+
+            let mut served = self.served_mut();
+            let served = &mut served[i];
+            let Source::Primary {
+                file,
+                refresh_at,
+                expire_at,
+                ..
+            } = &mut served.source
+            else {
+                continue;
+            };
+
+            let (zone, deltas) = match update {
+                Ok(Some(update)) => update,
+                Ok(None) => {
+                    if let Some(zone) = &served.zone {
+                        let (refresh, _, expire) = zone.timers();
+                        *refresh_at = now + refresh;
+                        *expire_at = now + expire;
+                    }
+                    continue;
+                }
+                Err(err) => {
+                    eprintln!(
+                        "Failed to refresh zone {} from {}: {}",
+                        origin, primary, err
+                    );
+                    *refresh_at = now
+                        + served
+                            .zone
+                            .as_ref()
+                            .map_or(INITIAL_RETRY, |zone| zone.timers().1);
+                    continue;
+                }
+            };
+
+            let (refresh, _, expire) = zone.timers();
+            *refresh_at = now + refresh;
+            *expire_at = now + expire;
+
+            if let Some(file) = file {
+                if let Err(err) = fs::write(&*file, zonefile::write(zone.iter())) {
+                    eprintln!("Failed to save zone {} to {}: {}", origin, file, err);
+                }
+            }
+            let journaled = match deltas {
+                Some(deltas) => deltas
+                    .into_iter()
+                    .try_for_each(|delta| served.journal.append(delta)),
+                None => served.journal.clear(),
+            };
+            if let Err(err) = journaled {
+                eprintln!("Failed to write the journal of zone {}: {}", origin, err);
+            }
+
+            println!(
+                "Transferred zone {} from {} at serial {}",
+                origin,
+                primary,
+                zone.serial()
+            );
+            served.zone = Some(Arc::new(zone));
+            refreshed.push(origin);
+        }
+
+        refreshed
+    }
+}
+
+/// The latest version of the zone `origin` from `primary` if it's newer
+/// than `current`, along with the changes since `current` unless they're
+/// unknown.
+///
+/// Only the changes are transferred if the primary can, the whole zone
+/// otherwise. Requests are signed with `key` if given.
+async fn fetch_zone(
+    origin: &str,
+    primary: SocketAddr,
+    key: Option<&Key>,
+    current: Option<&Zone>,
+) -> Result<Option<(Zone, Option<Vec<Delta>>)>> {
+    let Some(current) = current else {
+        return Ok(Some((transfer_zone(origin, primary, key).await?, None)));
+    };
+
+    let response =
+        resolver::lookup(origin, QueryType::SOA, (primary.ip(), primary.port()), key).await?;
+    let serial = response
+        .answers
+        .iter()
+        .find_map(|rec| match rec {
+            DnsRecord::SOA { domain, serial, .. } if domain == origin => Some(*serial),
+            _ => None,
+        })
+        .ok_or_else(|| format!("{} has no SOA for {}", primary, origin))?;
+    if !serial_newer(serial, current.serial()) {
+        return Ok(None);
+    }
+
+    let incremental = transfer::fetch(primary, origin, Some(current.serial()), key)
+        .await
+        .and_then(|transfer| match transfer {
+            Transfer::UpToDate => Ok(None),
+            Transfer::Full(records) => {
+                let zone = Zone::new(origin, records)?;
+                let delta = Delta::between(current, &zone);
+                Ok(Some((zone, Some(vec![delta]))))
+            }
+            Transfer::Incremental(deltas) => {
+                let zone = current.apply(&deltas)?;
+                Ok(Some((zone, Some(deltas))))
+            }
+        });
+    match incremental {
+        Ok(update) => Ok(update),
+        Err(err) => {
+            eprintln!(
+                "IXFR of {} from {} failed, trying AXFR: {}",
+                origin, primary, err
+            );
+            let zone = transfer_zone(origin, primary, key).await?;
+            let delta = Delta::between(current, &zone);
+            Ok(Some((zone, Some(vec![delta]))))
+        }
+    }
+}
+
+/// The whole zone `origin`, transferred from `primary` with AXFR.
+async fn transfer_zone(origin: &str, primary: SocketAddr, key: Option<&Key>) -> Result<Zone> {
+    match transfer::fetch(primary, origin, None, key).await? {
+        Transfer::Full(records) => Zone::new(origin, records),
+        _ => Err(format!("{} didn't send the whole zone {}", primary, origin).into()),
+    }
+}
+
+fn load_zone(origin: &str, file: &str) -> Result<Zone> {
+    let records = zonefile::load(file, origin)?;
+    Zone::new(origin, records).map_err(|err| format!("Zone {}: {}", file, err).into())
+}
+
+fn modified(file: &str) -> Option<SystemTime> {
+    fs::metadata(file).and_then(|meta| meta.modified()).ok()
+}
+
+/// Where the changes of the zone in `file` are kept.
+fn journal_path(file: &str) -> String {
+    format!("{}.jnl", file)
+}
+
+/// Whether serial `a` comes after serial `b`, serials wrapping around
+/// (RFC 1982).
+pub fn serial_newer(a: u32, b: u32) -> bool {
+    a != b && (a.wrapping_sub(b) as i32) > 0
+}
+
+/// `rdns zone <origin> <file>`, to check a zone file and print it in
+/// canonical form.
+pub fn run_command(args: &[String]) -> Result<()> {
+    let [origin, file] = args else {
+        return Err("Usage: rdns zone <origin> <file>".into());
+    };
+
+    let origin = parse_name(origin);
+    let zone = Zone::new(&origin, zonefile::load(file, &origin)?)?;
+    print!("{}", zonefile::write(zone.iter()));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ns(domain: &str) -> DnsRecord {
+        DnsRecord::NS {
+            domain: domain.to_owned(),
+            host: format!("ns.{}", domain),
+            ttl: 3600,
+        }
+    }
+
+    fn soa(origin: &str) -> DnsRecord {
+        DnsRecord::SOA {
+            domain: origin.to_owned(),
+            mname: format!("ns.{}", origin),
+            rname: format!("hostmaster.{}", origin),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 300,
+            ttl: 3600,
+        }
+    }
+
+    fn a(domain: &str) -> DnsRecord {
+        DnsRecord::A {
+            domain: domain.to_owned(),
+            addr: "192.0.2.1".parse().unwrap(),
+            ttl: 3600,
+        }
+    }
+
+    /// example.com from a file, and its child sub.example.com as a
+    /// secondary without data.
+    fn zones() -> Zones {
+        let parent = Zone::new(
+            "example.com",
+            vec![
+                soa("example.com"),
+                ns("example.com"),
+                a("www.example.com"),
+                ns("sub.example.com"),
+            ],
+        )
+        .unwrap();
+
+        let now = Instant::now();
+        Zones {
+            served: RwLock::new(vec![
+                Served {
+                    origin: "example.com".to_owned(),
+                    source: Source::File {
+                        file: "example.com.zone".to_owned(),
+                        modified: None,
+                    },
+                    zone: Some(Arc::new(parent)),
+                    journal: Journal::new(),
+                },
+                Served {
+                    origin: "sub.example.com".to_owned(),
+                    source: Source::Primary {
+                        primary: "192.0.2.53:53".parse().unwrap(),
+                        key: None,
+                        file: None,
+                        refresh_at: now,
+                        expire_at: now,
+                    },
+                    zone: None,
+                    journal: Journal::new(),
+                },
+            ]),
+        }
+    }
+
+    fn answer(zones: &Zones, name: &str) -> Option<DnsPacket> {
+        zones.answer(&DnsQuestion::new(name, QueryType::A))
+    }
+
+    #[test]
+    fn secondary_without_data_fails() {
+        let zones = zones();
+
+        let packet = answer(&zones, "www.example.com").unwrap();
+        assert_eq!(packet.answers, [a("www.example.com")]);
+
+        // neither the parent's referral nor recursion answers for it
+        let packet = answer(&zones, "www.sub.example.com").unwrap();
+        assert_eq!(packet.header.rescode, ResultCode::SERVFAIL);
+        assert!(!packet.header.authoritative_answer);
+        assert!(packet.authorities.is_empty());
+        assert!(zones.find("www.sub.example.com").is_none());
+
+        assert!(answer(&zones, "www.example.org").is_none());
+    }
+
+    #[test]
+    fn secondary_answers_once_transferred() {
+        let zones = zones();
+        let sub = Zone::new(
+            "sub.example.com",
+            vec![
+                soa("sub.example.com"),
+                ns("sub.example.com"),
+                a("www.sub.example.com"),
+            ],
+        )
+        .unwrap();
+        zones.served_mut()[1].zone = Some(Arc::new(sub));
+
+        let packet = answer(&zones, "www.sub.example.com").unwrap();
+        assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+        assert_eq!(packet.answers, [a("www.sub.example.com")]);
+
+        // and fails again once expired
+        zones.served_mut()[1].zone = None;
+        let packet = answer(&zones, "www.sub.example.com").unwrap();
+        assert_eq!(packet.header.rescode, ResultCode::SERVFAIL);
+    }
+
+    #[tokio::test]
+    async fn secondary_expires_before_refresh_is_due() {
+        let zones = zones();
+        let sub = Zone::new(
+            "sub.example.com",
+            vec![soa("sub.example.com"), ns("sub.example.com")],
+        )
+        .unwrap();
+        {
+            let mut served = zones.served_mut();
+            served[1].zone = Some(Arc::new(sub));
+            // the primary isn't due to be asked, and isn't there anyway
+            if let Source::Primary { refresh_at, .. } = &mut served[1].source {
+                *refresh_at = Instant::now() + Duration::from_secs(3600);
+            }
+        }
+
+        assert!(zones.refresh().await.is_empty());
+        let packet = answer(&zones, "www.sub.example.com").unwrap();
+        assert_eq!(packet.header.rescode, ResultCode::SERVFAIL);
+        // and the parent zone from its file never expires
+        assert!(zones.find("www.example.com").is_some());
+    }
+
+    #[test]
+    fn notify_from_primary_schedules_refresh() {
+        let zones = zones();
+        let later = Instant::now() + Duration::from_secs(3600);
+        let refresh_at = |zones: &Zones| match zones.served()[1].source {
+            Source::Primary { refresh_at, .. } => refresh_at,
+            _ => unreachable!(),
+        };
+        if let Source::Primary { refresh_at, .. } = &mut zones.served_mut()[1].source {
+            *refresh_at = later;
+        }
+
+        let primary = "192.0.2.53".parse().unwrap();
+        assert!(!zones.notified("sub.example.com", "192.0.2.54".parse().unwrap(), None));
+        assert!(!zones.notified("example.com", primary, None));
+        assert!(!zones.notified("other.example.com", primary, None));
+        assert_eq!(refresh_at(&zones), later);
+
+        assert!(zones.notified("sub.example.com", primary, None));
+        assert!(refresh_at(&zones) <= Instant::now());
+    }
+
+    /// A zone of wildcards: `*.example.com` and `*.deep.example.com` with
+    /// addresses of their own, a wildcard CNAME, `a.example.com` existing
+    /// on its own and `y.example.com` an empty non-terminal.
+    fn wildcards() -> Zone {
+        let wildcard = |domain: &str, addr: &str| DnsRecord::A {
+            domain: domain.to_owned(),
+            addr: addr.parse().unwrap(),
+            ttl: 3600,
+        };
+        Zone::new(
+            "example.com",
+            vec![
+                soa("example.com"),
+                ns("example.com"),
+                a("www.example.com"),
+                wildcard("*.example.com", "192.0.2.2"),
+                wildcard("*.deep.example.com", "192.0.2.3"),
+                DnsRecord::CNAME {
+                    domain: "*.alias.example.com".to_owned(),
+                    host: "www.example.com".to_owned(),
+                    ttl: 3600,
+                },
+                DnsRecord::MX {
+                    domain: "a.example.com".to_owned(),
+                    priority: 10,
+                    host: "www.example.com".to_owned(),
+                    ttl: 3600,
+                },
+                a("x.y.example.com"),
+            ],
+        )
+        .unwrap()
+    }
+
+    fn addrs(lookup: Lookup) -> Vec<(String, Option<IpAddr>)> {
+        match lookup {
+            Lookup::Answer(records) => records
+                .iter()
+                .map(|rec| (rec.domain().to_owned(), rec.addr()))
+                .collect(),
+            _ => panic!("Expected an answer"),
+        }
+    }
+
+    #[test]
+    fn wildcard_answers_with_qname_as_owner() {
+        let zone = wildcards();
+
+        assert_eq!(
+            addrs(zone.lookup("foo.example.com", QueryType::A)),
+            [("foo.example.com".to_owned(), "192.0.2.2".parse().ok())]
+        );
+        // only the closest encloser's wildcard applies
+        assert_eq!(
+            addrs(zone.lookup("foo.bar.deep.example.com", QueryType::A)),
+            [(
+                "foo.bar.deep.example.com".to_owned(),
+                "192.0.2.3".parse().ok()
+            )]
+        );
+        assert_eq!(
+            addrs(zone.lookup("foo.bar.example.com", QueryType::A)),
+            [("foo.bar.example.com".to_owned(), "192.0.2.2".parse().ok())]
+        );
+    }
+
+    #[test]
+    fn wildcard_blocked_by_existing_names() {
+        let zone = wildcards();
+
+        // a.example.com exists, so its missing A isn't made up
+        assert!(matches!(
+            zone.lookup("a.example.com", QueryType::A),
+            Lookup::NoData
+        ));
+        // and neither are names below it, which have no wildcard of their own
+        assert!(matches!(
+            zone.lookup("b.a.example.com", QueryType::A),
+            Lookup::NxDomain
+        ));
+
+        // an empty non-terminal exists too, with nothing to synthesize from
+        // below it
+        assert!(matches!(
+            zone.lookup("y.example.com", QueryType::A),
+            Lookup::NoData
+        ));
+        assert!(matches!(
+            zone.lookup("z.y.example.com", QueryType::A),
+            Lookup::NxDomain
+        ));
+    }
+
+    #[test]
+    fn wildcard_cname_and_nodata() {
+        let zones = Zones {
+            served: RwLock::new(vec![Served {
+                origin: "example.com".to_owned(),
+                source: Source::File {
+                    file: "example.com.zone".to_owned(),
+                    modified: None,
+                },
+                zone: Some(Arc::new(wildcards())),
+                journal: Journal::new(),
+            }]),
+        };
+
+        let packet = answer(&zones, "foo.alias.example.com").unwrap();
+        assert_eq!(
+            packet.answers,
+            [
+                DnsRecord::CNAME {
+                    domain: "foo.alias.example.com".to_owned(),
+                    host: "www.example.com".to_owned(),
+                    ttl: 3600,
+                },
+                a("www.example.com")
+            ]
+        );
+
+        // the wildcard has no MX, which is proven with the SOA
+        let packet = zones
+            .answer(&DnsQuestion::new("foo.example.com", QueryType::MX))
+            .unwrap();
+        assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+        assert!(packet.header.authoritative_answer);
+        assert!(packet.answers.is_empty());
+        assert!(matches!(
+            packet.authorities.as_slice(),
+            [DnsRecord::SOA { domain, ttl: 300, .. }] if domain == "example.com"
+        ));
+    }
+
+    fn with_serial(mut soa: DnsRecord, new_serial: u32) -> DnsRecord {
+        if let DnsRecord::SOA { serial, .. } = &mut soa {
+            *serial = new_serial;
+        }
+        soa
+    }
+
+    #[test]
+    fn transfers_deltas_or_whole_zone() {
+        let old = Zone::new(
+            "example.com",
+            vec![soa("example.com"), ns("example.com"), a("www.example.com")],
+        )
+        .unwrap();
+        let new = Zone::new(
+            "example.com",
+            vec![
+                with_serial(soa("example.com"), 2),
+                ns("example.com"),
+                a("mail.example.com"),
+            ],
+        )
+        .unwrap();
+        let mut journal = Journal::new();
+        journal.append(Delta::between(&old, &new)).unwrap();
+        let zones = Zones {
+            served: RwLock::new(vec![Served {
+                origin: "example.com".to_owned(),
+                source: Source::File {
+                    file: "example.com.zone".to_owned(),
+                    modified: None,
+                },
+                zone: Some(Arc::new(new)),
+                journal,
+            }]),
+        };
+        let soa2 = with_serial(soa("example.com"), 2);
+
+        // up to date
+        assert_eq!(
+            zones.transfer("example.com", Some(2)).unwrap(),
+            std::slice::from_ref(&soa2)
+        );
+
+        // the journal goes back to serial 1
+        assert_eq!(
+            zones.transfer("example.com", Some(1)).unwrap(),
+            [
+                soa2.clone(),
+                soa("example.com"),
+                a("www.example.com"),
+                soa2.clone(),
+                a("mail.example.com"),
+                soa2.clone(),
+            ]
+        );
+
+        // but not to serial 0, nor does a client without the zone get
+        // anything but the whole of it
+        let full = [
+            soa2.clone(),
+            ns("example.com"),
+            a("mail.example.com"),
+            soa2.clone(),
+        ];
+        assert_eq!(zones.transfer("example.com", Some(0)).unwrap(), full);
+        assert_eq!(zones.transfer("example.com", None).unwrap(), full);
+
+        assert!(zones.transfer("example.org", None).is_none());
+    }
+}
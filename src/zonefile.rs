@@ -0,0 +1,947 @@
+use crate::config::parse_hex;
+use crate::dnssec::{base32hex_decode, canonical_cmp};
+use crate::packet::*;
+use std::{fs, iter::Peekable, ops::Deref, path::Path, str::Chars};
+
+/// Files nested through `$INCLUDE`, to catch a file including itself.
+const MAX_INCLUDE_DEPTH: usize = 8;
+/// Records a single `$GENERATE` may produce.
+const MAX_GENERATED: usize = 65536;
+
+/// Read the records of the master file at `path` (RFC 1035 section 5),
+/// names being relative to `origin` until a `$ORIGIN` says otherwise.
+///
+/// Records without a TTL get the one of `$TTL` (RFC 2308), or else the TTL
+/// of the record before them. Errors tell the file and line they're on.
+pub fn load(path: &str, origin: &str) -> Result<Vec<DnsRecord>> {
+    let mut parser = Parser {
+        records: Vec::new(),
+        default_ttl: None,
+        last_ttl: None,
+    };
+    parser.read_file(Path::new(path), origin, 0)?;
+
+    Ok(parser.records)
+}
+
+/// `records` as a master file, the SOA first and then every record in
+/// canonical order (RFC 4034 section 6), so that the same data always
+/// reads the same and zones can be diffed.
+pub fn write<'a>(records: impl IntoIterator<Item = &'a DnsRecord>) -> String {
+    let mut records = records
+        .into_iter()
+        .map(|record| (record, record.rdata().unwrap_or_default()))
+        .collect::<Vec<_>>();
+    records.sort_by(|(a, a_rdata), (b, b_rdata)| {
+        (b.qtype() == QueryType::SOA)
+            .cmp(&(a.qtype() == QueryType::SOA))
+            .then_with(|| canonical_cmp(a.domain(), b.domain()))
+            .then_with(|| a.qtype().to_num().cmp(&b.qtype().to_num()))
+            .then_with(|| a_rdata.cmp(b_rdata))
+    });
+
+    records
+        .iter()
+        .map(|(record, _)| format!("{}\n", record))
+        .collect()
+}
+
+struct Parser {
+    records: Vec<DnsRecord>,
+    default_ttl: Option<u32>,
+    last_ttl: Option<u32>,
+}
+
+impl Parser {
+    fn read_file(&mut self, path: &Path, origin: &str, depth: usize) -> Result<()> {
+        let content =
+            fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+
+        // `$ORIGIN` and the owner carried over to the next record only hold
+        // until the end of the file
+        let mut origin = origin.to_owned();
+        let mut owner = None;
+
+        let entries = tokenize(&content)
+            .map_err(|(line, err)| format!("{}:{}: {}", path.display(), line, err))?;
+        for entry in entries {
+            self.read_entry(path, &entry, &mut origin, &mut owner, depth)
+                .map_err(|err| format!("{}:{}: {}", path.display(), entry.line, err))?;
+        }
+
+        Ok(())
+    }
+
+    fn read_entry(
+        &mut self,
+        path: &Path,
+        entry: &Entry,
+        origin: &mut String,
+        owner: &mut Option<String>,
+        depth: usize,
+    ) -> Result<()> {
+        let words = &entry.words;
+        let directive = match words.first() {
+            Some(word) if word.starts_with('$') && !word.quoted && !entry.blank_owner => {
+                word.to_ascii_uppercase()
+            }
+            _ => {
+                let record = self.parse_record(words, entry.blank_owner, origin, owner)?;
+                self.records.push(record);
+                return Ok(());
+            }
+        };
+
+        match (directive.as_str(), &words[1..]) {
+            ("$ORIGIN", [name]) => *origin = parse_name(name, origin)?,
+            ("$TTL", [ttl]) => self.default_ttl = Some(parse_ttl(ttl)?),
+            ("$INCLUDE", [file, rest @ ..]) if rest.len() <= 1 => {
+                if depth >= MAX_INCLUDE_DEPTH {
+                    return Err("Too many nested $INCLUDE".into());
+                }
+                let file = path.parent().unwrap_or(Path::new("")).join(&**file);
+                let included_origin = match rest {
+                    [name] => parse_name(name, origin)?,
+                    _ => origin.clone(),
+                };
+                self.read_file(&file, &included_origin, depth + 1)?;
+            }
+            ("$GENERATE", [range, lhs, rest @ ..]) if !rest.is_empty() => {
+                self.generate(range, lhs, rest, origin, owner)?
+            }
+            ("$ORIGIN" | "$TTL" | "$INCLUDE" | "$GENERATE", _) => {
+                return Err(format!("Invalid {} directive", directive).into())
+            }
+            _ => return Err(format!("Unknown directive {}", directive).into()),
+        }
+
+        Ok(())
+    }
+
+    /// `$GENERATE start-stop[/step] lhs [ttl] [class] type rhs`, as BIND
+    /// has it, `$` in the owner and data standing for each number in turn.
+    fn generate(
+        &mut self,
+        range: &str,
+        lhs: &Word,
+        rest: &[Word],
+        origin: &str,
+        owner: &mut Option<String>,
+    ) -> Result<()> {
+        let (range, step) = range.split_once('/').unwrap_or((range, "1"));
+        let (start, stop) = range
+            .split_once('-')
+            .ok_or_else(|| format!("Invalid range {}", range))?;
+        let (start, stop, step) = (start.parse::<i64>()?, stop.parse::<i64>()?, step.parse()?);
+        if start > stop || step == 0 || (stop - start) as usize / step >= MAX_GENERATED {
+            return Err(format!("Invalid range {}", range).into());
+        }
+
+        let Some((rhs, middle)) = rest.split_last() else {
+            return Err("Missing record data".into());
+        };
+        for i in (start..=stop).step_by(step) {
+            let mut words = vec![lhs.with_text(expand(lhs, i)?)];
+            words.extend(middle.iter().cloned());
+            words.push(rhs.with_text(expand(rhs, i)?));
+
+            let record = self.parse_record(&words, false, origin, owner)?;
+            self.records.push(record);
+        }
+
+        Ok(())
+    }
+
+    fn parse_record(
+        &mut self,
+        words: &[Word],
+        blank_owner: bool,
+        origin: &str,
+        owner: &mut Option<String>,
+    ) -> Result<DnsRecord> {
+        let mut rest = match (blank_owner, words) {
+            (true, rest) => rest,
+            (false, [name, rest @ ..]) => {
+                *owner = Some(parse_name(name, origin)?);
+                rest
+            }
+            (false, []) => return Err("Expected a record".into()),
+        };
+        let domain = owner.clone().ok_or("Missing owner name")?;
+
+        // the TTL and class come in either order, both optional
+        let mut ttl = None;
+        loop {
+            match rest {
+                [word, tail @ ..] if word.eq_ignore_ascii_case("IN") => rest = tail,
+                [word, ..] if ["CH", "HS", "CS"].contains(&word.to_ascii_uppercase().as_str()) => {
+                    return Err(format!("Unsupported class {}", word).into())
+                }
+                [word, tail @ ..]
+                    if ttl.is_none() && word.starts_with(|c: char| c.is_ascii_digit()) =>
+                {
+                    ttl = Some(parse_ttl(word)?);
+                    rest = tail;
+                }
+                _ => break,
+            }
+        }
+        if ttl.is_some() {
+            self.last_ttl = ttl;
+        }
+        let ttl = ttl
+            .or(self.default_ttl)
+            .or(self.last_ttl)
+            .ok_or("Missing TTL, and no $TTL to default to")?;
+
+        let [qtype, rdata @ ..] = rest else {
+            return Err("Missing record type".into());
+        };
+        let qtype = QueryType::from_name(qtype).ok_or_else(|| format!("Unknown type {}", qtype))?;
+
+        parse_rdata(domain, ttl, qtype, rdata, origin)
+    }
+}
+
+/// The words of an entry, which is a line or several when parentheses
+/// continue it.
+struct Entry {
+    line: usize,
+    /// The line starts with a blank, so the entry reuses the last owner.
+    blank_owner: bool,
+    words: Vec<Word>,
+}
+
+/// A word of an entry, its quotes removed. A quoted word is never `@` for
+/// the origin or a directive, and may be empty.
+#[derive(Clone)]
+struct Word {
+    text: String,
+    quoted: bool,
+}
+
+impl Word {
+    fn with_text(&self, text: String) -> Word {
+        Word {
+            text,
+            quoted: self.quoted,
+        }
+    }
+}
+
+impl std::fmt::Display for Word {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl Deref for Word {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.text
+    }
+}
+
+/// Split a master file into entries, dropping comments and unquoting
+/// quoted strings. Escapes within words are kept for the fields to decode.
+fn tokenize(content: &str) -> std::result::Result<Vec<Entry>, (usize, String)> {
+    let mut entries = Vec::new();
+    let mut words = Vec::new();
+    let mut line = 1;
+    let mut entry_line = 1;
+    let mut depth = 0;
+    let mut line_start = true;
+    let mut blank_owner = false;
+
+    let mut chars = content.chars().peekable();
+    while let Some(c) = chars.next() {
+        let at_line_start = line_start;
+        line_start = false;
+
+        match c {
+            '\n' => {
+                line += 1;
+                line_start = true;
+                if depth == 0 && !words.is_empty() {
+                    entries.push(Entry {
+                        line: entry_line,
+                        blank_owner,
+                        words: std::mem::take(&mut words),
+                    });
+                }
+            }
+            ' ' | '\t' | '\r' => {
+                if at_line_start && depth == 0 {
+                    blank_owner = true;
+                }
+            }
+            ';' => while chars.next_if(|c| *c != '\n').is_some() {},
+            '(' => depth += 1,
+            ')' if depth == 0 => return Err((line, "Unbalanced parenthesis".into())),
+            ')' => depth -= 1,
+            _ => {
+                if at_line_start && depth == 0 {
+                    blank_owner = false;
+                }
+                if words.is_empty() {
+                    entry_line = line;
+                }
+
+                let mut word = String::new();
+                let quoted = c == '"';
+                if quoted {
+                    loop {
+                        match chars.next() {
+                            None => return Err((line, "Unterminated quoted string".into())),
+                            Some('"') => break,
+                            Some('\\') => {
+                                word.push('\\');
+                                word.extend(chars.next());
+                            }
+                            Some(c) => {
+                                line += (c == '\n') as usize;
+                                word.push(c);
+                            }
+                        }
+                    }
+                } else {
+                    let mut c = Some(c);
+                    while let Some(next) = c {
+                        word.push(next);
+                        if next == '\\' {
+                            word.extend(chars.next());
+                        }
+                        c = chars.next_if(|c| !c.is_whitespace() && !"();\"".contains(*c));
+                    }
+                }
+                words.push(Word { text: word, quoted });
+            }
+        }
+    }
+
+    if depth != 0 {
+        return Err((line, "Unbalanced parenthesis".into()));
+    }
+    if !words.is_empty() {
+        entries.push(Entry {
+            line: entry_line,
+            blank_owner,
+            words,
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Add the byte escaped by `\X` or `\DDD` to `label`, the backslash
+/// already read.
+fn unescape(chars: &mut Peekable<Chars>, label: &mut Vec<u8>) -> Result<()> {
+    let Some(c) = chars.next() else {
+        return Err("Dangling escape".into());
+    };
+    if !c.is_ascii_digit() {
+        label.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+        return Ok(());
+    }
+
+    let mut value = c.to_digit(10).unwrap_or_default();
+    for _ in 0..2 {
+        let digit = chars
+            .next()
+            .and_then(|c| c.to_digit(10))
+            .ok_or("Expected three digits after \\")?;
+        value = value * 10 + digit;
+    }
+    let byte = u8::try_from(value).map_err(|_| format!("Invalid escape \\{}", value))?;
+    label.push(byte);
+
+    Ok(())
+}
+
+/// `name` made absolute, lowercase and without its trailing dot.
+fn parse_name(name: &Word, origin: &str) -> Result<String> {
+    if &**name == "@" && !name.quoted {
+        return Ok(origin.to_owned());
+    }
+    let name: &str = name;
+    if name == "." {
+        return Ok(String::new());
+    }
+
+    let mut labels = Vec::new();
+    let mut label = Vec::new();
+    let mut absolute = false;
+    let mut chars = name.chars().peekable();
+    while let Some(c) = chars.next() {
+        absolute = false;
+        match c {
+            '.' if label.is_empty() => return Err(format!("Empty label in {}", name).into()),
+            '.' => {
+                labels.push(escape_label(
+                    &std::mem::take(&mut label).to_ascii_lowercase(),
+                ));
+                absolute = true;
+            }
+            '\\' => unescape(&mut chars, &mut label)?,
+            c => label.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+        if label.len() > 63 {
+            return Err(format!("Label too long in {}", name).into());
+        }
+    }
+    if !label.is_empty() {
+        labels.push(escape_label(&label.to_ascii_lowercase()));
+    }
+    if !absolute && !origin.is_empty() {
+        labels.push(origin.to_owned());
+    }
+
+    let name = labels.join(".");
+    if name_len(&name) > 255 {
+        return Err(format!("Name too long: {}", name).into());
+    }
+
+    Ok(name)
+}
+
+/// A TTL in seconds, or with units as in `1h30m`.
+fn parse_ttl(ttl: &str) -> Result<u32> {
+    if let Ok(seconds) = ttl.parse() {
+        return Ok(seconds);
+    }
+
+    let mut total = 0u32;
+    let mut number = None;
+    for c in ttl.chars() {
+        if let Some(digit) = c.to_digit(10) {
+            number = Some(
+                number
+                    .unwrap_or(0u32)
+                    .saturating_mul(10)
+                    .saturating_add(digit),
+            );
+            continue;
+        }
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 24 * 3600,
+            'w' => 7 * 24 * 3600,
+            _ => return Err(format!("Invalid TTL {}", ttl).into()),
+        };
+        let count = number
+            .take()
+            .ok_or_else(|| format!("Invalid TTL {}", ttl))?;
+        total = total.saturating_add(count.saturating_mul(unit));
+    }
+    if number.is_some() {
+        return Err(format!("Invalid TTL {}", ttl).into());
+    }
+
+    Ok(total)
+}
+
+/// Replace `$` in a `$GENERATE` template with `i`, `${offset,width,base}`
+/// giving its format and `\$` a literal dollar.
+fn expand(template: &str, i: i64) -> Result<String> {
+    let mut expanded = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&'$') => expanded.extend(chars.next()),
+            '$' if chars.next_if_eq(&'{').is_some() => {
+                let mut spec = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => spec.push(c),
+                        None => return Err(format!("Unterminated ${{ in {}", template).into()),
+                    }
+                }
+                let fields = spec.split(',').collect::<Vec<_>>();
+                let (offset, width, base) = match fields.as_slice() {
+                    [offset] => (offset.parse::<i64>()?, 0, "d"),
+                    [offset, width] => (offset.parse()?, width.parse()?, "d"),
+                    [offset, width, base] => (offset.parse()?, width.parse()?, *base),
+                    _ => return Err(format!("Invalid ${{{}}}", spec).into()),
+                };
+                let value = i + offset;
+                expanded += &match base {
+                    "d" => format!("{:0width$}", value, width = width),
+                    "o" => format!("{:0width$o}", value, width = width),
+                    "x" => format!("{:0width$x}", value, width = width),
+                    "X" => format!("{:0width$X}", value, width = width),
+                    _ => return Err(format!("Invalid base {}", base).into()),
+                };
+            }
+            '$' => expanded += &i.to_string(),
+            c => expanded.push(c),
+        }
+    }
+
+    Ok(expanded)
+}
+
+fn parse_rdata(
+    domain: String,
+    ttl: u32,
+    qtype: QueryType,
+    rdata: &[Word],
+    origin: &str,
+) -> Result<DnsRecord> {
+    let record = match (qtype, rdata) {
+        (_, [escape, len, hex @ ..]) if &**escape == "\\#" && !escape.quoted => {
+            let data = parse_hex(&concat(hex))?;
+            if data.len() != len.parse::<usize>()? {
+                return Err(format!("Expected {} bytes of data", len).into());
+            }
+            return from_wire(&domain, ttl, qtype, &data);
+        }
+        (QueryType::A, [addr]) => DnsRecord::A {
+            domain,
+            addr: addr.parse()?,
+            ttl,
+        },
+        (QueryType::AAAA, [addr]) => DnsRecord::AAAA {
+            domain,
+            addr: addr.parse()?,
+            ttl,
+        },
+        (QueryType::NS, [host]) => DnsRecord::NS {
+            domain,
+            host: parse_name(host, origin)?,
+            ttl,
+        },
+        (QueryType::CNAME, [host]) => DnsRecord::CNAME {
+            domain,
+            host: parse_name(host, origin)?,
+            ttl,
+        },
+        (QueryType::MX, [priority, host]) => DnsRecord::MX {
+            domain,
+            priority: priority.parse()?,
+            host: parse_name(host, origin)?,
+            ttl,
+        },
+        (QueryType::TXT, strings) if !strings.is_empty() => DnsRecord::TXT {
+            domain,
+            data: strings
+                .iter()
+                .map(|string| parse_string(string))
+                .collect::<Result<_>>()?,
+            ttl,
+        },
+        (QueryType::SOA, [mname, rname, serial, refresh, retry, expire, minimum]) => {
+            DnsRecord::SOA {
+                domain,
+                mname: parse_name(mname, origin)?,
+                rname: parse_name(rname, origin)?,
+                serial: serial.parse()?,
+                refresh: parse_ttl(refresh)?,
+                retry: parse_ttl(retry)?,
+                expire: parse_ttl(expire)?,
+                minimum: parse_ttl(minimum)?,
+                ttl,
+            }
+        }
+        (QueryType::DS, [key_tag, algorithm, digest_type, digest @ ..]) if !digest.is_empty() => {
+            DnsRecord::DS {
+                domain,
+                key_tag: key_tag.parse()?,
+                algorithm: algorithm.parse()?,
+                digest_type: digest_type.parse()?,
+                digest: parse_hex(&concat(digest))?,
+                ttl,
+            }
+        }
+        (QueryType::DNSKEY, [flags, protocol, algorithm, key @ ..]) if !key.is_empty() => {
+            DnsRecord::DNSKEY {
+                domain,
+                flags: flags.parse()?,
+                protocol: protocol.parse()?,
+                algorithm: algorithm.parse()?,
+                public_key: base64_decode(&concat(key))?,
+                ttl,
+            }
+        }
+        (
+            QueryType::RRSIG,
+            [type_covered, algorithm, labels, original_ttl, expiration, inception, key_tag, signer_name, signature @ ..],
+        ) if !signature.is_empty() => DnsRecord::RRSIG {
+            domain,
+            type_covered: QueryType::from_name(type_covered)
+                .ok_or_else(|| format!("Unknown type {}", type_covered))?,
+            algorithm: algorithm.parse()?,
+            labels: labels.parse()?,
+            original_ttl: original_ttl.parse()?,
+            expiration: parse_time(expiration)?,
+            inception: parse_time(inception)?,
+            key_tag: key_tag.parse()?,
+            signer_name: parse_name(signer_name, origin)?,
+            signature: base64_decode(&concat(signature))?,
+            ttl,
+        },
+        (QueryType::NSEC, [next_domain, types @ ..]) => DnsRecord::NSEC {
+            domain,
+            next_domain: parse_name(next_domain, origin)?,
+            types: parse_types(types)?,
+            ttl,
+        },
+        (QueryType::NSEC3, [hash_algorithm, flags, iterations, salt, next_hashed, types @ ..]) => {
+            DnsRecord::NSEC3 {
+                domain,
+                hash_algorithm: hash_algorithm.parse()?,
+                flags: flags.parse()?,
+                iterations: iterations.parse()?,
+                salt: match &**salt {
+                    "-" => Vec::new(),
+                    salt => parse_hex(salt)?,
+                },
+                next_hashed: base32hex_decode(next_hashed)
+                    .ok_or_else(|| format!("Invalid base32hex {}", next_hashed))?,
+                types: parse_types(types)?,
+                ttl,
+            }
+        }
+        (QueryType::UNKNOWN(_) | QueryType::OPT, _) => {
+            return Err(format!("{:?} records need their data as \\# <length> <hex>", qtype).into())
+        }
+        _ => return Err(format!("Invalid {:?} record data", qtype).into()),
+    };
+
+    Ok(record)
+}
+
+/// A record given in the generic format of RFC 3597, decoded as if it had
+/// come off the wire so that known types get their own variant.
+fn from_wire(domain: &str, ttl: u32, qtype: QueryType, data: &[u8]) -> Result<DnsRecord> {
+    let mut buffer = BytePacketBuffer::with_size(name_len(domain) + 10 + data.len());
+    buffer.write_qname(domain)?;
+    buffer.write_u16(qtype.to_num())?;
+    buffer.write_u16(1)?;
+    buffer.write_u32(ttl)?;
+    buffer.write_u16(data.len() as u16)?;
+    buffer.write_bytes(data)?;
+    buffer.pos = 0;
+
+    DnsRecord::read(&mut buffer)
+}
+
+/// The words of a field split by blanks, such as base64 or hex, joined.
+fn concat(words: &[Word]) -> String {
+    words.iter().map(|word| &**word).collect()
+}
+
+/// A character-string, with its `\\X` and `\\DDD` escapes decoded.
+fn parse_string(string: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut chars = string.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unescape(&mut chars, &mut bytes)?,
+            c => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+    if bytes.len() > 255 {
+        return Err(format!("String too long: {}", string).into());
+    }
+
+    Ok(bytes)
+}
+
+fn parse_types(types: &[Word]) -> Result<Vec<QueryType>> {
+    types
+        .iter()
+        .map(|name| {
+            QueryType::from_name(name).ok_or_else(|| format!("Unknown type {}", name).into())
+        })
+        .collect()
+}
+
+/// An RRSIG time, as `YYYYMMDDHHmmSS` in UTC or as seconds since the epoch
+/// (RFC 4034 section 3.2).
+fn parse_time(time: &str) -> Result<u32> {
+    if time.len() != 14 {
+        return Ok(time.parse()?);
+    }
+
+    let field = |range: std::ops::Range<usize>| -> Result<i64> { Ok(time[range].parse()?) };
+    let (year, month, day) = (field(0..4)?, field(4..6)?, field(6..8)?);
+    let (hour, minute, second) = (field(8..10)?, field(10..12)?, field(12..14)?);
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return Err(format!("Invalid time {}", time).into());
+    }
+
+    // days since the epoch of the proleptic Gregorian calendar
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let year_of_era = y - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    // serial number arithmetic, so times past 2106 wrap around
+    Ok((days * 86400 + hour * 3600 + minute * 60 + second) as u32)
+}
+
+pub fn base64_decode(encoded: &str) -> Result<Vec<u8>> {
+    let mut bits = 0u32;
+    let mut bit_count = 0;
+    let mut decoded = Vec::new();
+
+    for c in encoded.bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            _ => return Err(format!("Invalid base64 {}", encoded).into()),
+        };
+        bits = (bits << 6) | value as u32;
+        bit_count += 6;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            decoded.push((bits >> bit_count) as u8);
+            bits &= (1 << bit_count) - 1;
+        }
+    }
+
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        process,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    /// Load `content` as the zone file of example.com.
+    fn parse(content: &str) -> Result<Vec<DnsRecord>> {
+        static FILES: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "rdns-test-{}-{}.zone",
+            process::id(),
+            FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&path, content)?;
+        let records = load(path.to_str().unwrap_or_default(), "example.com");
+        let _ = fs::remove_file(&path);
+        records
+    }
+
+    fn word(text: &str) -> Word {
+        Word {
+            text: text.to_owned(),
+            quoted: false,
+        }
+    }
+
+    fn wire(name: &str) -> Vec<u8> {
+        let mut buffer = BytePacketBuffer::with_size(name_len(name));
+        buffer.write_qname(name).unwrap();
+        buffer.buf
+    }
+
+    #[test]
+    fn decimal_escapes() {
+        let name = parse_name(&word("\\200\\065b.example."), "").unwrap();
+        assert_eq!(wire(&name), b"\x03\xc8ab\x07example\x00");
+        assert_eq!(name_len(&name), 13);
+        assert_eq!(display_name(&name), "\\200ab.example.");
+
+        assert!(parse_name(&word("\\256.example."), "").is_err());
+        assert!(parse_name(&word("\\25.example."), "").is_err());
+    }
+
+    #[test]
+    fn escaped_dot() {
+        let name = parse_name(&word("a\\.b.example."), "").unwrap();
+        assert_eq!(label_count(&name), 2);
+        assert_eq!(ancestor(&name, 1), "example");
+        assert_eq!(wire(&name), b"\x03a.b\x07example\x00");
+        assert_eq!(display_name(&name), "a\\.b.example.");
+    }
+
+    #[test]
+    fn escaped_names_survive_the_wire() {
+        let records = parse("$TTL 60\na\\.b\\200 A 192.0.2.1\n").unwrap();
+
+        let mut buffer = BytePacketBuffer::new();
+        records[0].write(&mut buffer).unwrap();
+        buffer.pos = 0;
+        let read = DnsRecord::read(&mut buffer).unwrap();
+        assert_eq!(read.domain(), records[0].domain());
+        assert_eq!(display_name(read.domain()), "a\\.b\\200.example.com.");
+    }
+
+    #[test]
+    fn long_labels() {
+        let label = "a".repeat(63);
+        assert!(parse_name(&word(&label), "example").is_ok());
+        assert!(parse_name(&word(&format!("{}a", label)), "example").is_err());
+        // an escape is a single byte of the label
+        assert!(parse_name(&word(&format!("{}\\097", &label[1..])), "example").is_ok());
+    }
+
+    #[test]
+    fn relative_names() {
+        let records = parse(
+            "$TTL 60\n\
+             @ NS ns\n\
+             www A 192.0.2.1\n\
+             \x20 AAAA 2001:db8::1\n\
+             mail.example.com. CNAME www\n",
+        )
+        .unwrap();
+
+        let owners = records.iter().map(DnsRecord::domain).collect::<Vec<_>>();
+        assert_eq!(
+            owners,
+            [
+                "example.com",
+                "www.example.com",
+                "www.example.com",
+                "mail.example.com"
+            ]
+        );
+        assert!(matches!(&records[0], DnsRecord::NS { host, .. } if host == "ns.example.com"));
+        assert!(matches!(&records[3], DnsRecord::CNAME { host, .. } if host == "www.example.com"));
+    }
+
+    #[test]
+    fn origin_and_ttl() {
+        let records = parse(
+            "$TTL 1h\n\
+             a A 192.0.2.1\n\
+             $ORIGIN sub\n\
+             b 30 A 192.0.2.2\n\
+             c A 192.0.2.3\n\
+             $ORIGIN other.\n\
+             @ A 192.0.2.4\n",
+        )
+        .unwrap();
+
+        let owners = records
+            .iter()
+            .map(|record| (record.domain(), record.ttl()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            owners,
+            [
+                ("a.example.com", 3600),
+                ("b.sub.example.com", 30),
+                ("c.sub.example.com", 3600),
+                ("other", 3600),
+            ]
+        );
+        assert!(parse("a A 192.0.2.1\n").is_err());
+    }
+
+    #[test]
+    fn parentheses() {
+        let records = parse(
+            "@ 3600 SOA ns hostmaster ( 2024010101 ; serial\n\
+             \x20   7200 3600\n\
+             \x20   1209600 300 )\n\
+             www A 192.0.2.1\n",
+        )
+        .unwrap();
+
+        assert_eq!(records.len(), 2);
+        assert!(matches!(&records[0], DnsRecord::SOA {
+            rname, serial: 2024010101, minimum: 300, ..
+        } if rname == "hostmaster.example.com"));
+        assert_eq!(records[1].ttl(), 3600);
+
+        assert!(parse("@ 3600 SOA ns hostmaster ( 1 2 3 4 5\n").is_err());
+        assert!(parse("@ 3600 A 192.0.2.1 )\n").is_err());
+    }
+
+    #[test]
+    fn txt() {
+        let records = parse(
+            "$TTL 60\n\
+             @ TXT \"v=spf1 -all\" plain \"\" \"say \\\"hi\\\"\\059\\255\"\n",
+        )
+        .unwrap();
+
+        let strings: [&[u8]; 4] = [b"v=spf1 -all", b"plain", b"", b"say \"hi\";\xff"];
+        assert!(matches!(&records[0], DnsRecord::TXT { data, .. } if data == &strings));
+        assert_eq!(
+            records[0].to_string(),
+            "example.com. 60 IN TXT \"v=spf1 -all\" \"plain\" \"\" \"say \\\"hi\\\";\\255\""
+        );
+
+        let rdata = records[0].rdata().unwrap();
+        assert_eq!(&rdata[..12], b"\x0bv=spf1 -all");
+        let mut buffer = BytePacketBuffer::new();
+        records[0].write(&mut buffer).unwrap();
+        buffer.pos = 0;
+        assert_eq!(DnsRecord::read(&mut buffer).unwrap(), records[0]);
+
+        assert!(parse(&format!("$TTL 60\n@ TXT \"{}\"\n", "a".repeat(256))).is_err());
+        assert!(parse("$TTL 60\n@ TXT\n").is_err());
+    }
+
+    #[test]
+    fn quoted_words_are_not_special() {
+        let records = parse(
+            "$TTL 60\n\
+             \"@\" TXT \"$ORIGIN\" \"@\"\n\
+             \"$ORIGIN\" TXT a\n",
+        )
+        .unwrap();
+
+        assert_eq!(records[0].domain(), "@.example.com");
+        assert!(matches!(&records[0], DnsRecord::TXT { data, .. }
+            if data == &[b"$ORIGIN".to_vec(), b"@".to_vec()]));
+        assert_eq!(records[1].domain(), "$origin.example.com");
+
+        // unquoted, they keep their meaning
+        let records = parse("$TTL 60\n$ORIGIN sub\n@ TXT a\n").unwrap();
+        assert_eq!(records[0].domain(), "sub.example.com");
+    }
+
+    #[test]
+    fn written_zone_parses_back() {
+        let records = parse(
+            "$TTL 3600\n\
+             @ SOA ns hostmaster 2024010101 7200 3600 1209600 300\n\
+             @ NS ns\n\
+             @ MX 10 mail\n\
+             @ TXT \"v=spf1 -all\" \"a \\\"quoted\\\" \\\\ string\\255\"\n\
+             ns A 192.0.2.53\n\
+             ns AAAA 2001:db8::53\n\
+             www CNAME ns\n\
+             a\\.b\\200 A 192.0.2.1\n\
+             \\@\\$\\; 60 A 192.0.2.2\n\
+             sub NS ns.sub\n\
+             sub DS 12345 8 2 abcdef0123456789\n\
+             @ TYPE65280 \\# 3 010203\n",
+        )
+        .unwrap();
+
+        let written = write(&records);
+        assert!(written.contains("a\\.b\\200.example.com. 3600 IN A 192.0.2.1\n"));
+        assert!(written.contains("\\@\\$\\;.example.com. 60 IN A 192.0.2.2\n"));
+        assert!(written.starts_with("example.com. 3600 IN SOA "));
+
+        let mut parsed = parse(&written).unwrap();
+        assert_eq!(write(&parsed), written);
+        let mut records = records;
+        records.sort_by_key(DnsRecord::to_string);
+        parsed.sort_by_key(DnsRecord::to_string);
+        assert_eq!(parsed, records);
+    }
+}